@@ -1,33 +1,466 @@
 //! Configuration management
 
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 use anyhow::Result;
 use config::Environment;
 use dotenvy::dotenv;
 use serde_derive::Deserialize;
 
+use crate::imaging::Colour;
+
 /// Struct containing configuration for both the image uploader and the
 /// imagehost
 #[derive(Default, Debug, Deserialize, Clone)]
 pub struct Config {
     /// The target location for images to be stored (if enabled)
     pub target_dir: Option<String>,
+    /// Further directories new uploads are stored in, in order, once the target directory and any
+    /// earlier failover directories are low on space. Images are addressed by their path relative
+    /// to whichever directory holds them, so all of these act as a single storage area.
+    pub failover_dirs: Vec<String>,
+    /// Minimum number of bytes which must remain free on a storage directory's volume for
+    /// uploads to be stored there
+    pub min_free_space: u64,
     /// Enable imagehost functionality
     pub enable_imagehost: bool,
+    /// Enable the JSON API for managing stored images. Requires the index to be enabled.
+    pub enable_api: bool,
+    /// Base URL the server is publicly reachable at, used when returning links to clients.
+    /// Links are relative to the server root if unset.
+    pub public_url: Option<String>,
     /// Enable using regex to attempt to guess the name of the program
     /// that was screenshotted, and store images in a corresponding subdirectory
     pub enable_subdirectories: bool,
     /// Override the regex used to extract the program name
     pub subdirectory_regex: String,
+    /// Template for the directory images are stored in, relative to the target directory.
+    /// Segments are separated by `/`, and may contain `{program}` (the `subdir` capture of
+    /// `subdirectory_regex`), any other named capture of `subdirectory_regex`, or the upload
+    /// date as `{YYYY}`, `{MM}`, `{DD}` or `{YYYY-MM-DD}`. Segments whose captures did not
+    /// match are left out.
+    pub subdirectory_layout: String,
+    /// Ordered rules for choosing the subdirectory an upload is stored in. The first rule whose
+    /// conditions all match is used, falling back to `subdirectory_layout` if none do.
+    pub routing_rules: Vec<RoutingRule>,
+    /// Ordered rules for converting uploads into a different format before they are stored. The
+    /// first rule whose conditions all match is used, and uploads matching no rule are kept as is.
+    pub transcode_rules: Vec<TranscodeRule>,
+    /// Ordered rules for overlaying a watermark onto images, either once when they are stored or
+    /// whenever they are served through `/img`. The first rule matching an image at each stage is used.
+    pub watermark_rules: Vec<WatermarkRule>,
+    /// Which embedded metadata, such as camera EXIF data and GPS coordinates, to keep in stored
+    /// JPEG, PNG and WebP images. Originals kept by transcoding rules are left untouched.
+    pub metadata_policy: MetadataPolicy,
+    /// How embedded ICC colour profiles, such as those of wide-gamut captures, are handled
+    pub colour_profiles: ColourProfilePolicy,
     /// The IP and port(s) (in <IP>:[<PORT>] format) that should be listened on.
     pub bind: Vec<String>,
     /// Maximum allowable size for uploaded images in bytes
     pub max_image_size: u64,
+    /// Maximum upload sizes in bytes for particular categories of file, such as `image`, which
+    /// replace `max_image_size` for uploads of that category
+    pub max_size_by_category: HashMap<String, u64>,
+    /// Limits on the images which will be decoded, protecting against small files which expand
+    /// to enormous images
+    pub decode_limits: DecodeLimits,
+    /// Record metadata about every stored image in an SQLite index. Disabled by default.
+    pub enable_index: bool,
+    /// Override the location of the index database, which defaults to a hidden directory within
+    /// the target directory
+    pub index_path: Option<String>,
+    /// Limits on how long and how many images are kept in the target directory
+    pub retention: RetentionConfig,
+    /// Settings for the trash which deleted images are moved to
+    pub trash: TrashConfig,
+    /// Settings for replicating stored images to backup directories
+    pub mirror: MirrorConfig,
+    /// Settings for generating and serving thumbnails of stored images
+    pub thumbnails: ThumbnailConfig,
+    /// Settings for resized and converted copies of images requested through `/img` query parameters
+    pub variants: VariantConfig,
+    /// Settings for losslessly recompressing stored images in the background
+    pub optimise: OptimiseConfig,
+    /// Handling of animated GIF, PNG and WebP uploads, which are always stored as received
+    pub animation: AnimationConfig,
+    /// Users identified by API key, whose uploads are attributed to them and limited by quotas
+    pub users: Vec<UserConfig>,
+}
+
+/// A user who uploads by sending an API key, in either an `Authorization: Bearer <key>` or an
+/// `X-Api-Key` header
+#[derive(Default, Deserialize, Clone)]
+pub struct UserConfig {
+    /// Name recorded as the uploader of this user's images
+    pub name: String,
+    /// Secret key identifying the user
+    pub api_key: String,
+    /// Maximum total size in bytes of the images this user may have stored
+    pub quota_bytes: Option<u64>,
+    /// Maximum number of images this user may have stored
+    pub quota_files: Option<u64>,
+    /// Maximum size in bytes of each upload by this user, replacing any other size limit
+    pub max_upload_size: Option<u64>,
+    /// Allow this user to manage every image through the API, not only their own uploads
+    #[serde(default)]
+    pub admin: bool,
+}
+
+//The configuration is printed on startup, so keys must not appear in it
+impl std::fmt::Debug for UserConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserConfig")
+            .field("name", &self.name)
+            .field("api_key", &"<redacted>")
+            .field("quota_bytes", &self.quota_bytes)
+            .field("quota_files", &self.quota_files)
+            .field("max_upload_size", &self.max_upload_size)
+            .field("admin", &self.admin)
+            .finish()
+    }
+}
+
+/// Limits checked against an image's header before it is decoded
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct DecodeLimits {
+    /// Largest image width in pixels which will be decoded
+    pub max_width: u32,
+    /// Largest image height in pixels which will be decoded
+    pub max_height: u32,
+    /// Largest number of bytes a decoder may allocate at once
+    pub max_alloc: u64,
+}
+
+/// Configuration for copies of stored images resized or converted on request
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct VariantConfig {
+    /// Largest width in pixels which may be requested
+    pub max_width: u32,
+    /// Largest height in pixels which may be requested
+    pub max_height: u32,
+    /// Quality to encode lossy formats at, from 1 to 100
+    pub quality: u8,
+    /// Override the directory variants are cached in, which defaults to a hidden directory
+    /// within the target directory
+    pub cache_dir: Option<String>,
+}
+
+/// Configuration for the background queue which recompresses stored images to save space. Files
+/// are only replaced by smaller copies which decode to exactly the same pixels.
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct OptimiseConfig {
+    /// Recompress PNG uploads in the background once they are stored
+    pub enabled: bool,
+    /// Optimisation level from 0 (fastest) to 6 (smallest)
+    pub level: u8,
+    /// Longest time to spend recompressing a single image
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Command which losslessly optimises a JPEG, such as
+    /// `["jpegtran", "-copy", "all", "-optimize", "-outfile", "{output}", "{input}"]`. `{input}` and
+    /// `{output}` are replaced with file paths. JPEGs are left as they are if this is empty.
+    pub jpeg_command: Vec<String>,
+}
+
+/// Configuration for animated uploads
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct AnimationConfig {
+    /// What to place on the clipboard when an animated image is uploaded
+    pub clipboard: AnimationClipboard,
+}
+
+/// What to place on the clipboard for an animated upload
+#[derive(Default, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnimationClipboard {
+    /// The first frame, as a still image
+    #[default]
+    FirstFrame,
+    /// A link to the stored animation on the imagehost. Falls back to the first frame for uploads
+    /// which aren't stored.
+    Url,
+}
+
+/// Configuration for scaled down copies of stored images, served under `/thumb`
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct ThumbnailConfig {
+    /// Enable thumbnails. Requires the imagehost to be enabled.
+    pub enabled: bool,
+    /// Sizes in pixels of the squares thumbnails are scaled to fit within. The first is served
+    /// when a request doesn't ask for a particular size.
+    pub sizes: Vec<u32>,
+    /// Generate thumbnails as soon as an image is uploaded, rather than on first request
+    pub on_upload: bool,
+    /// Override the directory thumbnails are cached in, which defaults to a hidden directory
+    /// within the target directory
+    pub cache_dir: Option<String>,
+}
+
+/// Configuration for copying stored images to one or more backup directories
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct MirrorConfig {
+    /// Directories every stored image is copied to, keeping its path relative to the target directory
+    pub destinations: Vec<String>,
+    /// Number of times to retry a failed copy before giving up
+    pub retries: u32,
+    /// Delay before the first retry, which doubles after every failed attempt
+    #[serde(with = "humantime_serde")]
+    pub retry_delay: Duration,
+}
+
+/// Configuration for the trash directory holding deleted images
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct TrashConfig {
+    /// How long deleted images are kept before being permanently removed
+    #[serde(with = "humantime_serde")]
+    pub purge_after: Duration,
+    /// How often to check for deleted images which should be permanently removed
+    #[serde(with = "humantime_serde")]
+    pub purge_interval: Duration,
+}
+
+/// A rule routing matching uploads into a subdirectory. All conditions which are set must match.
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct RoutingRule {
+    /// Name of the rule, used in logs and when testing routes
+    pub name: Option<String>,
+    /// Regex the original filename must match. Named captures can be used in `destination`.
+    pub regex: Option<String>,
+    /// Glob pattern the original filename must match
+    pub glob: Option<String>,
+    /// MIME type the upload must have, either exact (`image/png`) or by top level type (`image/*`)
+    pub mime: Option<String>,
+    /// Minimum size of the upload in bytes
+    pub min_size: Option<u64>,
+    /// Maximum size of the upload in bytes
+    pub max_size: Option<u64>,
+    /// Directory to store matching uploads in, using the same template syntax as
+    /// `subdirectory_layout`
+    pub destination: String,
+    /// Crop uniform borders from matching uploads
+    pub trim: Option<TrimRule>,
+}
+
+/// How uniform borders are cropped from uploads matching a routing rule
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct TrimRule {
+    /// Largest difference in any colour channel between pixels which are treated as the same colour
+    #[serde(default = "default_trim_tolerance")]
+    pub tolerance: u8,
+    /// Quality to re-encode lossy formats at once cropped, from 1 to 100
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+    /// Keep the upload as it was received in a hidden directory alongside the cropped file
+    #[serde(default)]
+    pub keep_original: bool,
+}
+
+/// A rule converting matching uploads into another format. All conditions which are set must match.
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct TranscodeRule {
+    /// MIME type the upload must have, either exact (`image/png`) or by top level type (`image/*`)
+    pub mime: Option<String>,
+    /// Subdirectory the upload must have been routed to, including any directories nested inside it
+    pub subdirectory: Option<String>,
+    /// Format to store matching uploads in
+    pub format: StorageFormat,
+    /// Quality to encode lossy formats at, from 1 to 100
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+    /// Keep the upload as it was received in a hidden directory alongside the converted file
+    #[serde(default)]
+    pub keep_original: bool,
+}
+
+/// A rule overlaying a watermark onto matching images. All conditions which are set must match.
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct WatermarkRule {
+    /// Subdirectory the image must be stored in, including any directories nested inside it
+    pub subdirectory: Option<String>,
+    /// Whether the watermark is added to the stored file or only to served copies
+    #[serde(default)]
+    pub stage: WatermarkStage,
+    /// Requests served copies are watermarked for. Watermarks added when storing are always kept.
+    #[serde(default)]
+    pub audience: WatermarkAudience,
+    /// Text to draw as the watermark
+    pub text: Option<String>,
+    /// Path of an image to overlay as the watermark, used instead of text
+    pub image: Option<String>,
+    /// Colour of text watermarks, defaulting to white
+    pub colour: Option<Colour>,
+    /// Where the watermark is placed on the image
+    #[serde(default)]
+    pub position: WatermarkPosition,
+    /// Opacity of the watermark, from 0 (invisible) to 1 (opaque)
+    #[serde(default = "default_watermark_opacity")]
+    pub opacity: f32,
+    /// Size of the watermark relative to the image. It is scaled to fit within this fraction of
+    /// the image's width and height.
+    #[serde(default = "default_watermark_scale")]
+    pub scale: f32,
+    /// Distance in pixels between the watermark and the edges of the image
+    #[serde(default = "default_watermark_margin")]
+    pub margin: u32,
+    /// Quality to re-encode lossy formats at once watermarked, from 1 to 100
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+    /// Keep the upload as it was received in a hidden directory alongside the watermarked file.
+    /// Only used when watermarking stored files.
+    #[serde(default)]
+    pub keep_original: bool,
+}
+
+/// When a watermark is added to an image
+#[derive(Default, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkStage {
+    /// Add the watermark to the stored file when it is uploaded
+    Store,
+    /// Keep the stored file unmarked, adding the watermark to copies served through `/img`.
+    /// Thumbnails are not watermarked, and neither are animated files unless they are resized,
+    /// though their full size posters are.
+    #[default]
+    Serve,
+}
+
+/// Requests a served watermark applies to
+#[derive(Default, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkAudience {
+    /// Every request
+    #[default]
+    All,
+    /// Requests without an API key
+    Public,
+    /// Requests sending the API key of a configured user
+    Authenticated,
+}
+
+/// Where a watermark is placed on an image
+#[derive(Default, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkPosition {
+    /// Top left corner
+    TopLeft,
+    /// Centre of the top edge
+    Top,
+    /// Top right corner
+    TopRight,
+    /// Centre of the left edge
+    Left,
+    /// Centre of the image
+    #[serde(alias = "center")]
+    Centre,
+    /// Centre of the right edge
+    Right,
+    /// Bottom left corner
+    BottomLeft,
+    /// Centre of the bottom edge
+    Bottom,
+    /// Bottom right corner
+    #[default]
+    BottomRight,
+}
+
+/// Embedded metadata to keep in stored images
+#[derive(Default, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataPolicy {
+    /// Remove all metadata. Images relying on an EXIF orientation may be displayed rotated.
+    StripAll,
+    /// Remove all metadata except the EXIF orientation and ICC colour profile
+    KeepOrientationIcc,
+    /// Store images exactly as they were uploaded
+    #[default]
+    KeepAll,
+}
+
+/// How embedded ICC colour profiles are handled
+#[derive(Default, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColourProfilePolicy {
+    /// Leave profiles to the metadata policy, and drop them from copies the server encodes
+    #[default]
+    Ignore,
+    /// Keep profiles on stored files even if the metadata policy removes metadata, and embed them
+    /// in every copy the server encodes, such as transcoded files, variants and thumbnails. Images
+    /// are converted to sRGB for the clipboard, which can't carry a profile.
+    Preserve,
+    /// Convert images to sRGB when decoding them, so the clipboard and every copy the server
+    /// encodes show the intended colours without needing a profile. Stored files are untouched.
+    #[serde(alias = "convert")]
+    Srgb,
+}
+
+/// Format uploads can be stored in
+#[derive(Default, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageFormat {
+    /// Store the upload exactly as it was received
+    #[default]
+    Original,
+    /// Lossless PNG
+    Png,
+    /// Lossy JPEG at the rule's quality
+    #[serde(alias = "jpg")]
+    Jpeg,
+    /// Lossless WebP
+    Webp,
+}
+
+fn default_trim_tolerance() -> u8 {
+    8
+}
+
+fn default_quality() -> u8 {
+    85
+}
+
+fn default_watermark_opacity() -> f32 {
+    0.5
+}
+
+fn default_watermark_scale() -> f32 {
+    0.25
+}
+
+fn default_watermark_margin() -> u32 {
+    16
+}
+
+/// Configuration for automatically pruning old images from the target directory
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct RetentionConfig {
+    /// Periodically prune images in the background while the webserver is running
+    pub enabled: bool,
+    /// How often the background pruning task should run
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// Limits to enforce. Each rule is applied independently to the files it covers.
+    pub rules: Vec<RetentionRule>,
+    /// Glob patterns (relative to the target directory) for files which must never be pruned
+    pub pinned: Vec<String>,
+}
+
+/// A single set of retention limits, optionally restricted to one subdirectory
+#[derive(Default, Debug, Deserialize, Clone)]
+pub struct RetentionRule {
+    /// Only apply this rule to files within the given subdirectory of the target directory,
+    /// including any directories nested inside it
+    pub subdirectory: Option<String>,
+    /// Remove files which were last modified longer ago than this
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+    /// Remove the oldest files until the covered files take up at most this many bytes
+    pub max_total_size: Option<u64>,
+    /// Remove the oldest files until at most this many files are covered
+    pub max_file_count: Option<u64>,
 }
 
 static ENV_PREFIX: &str = "YOINKX";
 static DEFAULT_SUBDIR_REGEX: &str = r"(?P<subdir>.*)_[\d\w]{10}.[\w]+";
+static DEFAULT_SUBDIR_LAYOUT: &str = "{program}";
 
 impl Config {
     /// Load the configuration from dotenv file, env vars or a config file.
@@ -40,11 +473,50 @@ impl Config {
 
         let mut config_builder = config::Config::builder()
             .set_default("target_dir", None::<Option<String>>)?
+            .set_default("failover_dirs", Vec::<String>::new())?
+            .set_default("min_free_space", 0)?
             .set_default("enable_imagehost", false)?
+            .set_default("enable_api", false)?
+            .set_default("public_url", None::<Option<String>>)?
             .set_default("enable_subdirectories", false)?
             .set_default("subdirectory_regex", DEFAULT_SUBDIR_REGEX)?
+            .set_default("subdirectory_layout", DEFAULT_SUBDIR_LAYOUT)?
+            .set_default("routing_rules", Vec::<String>::new())?
+            .set_default("transcode_rules", Vec::<String>::new())?
+            .set_default("watermark_rules", Vec::<String>::new())?
+            .set_default("metadata_policy", "keep_all")?
+            .set_default("colour_profiles", "ignore")?
             .set_default("bind", vec![String::from("localhost:1256")])?
             .set_default("max_image_size", 100_000_000)?
+            .set_default("max_size_by_category", HashMap::<String, u64>::new())?
+            .set_default("decode_limits.max_width", 16384)?
+            .set_default("decode_limits.max_height", 16384)?
+            .set_default("decode_limits.max_alloc", 512 * 1024 * 1024)?
+            .set_default("enable_index", false)?
+            .set_default("index_path", None::<Option<String>>)?
+            .set_default("retention.enabled", false)?
+            .set_default("retention.interval", "1h")?
+            .set_default("retention.rules", Vec::<String>::new())?
+            .set_default("retention.pinned", Vec::<String>::new())?
+            .set_default("trash.purge_after", "30d")?
+            .set_default("trash.purge_interval", "1h")?
+            .set_default("mirror.destinations", Vec::<String>::new())?
+            .set_default("mirror.retries", 5)?
+            .set_default("mirror.retry_delay", "5s")?
+            .set_default("users", Vec::<String>::new())?
+            .set_default("thumbnails.enabled", false)?
+            .set_default("thumbnails.sizes", vec![256])?
+            .set_default("thumbnails.on_upload", true)?
+            .set_default("thumbnails.cache_dir", None::<Option<String>>)?
+            .set_default("variants.max_width", 4096)?
+            .set_default("variants.max_height", 4096)?
+            .set_default("variants.quality", 85)?
+            .set_default("variants.cache_dir", None::<Option<String>>)?
+            .set_default("optimise.enabled", false)?
+            .set_default("optimise.level", 2)?
+            .set_default("optimise.timeout", "30s")?
+            .set_default("optimise.jpeg_command", Vec::<String>::new())?
+            .set_default("animation.clipboard", "first_frame")?
             .add_source(
                 Environment::with_prefix(ENV_PREFIX)
                     .try_parsing(true)
@@ -65,6 +537,41 @@ impl Config {
         Ok(res)
     }
 
+    /// Returns the size limit in bytes for an upload of the given category, sent by the given
+    /// user. A limit set for the user takes precedence over one set for the category, which in
+    /// turn takes precedence over `max_image_size`.
+    pub fn upload_size_limit(&self, category: &str, user: Option<&UserConfig>) -> u64 {
+        user.and_then(|user| user.max_upload_size)
+            .or_else(|| {
+                self.max_size_by_category
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(category))
+                    .map(|(_, &limit)| limit)
+            })
+            .unwrap_or(self.max_image_size)
+    }
+
+    /// Returns the largest size limit which could apply to an upload sent by the given user,
+    /// whatever its category
+    pub fn largest_upload_size_limit_for(&self, user: Option<&UserConfig>) -> u64 {
+        user.and_then(|user| user.max_upload_size)
+            .unwrap_or_else(|| {
+                self.max_size_by_category
+                    .values()
+                    .copied()
+                    .fold(self.max_image_size, u64::max)
+            })
+    }
+
+    /// Returns the largest size limit which could apply to any upload
+    pub fn largest_upload_size_limit(&self) -> u64 {
+        self.max_size_by_category
+            .values()
+            .copied()
+            .chain(self.users.iter().filter_map(|user| user.max_upload_size))
+            .fold(self.max_image_size, u64::max)
+    }
+
     fn check_options(&mut self) {
         //Canonicalize tgt_dir for comparisons later
         self.target_dir.as_mut().map(|tgt_dir_str| {
@@ -82,5 +589,41 @@ impl Config {
             println!("Cannot enable imagehost unless target dir is set");
             self.enable_imagehost = false;
         }
+        //The API works from the index, so needs it to be enabled
+        if self.enable_api && (self.target_dir.is_none() || !self.enable_index) {
+            println!("Cannot enable API unless target dir is set and index is enabled");
+            self.enable_api = false;
+        }
+        //Thumbnails are served alongside the imagehost
+        if self.thumbnails.enabled && !self.enable_imagehost {
+            println!("Cannot enable thumbnails unless imagehost is enabled");
+            self.thumbnails.enabled = false;
+        }
+        //Links can only be given to animations which are served
+        if self.animation.clipboard == AnimationClipboard::Url && !self.enable_imagehost {
+            println!("Cannot copy links to animated uploads unless imagehost is enabled");
+            self.animation.clipboard = AnimationClipboard::FirstFrame;
+        }
+        //Optimised files replace stored ones, so there must be somewhere they are stored
+        if self.optimise.enabled && self.target_dir.is_none() {
+            println!("Cannot enable optimisation unless target dir is set");
+            self.optimise.enabled = false;
+        }
+        //Watermarks are drawn from either text or an image, so rules without either can never apply
+        let before = self.watermark_rules.len();
+        self.watermark_rules
+            .retain(|rule| rule.text.is_some() || rule.image.is_some());
+        if self.watermark_rules.len() < before {
+            println!("Ignoring watermark rules which set neither text nor an image");
+        }
+        //Usage is counted from the index, so quotas only cover the upload in progress without it
+        if self
+            .users
+            .iter()
+            .any(|u| u.quota_bytes.is_some() || u.quota_files.is_some())
+            && (self.target_dir.is_none() || !self.enable_index)
+        {
+            println!("Quotas only limit individual uploads unless target dir is set and index is enabled");
+        }
     }
 }
@@ -0,0 +1,313 @@
+//! Detection of animated GIF, PNG and WebP files. Containers are walked without decoding any
+//! frames, so animated uploads can be recognised cheaply and stored exactly as they were received.
+
+use anyhow::{anyhow, Result};
+use serde_derive::Serialize;
+
+use super::metadata::PNG_SIGNATURE;
+
+/// Frame count and running time of an animated image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Animation {
+    /// Number of frames in the animation
+    pub frames: u32,
+    /// Time taken to play every frame once, in milliseconds
+    pub duration_ms: u64,
+}
+
+/// Returns true if files of the given type may hold an animation
+pub fn can_animate(mime_type: &str) -> bool {
+    matches!(
+        mime_type,
+        "image/gif" | "image/png" | "image/apng" | "image/webp"
+    )
+}
+
+/// Inspects an encoded image, returning its frame count and duration if it is animated. Images
+/// with a single frame, and formats which can't be animated, return `None`.
+pub fn inspect(data: &[u8]) -> Result<Option<Animation>> {
+    let animation = if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        inspect_gif(data)?
+    } else if data.starts_with(PNG_SIGNATURE) {
+        inspect_png(data)?
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        inspect_webp(data)?
+    } else {
+        None
+    };
+    Ok(animation.filter(|animation| animation.frames > 1))
+}
+
+/// Returns the byte at an offset of a file, or an error if it is truncated
+fn byte_at(data: &[u8], pos: usize) -> Result<u8> {
+    data.get(pos)
+        .copied()
+        .ok_or_else(|| anyhow!("Truncated file at offset {}", pos))
+}
+
+/// Returns the offset just past a run of GIF data sub-blocks, which ends with an empty block
+fn skip_gif_sub_blocks(data: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = byte_at(data, pos)? as usize;
+        pos += 1 + len;
+        if len == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/// Size in bytes of the colour table described by a GIF packed field, if it has one
+fn gif_colour_table_size(packed: u8) -> usize {
+    if packed & 0x80 != 0 {
+        3 << ((packed & 0x07) + 1)
+    } else {
+        0
+    }
+}
+
+/// Counts the image descriptors in a GIF, adding up the delays of the graphic control
+/// extensions which precede them
+fn inspect_gif(data: &[u8]) -> Result<Option<Animation>> {
+    //Header and logical screen descriptor, followed by the global colour table if present
+    let mut pos = 13 + gif_colour_table_size(byte_at(data, 10)?);
+    let (mut frames, mut duration_ms, mut delay_ms) = (0u32, 0u64, 0u64);
+    loop {
+        match byte_at(data, pos)? {
+            //Extension, which sets the delay of the next frame if it is a graphic control extension
+            0x21 => {
+                if byte_at(data, pos + 1)? == 0xf9 {
+                    let delay =
+                        u16::from_le_bytes([byte_at(data, pos + 4)?, byte_at(data, pos + 5)?]);
+                    delay_ms = delay as u64 * 10;
+                }
+                pos = skip_gif_sub_blocks(data, pos + 2)?;
+            }
+            //Image descriptor, followed by the local colour table if present, then the image data
+            0x2c => {
+                let table_size = gif_colour_table_size(byte_at(data, pos + 9)?);
+                pos = skip_gif_sub_blocks(data, pos + 10 + table_size + 1)?;
+                frames += 1;
+                duration_ms += std::mem::take(&mut delay_ms);
+            }
+            //Trailer
+            0x3b => break,
+            block => {
+                return Err(anyhow!(
+                    "Unknown GIF block {:#04x} at offset {}",
+                    block,
+                    pos
+                ))
+            }
+        }
+    }
+    Ok(Some(Animation {
+        frames,
+        duration_ms,
+    }))
+}
+
+/// Reads the frame count from a PNG's animation control chunk, adding up the delays of its frame
+/// control chunks. PNGs without an animation control chunk are stills.
+fn inspect_png(data: &[u8]) -> Result<Option<Animation>> {
+    let mut frames = None;
+    let mut duration_ms = 0u64;
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + 8)
+            .ok_or_else(|| anyhow!("Truncated PNG chunk at offset {}", pos))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| anyhow!("Truncated PNG chunk at offset {}", pos))?;
+        match &header[4..8] {
+            b"acTL" if len >= 4 => {
+                frames = Some(u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+            }
+            b"fcTL" if len >= 24 => {
+                let numerator = u16::from_be_bytes([chunk[20], chunk[21]]) as u64;
+                //A denominator of 0 means hundredths of a second
+                let denominator = match u16::from_be_bytes([chunk[22], chunk[23]]) {
+                    0 => 100,
+                    denominator => denominator as u64,
+                };
+                duration_ms += numerator * 1000 / denominator;
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += 12 + len;
+    }
+    Ok(frames.map(|frames| Animation {
+        frames,
+        duration_ms,
+    }))
+}
+
+/// Flag in the extended WebP header marking the file as animated
+const WEBP_ANIMATION_FLAG: u8 = 0x02;
+
+/// Counts the frame chunks of an animated WebP, adding up their durations. WebPs without the
+/// animation flag set in their extended header are stills.
+fn inspect_webp(data: &[u8]) -> Result<Option<Animation>> {
+    let mut animated = false;
+    let (mut frames, mut duration_ms) = (0u32, 0u64);
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let fourcc = &data[pos..pos + 4];
+        let len = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let payload = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| anyhow!("Truncated WebP chunk at offset {}", pos))?;
+        match fourcc {
+            b"VP8X" if len >= 1 => animated = payload[0] & WEBP_ANIMATION_FLAG != 0,
+            b"ANMF" if len >= 16 => {
+                frames += 1;
+                duration_ms +=
+                    u32::from_le_bytes([payload[12], payload[13], payload[14], 0]) as u64;
+            }
+            _ => {}
+        }
+        pos += 8 + len + len % 2;
+    }
+    Ok(animated.then_some(Animation {
+        frames,
+        duration_ms,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbaImage};
+
+    use super::*;
+    use crate::{
+        conf::StorageFormat,
+        imaging::metadata::{write_png_chunk, write_riff_chunk},
+    };
+
+    /// GIF with a frame for each of the given delays, in hundredths of a second. Frames without a
+    /// delay have no graphic control extension.
+    fn gif(delays: &[Option<u16>]) -> Vec<u8> {
+        let mut data = b"GIF89a".to_vec();
+        //2x2 screen with a 4 colour global table
+        data.extend_from_slice(&[2, 0, 2, 0, 0x81, 0, 0]);
+        data.extend_from_slice(&[0; 12]);
+        for delay in delays {
+            if let Some(delay) = delay {
+                data.extend_from_slice(&[0x21, 0xf9, 4, 0]);
+                data.extend_from_slice(&delay.to_le_bytes());
+                data.extend_from_slice(&[0, 0]);
+            }
+            //Comment extensions between frames are skipped
+            data.extend_from_slice(&[0x21, 0xfe, 2, b'h', b'i', 0]);
+            //Image descriptor with a 2 colour local table, then the image data
+            data.extend_from_slice(&[0x2c, 0, 0, 0, 0, 2, 0, 2, 0, 0x80]);
+            data.extend_from_slice(&[0; 6]);
+            data.extend_from_slice(&[2, 2, 0x4c, 0x01, 0]);
+        }
+        data.push(0x3b);
+        data
+    }
+
+    /// APNG with a frame for each of the given delays, as fractions of a second
+    fn apng(delays: &[(u16, u16)]) -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        write_png_chunk(&mut data, b"IHDR", &[0, 0, 0, 2, 0, 0, 0, 2, 8, 6, 0, 0, 0]);
+        let mut actl = (delays.len() as u32).to_be_bytes().to_vec();
+        actl.extend_from_slice(&0u32.to_be_bytes());
+        write_png_chunk(&mut data, b"acTL", &actl);
+        for (sequence, (numerator, denominator)) in delays.iter().enumerate() {
+            let mut fctl = (sequence as u32).to_be_bytes().to_vec();
+            fctl.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 2]);
+            fctl.extend_from_slice(&[0; 8]);
+            fctl.extend_from_slice(&numerator.to_be_bytes());
+            fctl.extend_from_slice(&denominator.to_be_bytes());
+            fctl.extend_from_slice(&[0, 0]);
+            write_png_chunk(&mut data, b"fcTL", &fctl);
+            write_png_chunk(&mut data, b"IDAT", &[0; 4]);
+        }
+        write_png_chunk(&mut data, b"IEND", &[]);
+        data
+    }
+
+    /// Extended WebP with the given header flags and a frame for each of the given durations, in
+    /// milliseconds
+    fn webp(flags: u8, durations: &[u32]) -> Vec<u8> {
+        let mut body = b"WEBP".to_vec();
+        write_riff_chunk(&mut body, b"VP8X", &[flags, 0, 0, 0, 1, 0, 0, 1, 0, 0]);
+        write_riff_chunk(&mut body, b"ANIM", &[0; 6]);
+        for duration in durations {
+            let mut frame = vec![0; 12];
+            frame.extend_from_slice(&duration.to_le_bytes()[..3]);
+            frame.push(0);
+            //Odd length frame data, so the chunk has to be padded
+            frame.extend_from_slice(&[0; 5]);
+            write_riff_chunk(&mut body, b"ANMF", &frame);
+        }
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend(body);
+        data
+    }
+
+    fn animation(frames: u32, duration_ms: u64) -> Option<Animation> {
+        Some(Animation {
+            frames,
+            duration_ms,
+        })
+    }
+
+    #[test]
+    fn counts_gif_frames_and_delays() {
+        let data = gif(&[Some(10), None, Some(25)]);
+        assert_eq!(inspect(&data).unwrap(), animation(3, 350));
+        assert_eq!(inspect(&gif(&[Some(10)])).unwrap(), None);
+    }
+
+    #[test]
+    fn counts_apng_frames_and_delays() {
+        //A denominator of 0 means hundredths of a second
+        let data = apng(&[(1, 10), (5, 0), (1, 4)]);
+        assert_eq!(inspect(&data).unwrap(), animation(3, 100 + 50 + 250));
+        assert_eq!(inspect(&apng(&[(1, 10)])).unwrap(), None);
+    }
+
+    #[test]
+    fn counts_webp_frames_and_durations() {
+        let data = webp(WEBP_ANIMATION_FLAG, &[40, 1000, 0x123456]);
+        assert_eq!(inspect(&data).unwrap(), animation(3, 40 + 1000 + 0x123456));
+        //Frames are ignored unless the header says the file is animated
+        assert_eq!(inspect(&webp(0, &[40, 40])).unwrap(), None);
+    }
+
+    #[test]
+    fn stills_are_not_animated() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(3, 3));
+        for format in [StorageFormat::Png, StorageFormat::Jpeg, StorageFormat::Webp] {
+            let data = format.encode(&img, 90).unwrap().unwrap();
+            assert_eq!(inspect(&data).unwrap(), None);
+        }
+        assert_eq!(inspect(b"not an image").unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_files_never_panic() {
+        let files = [
+            gif(&[Some(10), Some(10)]),
+            apng(&[(1, 10), (1, 10)]),
+            webp(WEBP_ANIMATION_FLAG, &[40, 40]),
+        ];
+        for data in files {
+            for len in 0..data.len() {
+                //Files cut off partway through usually fail, but must never be misreported as
+                //having more frames than they do
+                if let Ok(Some(animation)) = inspect(&data[..len]) {
+                    assert!(animation.frames <= 2);
+                }
+            }
+        }
+        assert!(inspect(&gif(&[Some(10), Some(10)])[..30]).is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Drawing annotations such as boxes, arrows and labels onto an image, so screenshots can be
+//! marked up without a desktop editor
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{DynamicImage, Pixel, Rgba, RgbaImage};
+use serde_derive::Deserialize;
+
+use super::{edit_rgba, Colour};
+
+/// Font used for text labels and step numbers, bundled so output doesn't depend on the fonts
+/// installed on the server
+static FONT_DATA: &[u8] = include_bytes!("../../assets/fonts/DejaVuSans-Bold.ttf");
+
+/// Colour shapes are drawn in unless they specify their own
+const DEFAULT_COLOUR: Rgba<u8> = Rgba([229, 57, 53, 255]);
+/// Width in pixels of lines unless a shape specifies its own
+const DEFAULT_THICKNESS: f32 = 4.0;
+/// Height in pixels of text unless a shape specifies its own
+const DEFAULT_TEXT_SIZE: f32 = 24.0;
+/// Largest text size which may be requested
+const MAX_TEXT_SIZE: f32 = 512.0;
+/// Largest line width which may be requested
+const MAX_THICKNESS: f32 = 64.0;
+
+/// A point on an image, in pixels from its top left corner
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Point {
+    /// Distance from the left edge
+    pub x: f32,
+    /// Distance from the top edge
+    pub y: f32,
+}
+
+/// A shape which can be drawn onto an image
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Shape {
+    /// A rectangle, either outlined or filled
+    Rectangle {
+        /// Distance of the left edge from the left of the image
+        x: f32,
+        /// Distance of the top edge from the top of the image
+        y: f32,
+        /// Width of the rectangle
+        width: f32,
+        /// Height of the rectangle
+        height: f32,
+        /// Fill the rectangle rather than outlining it
+        #[serde(default)]
+        filled: bool,
+    },
+    /// An ellipse within the given bounds, either outlined or filled
+    Ellipse {
+        /// Distance of the left of the bounds from the left of the image
+        x: f32,
+        /// Distance of the top of the bounds from the top of the image
+        y: f32,
+        /// Width of the ellipse
+        width: f32,
+        /// Height of the ellipse
+        height: f32,
+        /// Fill the ellipse rather than outlining it
+        #[serde(default)]
+        filled: bool,
+    },
+    /// A straight arrow with its head at `to`
+    Arrow {
+        /// Where the arrow's tail starts
+        from: Point,
+        /// Where the arrow points
+        to: Point,
+    },
+    /// A line of text with its top left corner at the given point, optionally on a background
+    Text {
+        /// Distance of the text from the left of the image
+        x: f32,
+        /// Distance of the text from the top of the image
+        y: f32,
+        /// Text to draw
+        text: String,
+        /// Height of the text in pixels
+        size: Option<f32>,
+        /// Colour of a box drawn behind the text
+        background: Option<Colour>,
+    },
+    /// A numbered circle centred on the given point. Steps without a number are numbered in order,
+    /// continuing from the previous step.
+    Step {
+        /// Distance of the circle's centre from the left of the image
+        x: f32,
+        /// Distance of the circle's centre from the top of the image
+        y: f32,
+        /// Number to show in the circle
+        number: Option<u32>,
+        /// Height of the number in pixels
+        size: Option<f32>,
+    },
+}
+
+/// A shape along with how it should be drawn
+#[derive(Debug, Clone, Deserialize)]
+pub struct Annotation {
+    /// What to draw
+    #[serde(flatten)]
+    pub shape: Shape,
+    /// Colour to draw in
+    pub colour: Option<Colour>,
+    /// Width in pixels of lines
+    pub thickness: Option<f32>,
+}
+
+/// Draws annotations onto an image in order, so later ones are drawn over earlier ones
+pub fn annotate(img: &mut DynamicImage, annotations: &[Annotation]) {
+    let font = font();
+    edit_rgba(img, |canvas| {
+        let mut next_step = 1;
+        for annotation in annotations {
+            let colour = annotation.colour.map_or(DEFAULT_COLOUR, |colour| colour.0);
+            let thickness = annotation
+                .thickness
+                .unwrap_or(DEFAULT_THICKNESS)
+                .clamp(1.0, MAX_THICKNESS);
+            match &annotation.shape {
+                &Shape::Rectangle {
+                    x,
+                    y,
+                    width,
+                    height,
+                    filled,
+                } => {
+                    if filled {
+                        fill_where(canvas, (x, y, x + width, y + height), colour, |_, _| 1.0);
+                    } else {
+                        let corners = [
+                            Point { x, y },
+                            Point { x: x + width, y },
+                            Point {
+                                x: x + width,
+                                y: y + height,
+                            },
+                            Point { x, y: y + height },
+                        ];
+                        for (idx, &corner) in corners.iter().enumerate() {
+                            line(canvas, corner, corners[(idx + 1) % 4], thickness, colour);
+                        }
+                    }
+                }
+                &Shape::Ellipse {
+                    x,
+                    y,
+                    width,
+                    height,
+                    filled,
+                } => ellipse(canvas, (x, y, width, height), filled, thickness, colour),
+                &Shape::Arrow { from, to } => arrow(canvas, from, to, thickness, colour),
+                Shape::Text {
+                    x,
+                    y,
+                    text,
+                    size,
+                    background,
+                } => {
+                    let size = size.unwrap_or(DEFAULT_TEXT_SIZE).clamp(1.0, MAX_TEXT_SIZE);
+                    if let Some(background) = background {
+                        let (width, height) = text_size(&font, text, size);
+                        let pad = size / 4.0;
+                        fill_where(
+                            canvas,
+                            (x - pad, y - pad, x + width + pad, y + height + pad),
+                            background.0,
+                            |_, _| 1.0,
+                        );
+                    }
+                    draw_text(canvas, &font, text, Point { x: *x, y: *y }, size, colour);
+                }
+                &Shape::Step { x, y, number, size } => {
+                    let number = number.unwrap_or(next_step);
+                    next_step = number + 1;
+                    let size = size.unwrap_or(DEFAULT_TEXT_SIZE).clamp(1.0, MAX_TEXT_SIZE);
+                    step(canvas, &font, Point { x, y }, number, size, colour);
+                }
+            }
+        }
+    });
+}
+
+/// Returns the bundled font
+pub(crate) fn font() -> FontRef<'static> {
+    FontRef::try_from_slice(FONT_DATA).expect("Bundled font is valid")
+}
+
+/// Blends a colour onto a pixel with the given coverage, ignoring pixels outside the image
+fn blend(canvas: &mut RgbaImage, x: i64, y: i64, colour: Rgba<u8>, coverage: f32) {
+    if x < 0 || y < 0 || x >= canvas.width() as i64 || y >= canvas.height() as i64 {
+        return;
+    }
+    let coverage = coverage.clamp(0.0, 1.0);
+    if coverage <= 0.0 {
+        return;
+    }
+    let mut colour = colour;
+    colour.0[3] = (colour.0[3] as f32 * coverage).round() as u8;
+    canvas.get_pixel_mut(x as u32, y as u32).blend(&colour);
+}
+
+/// Blends a colour over every pixel within the bounds `(left, top, right, bottom)`, with the
+/// coverage of each pixel given by `coverage` for the pixel's centre
+fn fill_where(
+    canvas: &mut RgbaImage,
+    bounds: (f32, f32, f32, f32),
+    colour: Rgba<u8>,
+    coverage: impl Fn(f32, f32) -> f32,
+) {
+    let (left, top, right, bottom) = bounds;
+    let clamp_x = |v: f32| v.clamp(0.0, canvas.width() as f32) as i64;
+    let clamp_y = |v: f32| v.clamp(0.0, canvas.height() as f32) as i64;
+    let (x_range, y_range) = (
+        clamp_x(left.floor())..clamp_x(right.ceil()),
+        clamp_y(top.floor())..clamp_y(bottom.ceil()),
+    );
+    for y in y_range {
+        for x in x_range.clone() {
+            let amount = coverage(x as f32 + 0.5, y as f32 + 0.5);
+            blend(canvas, x, y, colour, amount);
+        }
+    }
+}
+
+/// Returns the distance from a point to the line segment between `a` and `b`
+fn distance_to_segment(px: f32, py: f32, a: Point, b: Point) -> f32 {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let length_sq = dx * dx + dy * dy;
+    let t = if length_sq == 0.0 {
+        0.0
+    } else {
+        (((px - a.x) * dx + (py - a.y) * dy) / length_sq).clamp(0.0, 1.0)
+    };
+    let (cx, cy) = (a.x + t * dx, a.y + t * dy);
+    ((px - cx).powi(2) + (py - cy).powi(2)).sqrt()
+}
+
+/// Draws an antialiased line with rounded ends
+fn line(canvas: &mut RgbaImage, a: Point, b: Point, thickness: f32, colour: Rgba<u8>) {
+    let radius = thickness / 2.0;
+    fill_where(
+        canvas,
+        (
+            a.x.min(b.x) - radius - 1.0,
+            a.y.min(b.y) - radius - 1.0,
+            a.x.max(b.x) + radius + 1.0,
+            a.y.max(b.y) + radius + 1.0,
+        ),
+        colour,
+        |x, y| radius + 0.5 - distance_to_segment(x, y, a, b),
+    );
+}
+
+/// Draws an ellipse within the bounds `(x, y, width, height)`
+fn ellipse(
+    canvas: &mut RgbaImage,
+    bounds: (f32, f32, f32, f32),
+    filled: bool,
+    thickness: f32,
+    colour: Rgba<u8>,
+) {
+    let (x, y, width, height) = bounds;
+    let (rx, ry) = ((width / 2.0).max(0.5), (height / 2.0).max(0.5));
+    let (cx, cy) = (x + rx, y + ry);
+    let half = thickness / 2.0;
+    fill_where(
+        canvas,
+        (
+            x - half - 1.0,
+            y - half - 1.0,
+            x + width + half + 1.0,
+            y + height + half + 1.0,
+        ),
+        colour,
+        |px, py| {
+            let (dx, dy) = (px - cx, py - cy);
+            //Approximate the distance to the edge from the implicit equation and its gradient
+            let value = (dx / rx).powi(2) + (dy / ry).powi(2) - 1.0;
+            let gradient = ((2.0 * dx / (rx * rx)).powi(2) + (2.0 * dy / (ry * ry)).powi(2)).sqrt();
+            let distance = if gradient == 0.0 {
+                -rx.min(ry)
+            } else {
+                value / gradient
+            };
+            if filled {
+                0.5 - distance
+            } else {
+                half + 0.5 - distance.abs()
+            }
+        },
+    );
+}
+
+/// Returns true if a point lies within a triangle
+fn in_triangle(px: f32, py: f32, [a, b, c]: [Point; 3]) -> bool {
+    let side = |p: Point, q: Point| (q.x - p.x) * (py - p.y) - (q.y - p.y) * (px - p.x);
+    let (ab, bc, ca) = (side(a, b), side(b, c), side(c, a));
+    (ab >= 0.0 && bc >= 0.0 && ca >= 0.0) || (ab <= 0.0 && bc <= 0.0 && ca <= 0.0)
+}
+
+/// Draws an arrow from `from` with a filled head at `to`
+fn arrow(canvas: &mut RgbaImage, from: Point, to: Point, thickness: f32, colour: Rgba<u8>) {
+    let (dx, dy) = (to.x - from.x, to.y - from.y);
+    let length = (dx * dx + dy * dy).sqrt();
+    if length == 0.0 {
+        return;
+    }
+    let (ux, uy) = (dx / length, dy / length);
+    let head_length = (thickness * 4.0).max(12.0).min(length);
+    let head_width = head_length * 0.6;
+    let base = Point {
+        x: to.x - ux * head_length,
+        y: to.y - uy * head_length,
+    };
+    line(canvas, from, base, thickness, colour);
+    let head = [
+        to,
+        Point {
+            x: base.x - uy * head_width,
+            y: base.y + ux * head_width,
+        },
+        Point {
+            x: base.x + uy * head_width,
+            y: base.y - ux * head_width,
+        },
+    ];
+    fill_where(
+        canvas,
+        (
+            head.iter().map(|p| p.x).fold(f32::MAX, f32::min),
+            head.iter().map(|p| p.y).fold(f32::MAX, f32::min),
+            head.iter().map(|p| p.x).fold(f32::MIN, f32::max),
+            head.iter().map(|p| p.y).fold(f32::MIN, f32::max),
+        ),
+        colour,
+        |x, y| if in_triangle(x, y, head) { 1.0 } else { 0.0 },
+    );
+}
+
+/// Returns the width and height in pixels of a line of text
+pub(crate) fn text_size(font: &FontRef, text: &str, size: f32) -> (f32, f32) {
+    let font = font.as_scaled(PxScale::from(size));
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, id);
+        }
+        width += font.h_advance(id);
+        previous = Some(id);
+    }
+    (width, font.ascent() - font.descent())
+}
+
+/// Draws a line of text with its top left corner at the given point
+pub(crate) fn draw_text(
+    canvas: &mut RgbaImage,
+    font: &FontRef,
+    text: &str,
+    at: Point,
+    size: f32,
+    colour: Rgba<u8>,
+) {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut caret = point(at.x, at.y + scaled.ascent());
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            caret.x += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(PxScale::from(size), caret);
+        caret.x += scaled.h_advance(id);
+        previous = Some(id);
+        if let Some(outline) = font.outline_glyph(glyph) {
+            let bounds = outline.px_bounds();
+            outline.draw(|gx, gy, coverage| {
+                blend(
+                    canvas,
+                    bounds.min.x as i64 + gx as i64,
+                    bounds.min.y as i64 + gy as i64,
+                    colour,
+                    coverage,
+                );
+            });
+        }
+    }
+}
+
+/// Draws a filled circle holding a number, centred on the given point
+fn step(
+    canvas: &mut RgbaImage,
+    font: &FontRef,
+    centre: Point,
+    number: u32,
+    size: f32,
+    colour: Rgba<u8>,
+) {
+    let label = number.to_string();
+    let (text_width, text_height) = text_size(font, &label, size);
+    let radius = text_width.max(text_height) * 0.5 + size * 0.3;
+    ellipse(
+        canvas,
+        (
+            centre.x - radius,
+            centre.y - radius,
+            radius * 2.0,
+            radius * 2.0,
+        ),
+        true,
+        0.0,
+        colour,
+    );
+    //Numbers are drawn in black or white, whichever stands out more against the circle
+    let luma = colour.to_luma().0[0];
+    let text_colour = if luma > 150 {
+        Rgba([0, 0, 0, 255])
+    } else {
+        Rgba([255, 255, 255, 255])
+    };
+    draw_text(
+        canvas,
+        font,
+        &label,
+        Point {
+            x: centre.x - text_width / 2.0,
+            y: centre.y - text_height / 2.0,
+        },
+        size,
+        text_colour,
+    );
+}
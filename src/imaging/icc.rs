@@ -0,0 +1,407 @@
+//! Embedded ICC colour profiles. Profiles are read from and written to JPEG, PNG and WebP files
+//! at the container level, and decoded images can be converted from their profile to sRGB so they
+//! display correctly where profiles are ignored, such as on the clipboard.
+
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, bail, Result};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use image::DynamicImage;
+
+use super::{
+    edit_rgba,
+    metadata::{write_png_chunk, write_riff_chunk, JPEG_ICC_PREFIX, PNG_SIGNATURE, WEBP_ICC_FLAG},
+};
+use crate::conf::ColourProfilePolicy;
+
+/// Most profile bytes a single JPEG APP2 segment can hold, after its length, prefix and sequence
+/// numbers
+const JPEG_ICC_CHUNK_SIZE: usize = 65535 - 2 - 12 - 2;
+/// Name given to profiles embedded in PNG files
+const PNG_PROFILE_NAME: &[u8] = b"ICC Profile";
+/// Flag in the extended WebP header marking the image as having transparency
+const WEBP_ALPHA_FLAG: u8 = 0x10;
+/// Largest profile which will be inflated from a PNG, protecting against compression bombs
+const MAX_PROFILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Reads the ICC profile embedded in an encoded image. Returns `None` if it has none, or its
+/// format can't hold one.
+pub fn extract(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        extract_jpeg(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        extract_png(data)
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        extract_webp(data)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Joins the APP2 segments of a JPEG holding its profile, in the order given by their sequence
+/// numbers
+fn extract_jpeg(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut chunks: Vec<(u8, &[u8])> = Vec::new();
+    let mut pos = 2;
+    while let Some(&[0xFF, marker]) = data.get(pos..pos + 2) {
+        match marker {
+            //Fill byte before a marker
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            //Start of scan or end of image, after which there are no more profile segments
+            0xDA | 0xD9 => break,
+            //Markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+        let len = data
+            .get(pos + 2..pos + 4)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+            .filter(|&len| len >= 2)
+            .ok_or_else(|| anyhow!("Truncated JPEG segment at offset {}", pos))?;
+        let payload = data
+            .get(pos + 4..pos + 2 + len)
+            .ok_or_else(|| anyhow!("Truncated JPEG segment at offset {}", pos))?;
+        if marker == 0xE2 && payload.starts_with(JPEG_ICC_PREFIX) {
+            let sequence = *payload
+                .get(JPEG_ICC_PREFIX.len())
+                .ok_or_else(|| anyhow!("Truncated ICC segment at offset {}", pos))?;
+            chunks.push((
+                sequence,
+                payload.get(JPEG_ICC_PREFIX.len() + 2..).unwrap_or_default(),
+            ));
+        }
+        pos += 2 + len;
+    }
+    if chunks.is_empty() {
+        return Ok(None);
+    }
+    chunks.sort_by_key(|(sequence, _)| *sequence);
+    Ok(Some(
+        chunks
+            .into_iter()
+            .flat_map(|(_, chunk)| chunk.to_vec())
+            .collect(),
+    ))
+}
+
+/// Inflates the profile held in a PNG's iCCP chunk
+fn extract_png(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + 8)
+            .ok_or_else(|| anyhow!("Truncated PNG chunk at offset {}", pos))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| anyhow!("Truncated PNG chunk at offset {}", pos))?;
+        match &header[4..8] {
+            b"iCCP" => {
+                //Profile name, then a null separator and the compression method
+                let name_end = chunk
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .ok_or_else(|| anyhow!("Invalid iCCP chunk at offset {}", pos))?;
+                let compressed = chunk.get(name_end + 2..).unwrap_or_default();
+                let mut profile = Vec::new();
+                ZlibDecoder::new(compressed)
+                    .take(MAX_PROFILE_SIZE)
+                    .read_to_end(&mut profile)?;
+                return Ok(Some(profile));
+            }
+            //The profile must come before the image data
+            b"IDAT" | b"IEND" => break,
+            _ => {}
+        }
+        pos += 12 + len;
+    }
+    Ok(None)
+}
+
+/// Reads the profile held in a WebP's ICCP chunk
+fn extract_webp(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let fourcc = &data[pos..pos + 4];
+        let len = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let payload = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| anyhow!("Truncated WebP chunk at offset {}", pos))?;
+        if fourcc == b"ICCP" {
+            return Ok(Some(payload.to_vec()));
+        }
+        pos += 8 + len + len % 2;
+    }
+    Ok(None)
+}
+
+/// Embeds a profile into an image encoded by the server. Formats which can't hold a profile are
+/// returned unchanged.
+pub fn embed(data: Vec<u8>, profile: &[u8]) -> Result<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        Ok(embed_jpeg(&data, profile))
+    } else if data.starts_with(PNG_SIGNATURE) {
+        embed_png(&data, profile)
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        embed_webp(&data, profile)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Inserts APP2 segments holding a profile into a JPEG, after its JFIF header if it has one
+fn embed_jpeg(data: &[u8], profile: &[u8]) -> Vec<u8> {
+    let mut pos = 2;
+    if data.get(2..4) == Some(&[0xFF, 0xE0]) {
+        if let Some(len) = data.get(4..6) {
+            pos += 2 + u16::from_be_bytes([len[0], len[1]]) as usize;
+        }
+    }
+    let pos = pos.min(data.len());
+    let mut out = Vec::with_capacity(data.len() + profile.len() + 64);
+    out.extend_from_slice(&data[..pos]);
+    let count = profile.chunks(JPEG_ICC_CHUNK_SIZE).len();
+    for (idx, chunk) in profile.chunks(JPEG_ICC_CHUNK_SIZE).enumerate() {
+        out.extend_from_slice(&[0xFF, 0xE2]);
+        out.extend_from_slice(&((chunk.len() + JPEG_ICC_PREFIX.len() + 4) as u16).to_be_bytes());
+        out.extend_from_slice(JPEG_ICC_PREFIX);
+        out.extend_from_slice(&[idx as u8 + 1, count as u8]);
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&data[pos..]);
+    out
+}
+
+/// Inserts an iCCP chunk holding a profile into a PNG, directly after its header chunk
+fn embed_png(data: &[u8], profile: &[u8]) -> Result<Vec<u8>> {
+    //The signature is followed by the 13 byte IHDR chunk, along with its length, type and checksum
+    let header_end = PNG_SIGNATURE.len() + 25;
+    if data.get(PNG_SIGNATURE.len() + 4..PNG_SIGNATURE.len() + 8) != Some(b"IHDR") {
+        bail!("PNG file doesn't start with a header chunk");
+    }
+    let mut chunk = PNG_PROFILE_NAME.to_vec();
+    chunk.extend_from_slice(&[0, 0]);
+    let mut encoder = ZlibEncoder::new(chunk, Compression::default());
+    encoder.write_all(profile)?;
+    let chunk = encoder.finish()?;
+
+    let mut out = Vec::with_capacity(data.len() + chunk.len() + 12);
+    out.extend_from_slice(&data[..header_end]);
+    write_png_chunk(&mut out, b"iCCP", &chunk);
+    out.extend_from_slice(&data[header_end..]);
+    Ok(out)
+}
+
+/// Inserts an ICCP chunk holding a profile into a WebP, adding an extended header to simple files
+fn embed_webp(data: &[u8], profile: &[u8]) -> Result<Vec<u8>> {
+    let mut chunks: Vec<(&[u8], Vec<u8>)> = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let fourcc = &data[pos..pos + 4];
+        let len = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let payload = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| anyhow!("Truncated WebP chunk at offset {}", pos))?;
+        if fourcc != b"ICCP" {
+            chunks.push((fourcc, payload.to_vec()));
+        }
+        pos += 8 + len + len % 2;
+    }
+    if !chunks.iter().any(|(fourcc, _)| *fourcc == b"VP8X") {
+        let (width, height, alpha) = webp_canvas(&chunks)?;
+        let mut header = vec![if alpha { WEBP_ALPHA_FLAG } else { 0 }, 0, 0, 0];
+        header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        chunks.insert(0, (b"VP8X", header));
+    }
+    if let Some(flags) = chunks[0].1.first_mut() {
+        *flags |= WEBP_ICC_FLAG;
+    }
+    //The profile must directly follow the extended header
+    chunks.insert(1, (b"ICCP", profile.to_vec()));
+
+    let mut body = b"WEBP".to_vec();
+    for (fourcc, payload) in &chunks {
+        write_riff_chunk(&mut body, fourcc, payload);
+    }
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend(body);
+    Ok(out)
+}
+
+/// Reads the dimensions of a simple WebP from its image data chunk, along with whether it is
+/// lossless with transparency
+fn webp_canvas(chunks: &[(&[u8], Vec<u8>)]) -> Result<(u32, u32, bool)> {
+    for (fourcc, payload) in chunks {
+        match (*fourcc, payload.as_slice()) {
+            //Signature byte, then 14 bits each of width and height minus one, then the alpha bit
+            (b"VP8L", [0x2f, b0, b1, b2, b3, ..]) => {
+                let bits = u32::from_le_bytes([*b0, *b1, *b2, *b3]);
+                return Ok((
+                    (bits & 0x3fff) + 1,
+                    ((bits >> 14) & 0x3fff) + 1,
+                    bits & (1 << 28) != 0,
+                ));
+            }
+            //Frame tag and start code, then 14 bits each of width and height
+            (b"VP8 ", [_, _, _, _, _, _, w0, w1, h0, h1, ..]) => {
+                return Ok((
+                    u16::from_le_bytes([*w0, *w1]) as u32 & 0x3fff,
+                    u16::from_le_bytes([*h0, *h1]) as u32 & 0x3fff,
+                    false,
+                ));
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow!("WebP file has no image data"))
+}
+
+/// Converts an image from the colour space described by a profile to sRGB. Images already in
+/// sRGB are left as they are.
+pub fn to_srgb(img: &mut DynamicImage, profile: &[u8]) -> Result<()> {
+    let input = qcms::Profile::new_from_slice(profile, false)
+        .ok_or_else(|| anyhow!("Couldn't parse ICC profile"))?;
+    if input.is_sRGB() {
+        return Ok(());
+    }
+    let mut output = qcms::Profile::new_sRGB();
+    output.precache_output_transform();
+    let transform = qcms::Transform::new(
+        &input,
+        &output,
+        qcms::DataType::RGBA8,
+        qcms::Intent::default(),
+    )
+    .ok_or_else(|| anyhow!("ICC profile can't be converted to sRGB"))?;
+    edit_rgba(img, |canvas| transform.apply(canvas));
+    Ok(())
+}
+
+/// Handles the profile embedded in an encoded image according to the configured policy, given the
+/// image decoded from it. Converts the image to sRGB if asked to, otherwise returns the profile
+/// copies of the image encoded by the server should carry. Profiles which can't be read or
+/// converted are ignored.
+pub fn manage(policy: ColourProfilePolicy, data: &[u8], img: &mut DynamicImage) -> Option<Vec<u8>> {
+    if policy == ColourProfilePolicy::Ignore {
+        return None;
+    }
+    let profile = match extract(data) {
+        Ok(profile) => profile?,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to read embedded colour profile");
+            return None;
+        }
+    };
+    match policy {
+        ColourProfilePolicy::Srgb => {
+            if let Err(e) = to_srgb(img, &profile) {
+                tracing::warn!(error = %e, "Failed to convert image to sRGB");
+            }
+            None
+        }
+        _ => Some(profile),
+    }
+}
+
+/// Embeds a profile into an image encoded by the server, if there is one to carry. If it can't be
+/// embedded, the image is returned without it.
+pub fn attach(data: Vec<u8>, profile: Option<&[u8]>) -> Vec<u8> {
+    let Some(profile) = profile else {
+        return data;
+    };
+    match embed(data.clone(), profile) {
+        Ok(embedded) => embedded,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to embed colour profile");
+            data
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::conf::StorageFormat;
+
+    /// Small image with a partly transparent column, so formats which keep alpha have to carry it
+    fn sample() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(7, 5, |x, y| {
+            Rgba([
+                x as u8 * 30,
+                y as u8 * 40,
+                90,
+                if x == 0 { 128 } else { 255 },
+            ])
+        }))
+    }
+
+    /// Stand-in profile of the given length. Profiles are carried as opaque bytes, so it doesn't
+    /// need to be valid.
+    fn profile(len: usize) -> Vec<u8> {
+        (0..len).map(|idx| (idx % 251) as u8).collect()
+    }
+
+    /// Embeds a profile into the sample image encoded in a format, checking it can be read back
+    /// and the file still decodes. Returns the decoded image.
+    fn round_trip(format: StorageFormat, profile: &[u8]) -> DynamicImage {
+        let data = format.encode(&sample(), 90).unwrap().unwrap();
+        assert_eq!(extract(&data).unwrap(), None);
+        let embedded = embed(data, profile).unwrap();
+        assert_eq!(extract(&embedded).unwrap().as_deref(), Some(profile));
+        let decoded = image::load_from_memory(&embedded).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (7, 5));
+        decoded
+    }
+
+    #[test]
+    fn round_trips_jpeg() {
+        round_trip(StorageFormat::Jpeg, &profile(3000));
+    }
+
+    #[test]
+    fn round_trips_jpeg_split_across_segments() {
+        round_trip(StorageFormat::Jpeg, &profile(JPEG_ICC_CHUNK_SIZE * 2 + 100));
+    }
+
+    #[test]
+    fn round_trips_png() {
+        let decoded = round_trip(StorageFormat::Png, &profile(3000));
+        assert_eq!(decoded.to_rgba8(), sample().to_rgba8());
+    }
+
+    #[test]
+    fn round_trips_webp() {
+        //Simple files gain an extended header, which must keep the transparency flag
+        let decoded = round_trip(StorageFormat::Webp, &profile(3000));
+        assert_eq!(decoded.to_rgba8(), sample().to_rgba8());
+    }
+
+    #[test]
+    fn replaces_existing_webp_profile() {
+        let data = StorageFormat::Webp.encode(&sample(), 90).unwrap().unwrap();
+        let embedded = embed(embed(data, &profile(10)).unwrap(), &profile(20)).unwrap();
+        assert_eq!(extract(&embedded).unwrap(), Some(profile(20)));
+        assert!(image::load_from_memory(&embedded).is_ok());
+    }
+
+    #[test]
+    fn leaves_other_formats_alone() {
+        let data = b"GIF89a not really a gif".to_vec();
+        assert_eq!(embed(data.clone(), &profile(10)).unwrap(), data);
+        assert_eq!(extract(&data).unwrap(), None);
+        assert_eq!(attach(data.clone(), None), data);
+    }
+}
@@ -0,0 +1,599 @@
+//! Removal of embedded metadata such as camera EXIF, GPS coordinates and comments from stored
+//! images. Files are edited at the container level, so image data is never re-encoded.
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::conf::MetadataPolicy;
+
+/// Signature at the start of every PNG file
+pub(crate) const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Prefix of the APP1 segment holding EXIF data in a JPEG file
+const JPEG_EXIF_PREFIX: &[u8] = b"Exif\0\0";
+/// Prefix of the APP2 segments holding an ICC profile in a JPEG file
+pub(crate) const JPEG_ICC_PREFIX: &[u8] = b"ICC_PROFILE\0";
+/// EXIF tag holding the orientation of the image
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Removes metadata from an image according to the policy. The ICC profile is also kept if
+/// `keep_icc` is set. Returns `None` if the image is left unchanged, either because it contains
+/// nothing to remove or because its format is not supported.
+pub fn strip(
+    data: &[u8],
+    mime_type: &str,
+    policy: MetadataPolicy,
+    keep_icc: bool,
+) -> Result<Option<Vec<u8>>> {
+    if policy == MetadataPolicy::KeepAll {
+        return Ok(None);
+    }
+    let keep_essentials = policy == MetadataPolicy::KeepOrientationIcc;
+    let keep_icc = keep_icc || keep_essentials;
+    let stripped = match mime_type {
+        "image/jpeg" => strip_jpeg(data, keep_essentials, keep_icc)?,
+        "image/png" => strip_png(data, keep_essentials, keep_icc)?,
+        "image/webp" => strip_webp(data, keep_essentials, keep_icc)?,
+        _ => return Ok(None),
+    };
+    Ok((stripped != data).then_some(stripped))
+}
+
+/// Reads the orientation from EXIF data in TIFF format, optionally preceded by the JPEG EXIF prefix
+fn exif_orientation(exif: &[u8]) -> Option<u16> {
+    let tiff = exif.strip_prefix(JPEG_EXIF_PREFIX).unwrap_or(exif);
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| -> Option<u16> {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |pos: usize| -> Option<u32> {
+        let bytes: [u8; 4] = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|&orientation| orientation != 1)
+}
+
+/// Builds EXIF data in TIFF format holding nothing but an orientation
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = Vec::with_capacity(26);
+    //Little endian header, with the first IFD directly after it
+    tiff.extend_from_slice(b"II\x2a\x00\x08\x00\x00\x00");
+    //One entry holding a single SHORT
+    tiff.extend_from_slice(&1u16.to_le_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_le_bytes());
+    tiff.extend_from_slice(&3u16.to_le_bytes());
+    tiff.extend_from_slice(&1u32.to_le_bytes());
+    tiff.extend_from_slice(&orientation.to_le_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    //No further IFDs
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    tiff
+}
+
+/// Removes application segments and comments from a JPEG file. Only the JFIF and Adobe segments,
+/// which affect how the image is decoded, are always kept.
+fn strip_jpeg(data: &[u8], keep_essentials: bool, keep_icc: bool) -> Result<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        bail!("Not a JPEG file");
+    }
+    let mut segments: Vec<&[u8]> = Vec::new();
+    let mut orientation = None;
+    let mut pos = 2;
+    let rest = loop {
+        let marker_bytes = data
+            .get(pos..pos + 2)
+            .ok_or_else(|| anyhow!("JPEG file ended before image data"))?;
+        if marker_bytes[0] != 0xFF {
+            bail!("Invalid JPEG marker at offset {}", pos);
+        }
+        let marker = marker_bytes[1];
+        match marker {
+            //Fill byte before a marker
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            //Start of scan or end of image, after which everything is kept as is
+            0xDA | 0xD9 => break &data[pos..],
+            //Markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                segments.push(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+        let len = data
+            .get(pos + 2..pos + 4)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+            .filter(|&len| len >= 2)
+            .ok_or_else(|| anyhow!("Truncated JPEG segment at offset {}", pos))?;
+        let segment = data
+            .get(pos..pos + 2 + len)
+            .ok_or_else(|| anyhow!("Truncated JPEG segment at offset {}", pos))?;
+        let payload = &segment[4..];
+        let keep = match marker {
+            0xE0 => payload.starts_with(b"JFIF\0"),
+            0xE1 => {
+                if payload.starts_with(JPEG_EXIF_PREFIX) {
+                    orientation = orientation.or_else(|| exif_orientation(payload));
+                }
+                false
+            }
+            0xE2 => keep_icc && payload.starts_with(JPEG_ICC_PREFIX),
+            0xEE => true,
+            0xE3..=0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            segments.push(segment);
+        }
+        pos += 2 + len;
+    };
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    let mut segments = segments.into_iter().peekable();
+    //EXIF belongs as early as possible, but after the JFIF header if there is one
+    if let Some(jfif) = segments.next_if(|segment| segment.get(1) == Some(&0xE0)) {
+        out.extend_from_slice(jfif);
+    }
+    if let Some(orientation) = orientation.filter(|_| keep_essentials) {
+        let mut payload = JPEG_EXIF_PREFIX.to_vec();
+        payload.extend(orientation_exif(orientation));
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        out.extend(payload);
+    }
+    for segment in segments {
+        out.extend_from_slice(segment);
+    }
+    out.extend_from_slice(rest);
+    Ok(out)
+}
+
+/// Writes a PNG chunk, calculating its checksum
+pub(crate) fn write_png_chunk(out: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+    out.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+/// Removes text, timestamp and EXIF chunks from a PNG file, along with the ICC profile unless
+/// keeping it
+fn strip_png(data: &[u8], keep_essentials: bool, keep_icc: bool) -> Result<Vec<u8>> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        bail!("Not a PNG file");
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+    let mut orientation = None;
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + 8)
+            .ok_or_else(|| anyhow!("Truncated PNG chunk at offset {}", pos))?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk_type = &header[4..8];
+        let chunk = data
+            .get(pos..pos + 12 + len)
+            .ok_or_else(|| anyhow!("Truncated PNG chunk at offset {}", pos))?;
+        let keep = match chunk_type {
+            b"eXIf" => {
+                orientation = exif_orientation(&chunk[8..8 + len]);
+                false
+            }
+            b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => false,
+            b"iCCP" => keep_icc,
+            _ => true,
+        };
+        //EXIF must come before the image data
+        if chunk_type == b"IDAT" {
+            if let Some(orientation) = orientation.take().filter(|_| keep_essentials) {
+                write_png_chunk(&mut out, b"eXIf", &orientation_exif(orientation));
+            }
+        }
+        if keep {
+            out.extend_from_slice(chunk);
+        }
+        pos += 12 + len;
+    }
+    Ok(out)
+}
+
+/// Flags in the extended WebP header marking which metadata chunks are present
+pub(crate) const WEBP_ICC_FLAG: u8 = 0x20;
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Writes a RIFF chunk, padding it to an even length
+pub(crate) fn write_riff_chunk(out: &mut Vec<u8>, fourcc: &[u8], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Removes EXIF and XMP chunks from a WebP file, along with the ICC profile unless keeping it.
+/// Simple WebP files can't hold metadata, so are returned unchanged.
+fn strip_webp(data: &[u8], keep_essentials: bool, keep_icc: bool) -> Result<Vec<u8>> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        bail!("Not a WebP file");
+    }
+    let mut chunks: Vec<(&[u8], Vec<u8>)> = Vec::new();
+    let mut orientation = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let fourcc = &data[pos..pos + 4];
+        let len = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]])
+            as usize;
+        let payload = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| anyhow!("Truncated WebP chunk at offset {}", pos))?;
+        match fourcc {
+            b"EXIF" => orientation = exif_orientation(payload),
+            b"XMP " => {}
+            b"ICCP" if !keep_icc => {}
+            _ => chunks.push((fourcc, payload.to_vec())),
+        }
+        pos += 8 + len + len % 2;
+    }
+    let Some((_, header)) = chunks.iter_mut().find(|(fourcc, _)| *fourcc == b"VP8X") else {
+        return Ok(data.to_vec());
+    };
+    let orientation = orientation.filter(|_| keep_essentials);
+    if let Some(flags) = header.first_mut() {
+        *flags &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+        if !keep_icc {
+            *flags &= !WEBP_ICC_FLAG;
+        }
+        if orientation.is_some() {
+            *flags |= WEBP_EXIF_FLAG;
+        }
+    }
+    if let Some(orientation) = orientation {
+        chunks.push((b"EXIF", orientation_exif(orientation)));
+    }
+
+    let mut body = b"WEBP".to_vec();
+    for (fourcc, payload) in &chunks {
+        write_riff_chunk(&mut body, fourcc, payload);
+    }
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend(body);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    use super::*;
+    use crate::{conf::StorageFormat, imaging::icc};
+
+    /// Marker left in metadata which must never survive stripping
+    const SECRET: &[u8] = b"GPS 51.5N 0.1W";
+
+    fn sample() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(6, 4, |x, y| {
+            Rgba([x as u8 * 40, y as u8 * 60, 120, 255])
+        }))
+    }
+
+    fn encode(format: StorageFormat) -> Vec<u8> {
+        format.encode(&sample(), 90).unwrap().unwrap()
+    }
+
+    fn profile() -> Vec<u8> {
+        (0..500).map(|idx| (idx % 251) as u8).collect()
+    }
+
+    /// Big endian EXIF holding a GPS pointer and an orientation, followed by data standing in for
+    /// the GPS coordinates
+    fn camera_exif(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08".to_vec();
+        tiff.extend_from_slice(&2u16.to_be_bytes());
+        for (tag, kind, value) in [(0x8825u16, 4u16, 38u32), (ORIENTATION_TAG, 3, 0)] {
+            tiff.extend_from_slice(&tag.to_be_bytes());
+            tiff.extend_from_slice(&kind.to_be_bytes());
+            tiff.extend_from_slice(&1u32.to_be_bytes());
+            if tag == ORIENTATION_TAG {
+                tiff.extend_from_slice(&orientation.to_be_bytes());
+                tiff.extend_from_slice(&[0, 0]);
+            } else {
+                tiff.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+        tiff.extend_from_slice(SECRET);
+        tiff
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    /// JPEG with camera EXIF, a comment, a Photoshop segment and an ICC profile after its JFIF
+    /// header
+    fn camera_jpeg() -> Vec<u8> {
+        let data = icc::embed(encode(StorageFormat::Jpeg), &profile()).unwrap();
+        assert_eq!(&data[2..4], &[0xFF, 0xE0]);
+        let jfif_end = 4 + u16::from_be_bytes([data[4], data[5]]) as usize;
+        let mut exif = JPEG_EXIF_PREFIX.to_vec();
+        exif.extend(camera_exif(6));
+        let mut out = data[..jfif_end].to_vec();
+        out.extend(jpeg_segment(0xE1, &exif));
+        out.extend(jpeg_segment(0xFE, SECRET));
+        out.extend(jpeg_segment(0xED, SECRET));
+        out.extend_from_slice(&data[jfif_end..]);
+        out
+    }
+
+    #[test]
+    fn strips_jpeg_metadata_keeping_orientation_and_profile() {
+        let stripped = strip(
+            &camera_jpeg(),
+            "image/jpeg",
+            MetadataPolicy::KeepOrientationIcc,
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!contains(&stripped, SECRET));
+        //The JFIF header stays first, followed by EXIF holding only the orientation
+        assert_eq!(&stripped[2..4], &[0xFF, 0xE0]);
+        let mut exif = JPEG_EXIF_PREFIX.to_vec();
+        exif.extend(orientation_exif(6));
+        assert!(contains(&stripped, &jpeg_segment(0xE1, &exif)));
+        assert_eq!(exif_orientation(&exif), Some(6));
+        assert_eq!(icc::extract(&stripped).unwrap(), Some(profile()));
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn strips_everything_from_jpeg() {
+        let stripped = strip(
+            &camera_jpeg(),
+            "image/jpeg",
+            MetadataPolicy::StripAll,
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!contains(&stripped, SECRET));
+        assert!(!contains(&stripped, JPEG_EXIF_PREFIX));
+        assert_eq!(icc::extract(&stripped).unwrap(), None);
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_jpeg_segments() {
+        let data = camera_jpeg();
+        //Cut off partway through the EXIF segment
+        let truncated = &data[..30];
+        assert!(strip(truncated, "image/jpeg", MetadataPolicy::StripAll, false).is_err());
+        //Segment lengths include themselves, so can't be less than 2
+        let mut short = vec![0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x01];
+        short.extend_from_slice(&data[2..]);
+        assert!(strip(&short, "image/jpeg", MetadataPolicy::StripAll, false).is_err());
+        //Segment claiming to run past the end of the file
+        let mut long = vec![0xFF, 0xD8, 0xFF, 0xE1, 0xFF, 0xFF, 0x00];
+        long.extend_from_slice(b"Exif");
+        assert!(strip(&long, "image/jpeg", MetadataPolicy::StripAll, false).is_err());
+        assert!(strip(
+            b"\xFF\xD8\x00\x00",
+            "image/jpeg",
+            MetadataPolicy::StripAll,
+            false
+        )
+        .is_err());
+    }
+
+    /// PNG with text, timestamp and camera EXIF chunks, along with an ICC profile
+    fn camera_png() -> Vec<u8> {
+        let data = icc::embed(encode(StorageFormat::Png), &profile()).unwrap();
+        let header_end = PNG_SIGNATURE.len() + 25;
+        let mut out = data[..header_end].to_vec();
+        write_png_chunk(
+            &mut out,
+            b"tEXt",
+            &[b"Comment\0".as_slice(), SECRET].concat(),
+        );
+        write_png_chunk(&mut out, b"tIME", &[7, 232, 1, 2, 3, 4, 5]);
+        write_png_chunk(&mut out, b"eXIf", &camera_exif(8));
+        out.extend_from_slice(&data[header_end..]);
+        out
+    }
+
+    /// Lists the types of the chunks in a PNG, in order
+    fn png_chunks(data: &[u8]) -> Vec<String> {
+        let mut chunks = Vec::new();
+        let mut pos = PNG_SIGNATURE.len();
+        while pos < data.len() {
+            let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            chunks.push(String::from_utf8_lossy(&data[pos + 4..pos + 8]).to_string());
+            pos += 12 + len;
+        }
+        chunks
+    }
+
+    #[test]
+    fn strips_png_metadata_keeping_orientation_and_profile() {
+        let stripped = strip(
+            &camera_png(),
+            "image/png",
+            MetadataPolicy::KeepOrientationIcc,
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!contains(&stripped, SECRET));
+        let chunks = png_chunks(&stripped);
+        assert_eq!(chunks[..2], ["IHDR", "iCCP"]);
+        assert!(!chunks
+            .iter()
+            .any(|chunk| chunk == "tEXt" || chunk == "tIME"));
+        //The rebuilt EXIF comes directly before the image data
+        let exif = chunks.iter().position(|chunk| chunk == "eXIf").unwrap();
+        assert_eq!(chunks[exif + 1], "IDAT");
+        assert!(contains(&stripped, &orientation_exif(8)));
+        assert_eq!(icc::extract(&stripped).unwrap(), Some(profile()));
+        let decoded = image::load_from_memory(&stripped).unwrap();
+        assert_eq!(decoded.to_rgba8(), sample().to_rgba8());
+    }
+
+    #[test]
+    fn strips_everything_from_png() {
+        let stripped = strip(&camera_png(), "image/png", MetadataPolicy::StripAll, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            png_chunks(&stripped),
+            png_chunks(&encode(StorageFormat::Png))
+        );
+        //Asking to keep the profile keeps it even when everything else goes
+        let stripped = strip(&camera_png(), "image/png", MetadataPolicy::StripAll, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(icc::extract(&stripped).unwrap(), Some(profile()));
+    }
+
+    #[test]
+    fn rejects_malformed_png_chunks() {
+        let data = camera_png();
+        assert!(strip(&data[..40], "image/png", MetadataPolicy::StripAll, false).is_err());
+        let mut long = data.clone();
+        //Claim the header chunk is longer than the whole file
+        long[PNG_SIGNATURE.len()..PNG_SIGNATURE.len() + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(strip(&long, "image/png", MetadataPolicy::StripAll, false).is_err());
+        assert!(strip(b"not a png", "image/png", MetadataPolicy::StripAll, false).is_err());
+    }
+
+    /// Extended WebP with EXIF, XMP and ICC chunks
+    fn camera_webp() -> Vec<u8> {
+        //Embedding a profile gives the simple file an extended header to flag metadata in
+        let mut data = icc::embed(encode(StorageFormat::Webp), &profile()).unwrap();
+        assert_eq!(&data[12..16], b"VP8X");
+        data[20] |= WEBP_EXIF_FLAG | WEBP_XMP_FLAG;
+        write_riff_chunk(&mut data, b"EXIF", &camera_exif(3));
+        write_riff_chunk(&mut data, b"XMP ", SECRET);
+        let size = (data.len() - 8) as u32;
+        data[4..8].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn strips_webp_metadata_keeping_orientation_and_profile() {
+        let stripped = strip(
+            &camera_webp(),
+            "image/webp",
+            MetadataPolicy::KeepOrientationIcc,
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!contains(&stripped, SECRET));
+        assert!(contains(&stripped, &orientation_exif(3)));
+        assert_eq!(stripped[20] & WEBP_XMP_FLAG, 0);
+        assert_ne!(stripped[20] & WEBP_EXIF_FLAG, 0);
+        assert_ne!(stripped[20] & WEBP_ICC_FLAG, 0);
+        assert_eq!(icc::extract(&stripped).unwrap(), Some(profile()));
+        let decoded = image::load_from_memory(&stripped).unwrap();
+        assert_eq!(decoded.to_rgba8(), sample().to_rgba8());
+    }
+
+    #[test]
+    fn strips_everything_from_webp() {
+        let stripped = strip(
+            &camera_webp(),
+            "image/webp",
+            MetadataPolicy::StripAll,
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!contains(&stripped, SECRET));
+        assert!(!contains(&stripped, b"EXIF"));
+        assert_eq!(
+            stripped[20] & (WEBP_EXIF_FLAG | WEBP_XMP_FLAG | WEBP_ICC_FLAG),
+            0
+        );
+        assert_eq!(icc::extract(&stripped).unwrap(), None);
+        assert!(image::load_from_memory(&stripped).is_ok());
+        //Simple files can't hold metadata, so are left alone
+        let simple = encode(StorageFormat::Webp);
+        assert_eq!(
+            strip(&simple, "image/webp", MetadataPolicy::StripAll, false).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_webp_chunks() {
+        let data = camera_webp();
+        assert!(strip(&data[..40], "image/webp", MetadataPolicy::StripAll, false).is_err());
+        assert!(strip(
+            b"RIFF\0\0\0\0WEB",
+            "image/webp",
+            MetadataPolicy::StripAll,
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reads_orientation_from_either_byte_order() {
+        assert_eq!(exif_orientation(&camera_exif(6)), Some(6));
+        assert_eq!(exif_orientation(&orientation_exif(5)), Some(5));
+        //The default orientation needs no EXIF to keep it
+        assert_eq!(exif_orientation(&orientation_exif(1)), None);
+        //IFD offset pointing past the end of the data
+        assert_eq!(exif_orientation(b"II\x2a\x00\xff\xff\x00\x00"), None);
+        assert_eq!(exif_orientation(b"XX"), None);
+    }
+
+    #[test]
+    fn leaves_files_alone_when_keeping_everything_or_unsupported() {
+        let data = camera_png();
+        assert_eq!(
+            strip(&data, "image/png", MetadataPolicy::KeepAll, false).unwrap(),
+            None
+        );
+        assert_eq!(
+            strip(b"GIF89a", "image/gif", MetadataPolicy::StripAll, false).unwrap(),
+            None
+        );
+    }
+}
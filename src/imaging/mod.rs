@@ -0,0 +1,183 @@
+//! Processing applied to uploaded images before they are stored
+
+pub mod animation;
+pub mod annotate;
+pub mod icc;
+pub mod metadata;
+pub mod phash;
+pub mod placeholder;
+pub mod redact;
+pub mod thumbnail;
+pub mod transcode;
+pub mod trim;
+pub mod variant;
+pub mod watermark;
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+use image::{io::Limits, DynamicImage, Rgba, RgbaImage};
+use serde_derive::Deserialize;
+
+use crate::{
+    conf::{Config, DecodeLimits},
+    storage,
+};
+
+/// Name of the hidden directory within each storage directory that uploads are kept in, as they
+/// were received, when the stored file has been altered
+pub static ORIGINALS_DIR_NAME: &str = ".originals";
+
+/// A colour given as a hex string such as `#ff0000`, or `#ff000080` to include opacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Colour(pub Rgba<u8>);
+
+impl TryFrom<String> for Colour {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value.trim_start_matches('#');
+        let channel = |idx: usize| {
+            hex.get(idx * 2..idx * 2 + 2)
+                .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+        };
+        let parsed = match hex.len() {
+            6 => channel(0)
+                .zip(channel(1))
+                .zip(channel(2))
+                .map(|((r, g), b)| [r, g, b, 255]),
+            8 => channel(0)
+                .zip(channel(1))
+                .zip(channel(2))
+                .zip(channel(3))
+                .map(|(((r, g), b), a)| [r, g, b, a]),
+            _ => None,
+        };
+        parsed
+            .map(|rgba| Colour(Rgba(rgba)))
+            .ok_or_else(|| format!("Invalid colour {}, expected #rrggbb or #rrggbbaa", value))
+    }
+}
+
+/// Edits an image as 8 bit RGBA, converting it back afterwards so images without transparency
+/// don't gain an alpha channel
+pub(crate) fn edit_rgba(img: &mut DynamicImage, edit: impl FnOnce(&mut RgbaImage)) {
+    let mut rgba = img.to_rgba8();
+    edit(&mut rgba);
+    let edited = DynamicImage::ImageRgba8(rgba);
+    *img = if img.color().has_alpha() {
+        edited
+    } else {
+        DynamicImage::ImageRgb8(edited.to_rgb8())
+    };
+}
+
+impl DecodeLimits {
+    /// Returns the limits in the form used by image decoders
+    pub fn to_image_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+
+    /// Returns true if an image with the given dimensions is too large to decode
+    pub fn exceeded_by(&self, width: u32, height: u32) -> bool {
+        width > self.max_width || height > self.max_height
+    }
+}
+
+/// Decodes a stored image within the configured limits, detecting its format from its contents
+pub(crate) fn decode(path: &Path, limits: &DecodeLimits) -> Result<DynamicImage> {
+    let mut reader = image::io::Reader::open(path)?.with_guessed_format()?;
+    reader.limits(limits.to_image_limits());
+    Ok(reader.decode()?)
+}
+
+/// Decodes image data held in memory within the configured limits, detecting its format from its
+/// contents
+pub(crate) fn decode_data(data: &[u8], limits: &DecodeLimits) -> Result<DynamicImage> {
+    let mut reader = image::io::Reader::new(std::io::Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits.to_image_limits());
+    Ok(reader.decode()?)
+}
+
+/// Decodes a stored image within the configured limits, then handles its embedded colour profile
+/// according to the configured policy. Returns the profile copies of the image should carry, if
+/// any.
+pub(crate) fn decode_managed(
+    path: &Path,
+    config: &Config,
+) -> Result<(DynamicImage, Option<Vec<u8>>)> {
+    let data = std::fs::read(path)?;
+    let mut reader = image::io::Reader::new(std::io::Cursor::new(&data)).with_guessed_format()?;
+    reader.limits(config.decode_limits.to_image_limits());
+    let mut img = reader.decode()?;
+    let profile = icc::manage(config.colour_profiles, &data, &mut img);
+    Ok((img, profile))
+}
+
+/// Returns the modification time of a file, if it exists
+pub(crate) fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+/// Writes a derived image into a cache through a temporary file, so that partially written files
+/// are never served
+pub(crate) fn write_cached(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("Cache path has no parent directory"))?;
+    std::fs::create_dir_all(dir)?;
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    std::io::Write::write_all(&mut temp_file, data)?;
+    temp_file.persist(path)?;
+    Ok(())
+}
+
+/// Removes files from a cache directory which were derived with the same key and extension as the
+/// file at `current`, but from an earlier version of their source, so will never be served again
+pub(crate) fn remove_stale(current: &Path, key: &str, extension: &str) {
+    let Some(dir) = current.parent() else {
+        return;
+    };
+    let prefix = format!("{}-", key);
+    let suffix = format!(".{}", extension);
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(&prefix) && name.ends_with(&suffix) && entry.path() != current {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// Removes files from a cache directory laid out as `<image path>/<file>` whose image no longer
+/// exists in any storage directory. Returns the number removed.
+pub(crate) fn remove_orphaned(config: &Config, cache_dir: Option<PathBuf>) -> usize {
+    let Some(cache_dir) = cache_dir.filter(|dir| dir.is_dir()) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for cached in storage::list_files(&cache_dir) {
+        let Some(rel_path) = cached.relative_path.parent() else {
+            continue;
+        };
+        if storage::exists_in_any_root(config, rel_path) {
+            continue;
+        }
+        match std::fs::remove_file(&cached.path) {
+            Ok(()) => removed += 1,
+            Err(e) => {
+                tracing::warn!(error = %e, path = ?cached.path, "Failed to remove cached file")
+            }
+        }
+    }
+    removed
+}
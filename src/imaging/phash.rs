@@ -0,0 +1,136 @@
+//! Perceptual hashes of image contents, which stay close for images that look alike even when
+//! their files differ, such as the same window captured twice with a blinking cursor
+
+use image::DynamicImage;
+use serde_derive::Serialize;
+
+/// Hamming distance at or below which images are treated as near-duplicates unless another is given
+pub const DEFAULT_MAX_DISTANCE: u32 = 6;
+
+/// 64 bit difference hash of an image, serialized as 16 hex digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(into = "String")]
+pub struct PerceptualHash(pub u64);
+
+impl PerceptualHash {
+    /// Computes the difference hash (dHash) of an image. The image is shrunk to 9x8 greyscale
+    /// pixels, and each bit records whether a pixel is darker than its right hand neighbour, so
+    /// the hash depends on the image's structure rather than its exact colours or size.
+    pub fn of(img: &DynamicImage) -> Self {
+        let small = img.thumbnail_exact(9, 8).into_luma8();
+        let mut hash = 0u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                let darker = small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0];
+                hash = (hash << 1) | darker as u64;
+            }
+        }
+        Self(hash)
+    }
+
+    /// Parses a hash from its hex representation
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        u64::from_str_radix(hex, 16).ok().map(Self)
+    }
+
+    /// Returns the number of bits which differ between two hashes, from 0 for images which look
+    /// the same up to 64
+    pub fn distance(&self, other: &PerceptualHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+impl From<PerceptualHash> for String {
+    fn from(hash: PerceptualHash) -> Self {
+        format!("{:016x}", hash.0)
+    }
+}
+
+/// Groups hashes which are within `max_distance` of each other, either directly or through a
+/// chain of other hashes. Returns the indexes of the hashes in each group of two or more.
+pub fn cluster(hashes: &[PerceptualHash], max_distance: u32) -> Vec<Vec<usize>> {
+    //Union-find over every pair of hashes which are close enough
+    let mut parents: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parents: &mut [usize], mut idx: usize) -> usize {
+        while parents[idx] != idx {
+            parents[idx] = parents[parents[idx]];
+            idx = parents[idx];
+        }
+        idx
+    }
+    for a in 0..hashes.len() {
+        for b in a + 1..hashes.len() {
+            if hashes[a].distance(&hashes[b]) <= max_distance {
+                let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+                parents[root_b] = root_a;
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of_root = std::collections::HashMap::new();
+    for idx in 0..hashes.len() {
+        let root = root(&mut parents, idx);
+        let group = *group_of_root.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(idx);
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    /// Greyscale image getting brighter or darker from left to right
+    fn gradient(width: u32, height: u32, brightening: bool) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, _| {
+            let level = (x * 255 / (width - 1)) as u8;
+            Luma([if brightening { level } else { 255 - level }])
+        }))
+    }
+
+    #[test]
+    fn distance_counts_differing_bits() {
+        let hash = PerceptualHash(0b1011);
+        assert_eq!(hash.distance(&hash), 0);
+        assert_eq!(hash.distance(&PerceptualHash(0b0010)), 2);
+        assert_eq!(PerceptualHash(0).distance(&PerceptualHash(u64::MAX)), 64);
+    }
+
+    #[test]
+    fn hashes_structure_rather_than_size() {
+        let small = PerceptualHash::of(&gradient(90, 80, true));
+        let large = PerceptualHash::of(&gradient(900, 400, true));
+        assert_eq!(small, PerceptualHash(u64::MAX));
+        assert_eq!(small.distance(&large), 0);
+        let reversed = PerceptualHash::of(&gradient(90, 80, false));
+        assert_eq!(small.distance(&reversed), 64);
+    }
+
+    #[test]
+    fn round_trips_through_hex() {
+        let hash = PerceptualHash(0x00f0_1234_abcd_0001);
+        let hex = String::from(hash);
+        assert_eq!(hex, "00f01234abcd0001");
+        assert_eq!(PerceptualHash::from_hex(&hex), Some(hash));
+        assert_eq!(PerceptualHash::from_hex("not hex"), None);
+    }
+
+    #[test]
+    fn clusters_chains_of_close_hashes() {
+        let hashes = [
+            PerceptualHash(0b000000),
+            PerceptualHash(0b000111),
+            PerceptualHash(0b111111),
+            PerceptualHash(u64::MAX),
+        ];
+        assert_eq!(cluster(&hashes, 3), vec![vec![0, 1, 2]]);
+        assert!(cluster(&hashes, 2).is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+//! Compact placeholders shown by galleries and embeds while an image loads: a BlurHash, along
+//! with the average colour of the image and its most common colour
+
+use std::collections::HashMap;
+
+use image::{DynamicImage, RgbaImage};
+use serde_derive::Serialize;
+
+/// Size in pixels of the square images are shrunk to fit before being summarised. Placeholders
+/// are blurry anyway, so using more pixels would only make them slower to compute.
+const SAMPLE_SIZE: u32 = 32;
+/// Number of BlurHash components along the longer side of an image
+const MAX_COMPONENTS: u32 = 4;
+/// Number of low bits dropped from each channel when grouping similar colours
+const COLOUR_BUCKET_SHIFT: u8 = 4;
+
+/// Placeholder for an image, with colours given as `#rrggbb` hex strings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Placeholder {
+    /// BlurHash of the image, which decodes to a blurred approximation of it
+    pub blurhash: String,
+    /// Mean colour of the image's opaque pixels
+    pub average_colour: String,
+    /// Colour covering the largest area of the image
+    pub dominant_colour: String,
+}
+
+impl Placeholder {
+    /// Computes the placeholder for an image. Returns `None` for images with no visible pixels.
+    pub fn of(img: &DynamicImage) -> Option<Self> {
+        let sample = img.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).into_rgba8();
+        let (width, height) = sample.dimensions();
+        let (x_components, y_components) = components(width, height);
+        let blurhash =
+            blurhash::encode(x_components, y_components, width, height, sample.as_raw()).ok()?;
+        Some(Self {
+            blurhash,
+            average_colour: hex_colour(average_colour(&sample)?),
+            dominant_colour: hex_colour(dominant_colour(&sample)?),
+        })
+    }
+}
+
+/// Chooses the number of BlurHash components along each side of an image. Fewer are used along the
+/// shorter side, so the blur keeps the image's proportions.
+fn components(width: u32, height: u32) -> (u32, u32) {
+    let longer = width.max(height).max(1);
+    let along = |side: u32| {
+        (MAX_COMPONENTS * side)
+            .div_ceil(longer)
+            .clamp(1, MAX_COMPONENTS)
+    };
+    (along(width), along(height))
+}
+
+/// Formats a colour as a `#rrggbb` hex string
+fn hex_colour([r, g, b]: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Adds pixels to a running total of their colour channels, weighted by opacity
+#[derive(Debug, Default, Clone, Copy)]
+struct ColourSum {
+    channels: [u64; 3],
+    weight: u64,
+}
+
+impl ColourSum {
+    fn add(&mut self, [r, g, b, a]: [u8; 4]) {
+        for (sum, channel) in self.channels.iter_mut().zip([r, g, b]) {
+            *sum += channel as u64 * a as u64;
+        }
+        self.weight += a as u64;
+    }
+
+    fn mean(&self) -> Option<[u8; 3]> {
+        (self.weight > 0).then(|| self.channels.map(|sum| (sum / self.weight) as u8))
+    }
+}
+
+/// Returns the mean colour of an image, ignoring transparent pixels
+fn average_colour(img: &RgbaImage) -> Option<[u8; 3]> {
+    let mut sum = ColourSum::default();
+    for pixel in img.pixels() {
+        sum.add(pixel.0);
+    }
+    sum.mean()
+}
+
+/// Groups the pixels of an image into buckets of similar colours, returning the mean colour of the
+/// bucket covering the largest area
+fn dominant_colour(img: &RgbaImage) -> Option<[u8; 3]> {
+    let mut buckets: HashMap<[u8; 3], ColourSum> = HashMap::new();
+    for pixel in img.pixels() {
+        let [r, g, b, _] = pixel.0;
+        let key = [r, g, b].map(|channel| channel >> COLOUR_BUCKET_SHIFT);
+        buckets.entry(key).or_default().add(pixel.0);
+    }
+    buckets
+        .into_iter()
+        .max_by_key(|(key, sum)| (sum.weight, *key))
+        .and_then(|(_, sum)| sum.mean())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn chooses_components_by_aspect_ratio() {
+        assert_eq!(components(32, 32), (4, 4));
+        assert_eq!(components(32, 16), (4, 2));
+        assert_eq!(components(10, 32), (2, 4));
+        //Very thin images still get at least one component along their shorter side
+        assert_eq!(components(32, 1), (4, 1));
+    }
+
+    #[test]
+    fn blurhash_encodes_chosen_components() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 16, |x, _| {
+            Rgba([(x * 4) as u8, 100, 50, 255])
+        }));
+        let placeholder = Placeholder::of(&img).unwrap();
+        //The first character encodes the components as (x - 1) + (y - 1) * 9, then each component
+        //takes two characters after the four header characters
+        assert!(placeholder.blurhash.starts_with('3'));
+        assert_eq!(placeholder.blurhash.len(), 4 + 2 * 4);
+    }
+
+    #[test]
+    fn dominant_colour_groups_similar_shades() {
+        //Three shades of red which share a bucket outnumber the single shade of blue, even though
+        //each shade on its own covers less of the image
+        let img = RgbaImage::from_fn(50, 1, |x, _| match x {
+            0..=9 => Rgba([200, 0, 0, 255]),
+            10..=19 => Rgba([203, 0, 0, 255]),
+            20..=29 => Rgba([206, 0, 0, 255]),
+            _ => Rgba([0, 0, 255, 255]),
+        });
+        assert_eq!(dominant_colour(&img), Some([203, 0, 0]));
+        assert_eq!(average_colour(&img), Some([121, 0, 102]));
+    }
+
+    #[test]
+    fn transparent_pixels_are_ignored() {
+        let img = RgbaImage::from_fn(10, 1, |x, _| match x {
+            0..=1 => Rgba([0, 255, 0, 255]),
+            _ => Rgba([255, 255, 255, 0]),
+        });
+        assert_eq!(dominant_colour(&img), Some([0, 255, 0]));
+        assert_eq!(average_colour(&img), Some([0, 255, 0]));
+        let invisible = DynamicImage::ImageRgba8(RgbaImage::new(8, 8));
+        assert_eq!(Placeholder::of(&invisible), None);
+    }
+
+    #[test]
+    fn formats_hex_colours() {
+        assert_eq!(hex_colour([0, 128, 255]), "#0080ff");
+    }
+}
@@ -0,0 +1,183 @@
+//! Hiding regions of an image, such as email addresses or tokens in a screenshot, before it is
+//! shared
+
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+use serde_derive::Deserialize;
+
+use super::{edit_rgba, Colour};
+
+/// Default size in pixels of the blocks pixelated regions are divided into
+const DEFAULT_BLOCK_SIZE: u32 = 12;
+/// Default standard deviation of the blur applied to blurred regions
+const DEFAULT_BLUR_SIGMA: u32 = 12;
+/// Largest block size or blur standard deviation a client may ask for. Blurring takes longer the
+/// larger the deviation, and anything beyond this hides text no better.
+pub const MAX_STRENGTH: u32 = 100;
+
+/// A rectangular region of an image, in pixels
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Region {
+    /// Distance of the left edge from the left of the image
+    pub x: u32,
+    /// Distance of the top edge from the top of the image
+    pub y: u32,
+    /// Width of the region
+    pub width: u32,
+    /// Height of the region
+    pub height: u32,
+}
+
+impl Region {
+    /// Returns the part of the region which lies within an image of the given size, or `None` if
+    /// it lies entirely outside
+    pub fn clamp(&self, width: u32, height: u32) -> Option<Region> {
+        let right = self.x.saturating_add(self.width).min(width);
+        let bottom = self.y.saturating_add(self.height).min(height);
+        (self.x < right && self.y < bottom).then(|| Region {
+            x: self.x,
+            y: self.y,
+            width: right - self.x,
+            height: bottom - self.y,
+        })
+    }
+}
+
+/// How redacted regions are hidden
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactMode {
+    /// Fill the region with a solid colour
+    #[default]
+    Solid,
+    /// Replace the region with large blocks of its average colour
+    Pixelate,
+    /// Blur the region
+    Blur,
+}
+
+/// Settings for a redaction
+#[derive(Debug, Clone, Copy)]
+pub struct Redaction {
+    /// How the regions are hidden
+    pub mode: RedactMode,
+    /// Colour solid regions are filled with
+    pub colour: Colour,
+    /// Block size for pixelation or standard deviation for blurring, defaulting to a value which
+    /// leaves text unreadable
+    pub strength: Option<u32>,
+}
+
+impl Redaction {
+    /// Checks the requested strength is within the limit, returning a description of the problem
+    /// if not
+    pub fn validate(&self) -> Result<(), String> {
+        match self.strength {
+            Some(strength) if strength > MAX_STRENGTH => {
+                Err(format!("strength must be at most {}", MAX_STRENGTH))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Hides the given regions of an image. Parts of regions outside the image are ignored.
+pub fn redact(img: &mut DynamicImage, regions: &[Region], redaction: Redaction) {
+    edit_rgba(img, |rgba| {
+        let (width, height) = rgba.dimensions();
+        for region in regions.iter().filter_map(|r| r.clamp(width, height)) {
+            match redaction.mode {
+                RedactMode::Solid => fill(rgba, region, redaction.colour.0),
+                RedactMode::Pixelate => pixelate(
+                    rgba,
+                    region,
+                    redaction.strength.unwrap_or(DEFAULT_BLOCK_SIZE).max(2),
+                ),
+                RedactMode::Blur => blur(
+                    rgba,
+                    region,
+                    redaction.strength.unwrap_or(DEFAULT_BLUR_SIGMA).max(1) as f32,
+                ),
+            }
+        }
+    });
+}
+
+fn fill(img: &mut RgbaImage, region: Region, colour: Rgba<u8>) {
+    for y in region.y..region.y + region.height {
+        for x in region.x..region.x + region.width {
+            img.put_pixel(x, y, colour);
+        }
+    }
+}
+
+fn pixelate(img: &mut RgbaImage, region: Region, block_size: u32) {
+    for block_y in (region.y..region.y + region.height).step_by(block_size as usize) {
+        for block_x in (region.x..region.x + region.width).step_by(block_size as usize) {
+            let block = Region {
+                x: block_x,
+                y: block_y,
+                width: block_size.min(region.x + region.width - block_x),
+                height: block_size.min(region.y + region.height - block_y),
+            };
+            let mut totals = [0u64; 4];
+            for y in block.y..block.y + block.height {
+                for x in block.x..block.x + block.width {
+                    for (total, channel) in totals.iter_mut().zip(img.get_pixel(x, y).0) {
+                        *total += channel as u64;
+                    }
+                }
+            }
+            let count = (block.width * block.height) as u64;
+            fill(img, block, Rgba(totals.map(|total| (total / count) as u8)));
+        }
+    }
+}
+
+fn blur(img: &mut RgbaImage, region: Region, sigma: f32) {
+    let section = imageops::crop_imm(img, region.x, region.y, region.width, region.height);
+    let blurred = imageops::blur(&section.to_image(), sigma);
+    imageops::replace(img, &blurred, region.x as i64, region.y as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redaction(mode: RedactMode, strength: Option<u32>) -> Redaction {
+        Redaction {
+            mode,
+            colour: Colour(Rgba([0, 0, 0, 255])),
+            strength,
+        }
+    }
+
+    #[test]
+    fn rejects_excessive_strength() {
+        assert!(redaction(RedactMode::Blur, None).validate().is_ok());
+        assert!(redaction(RedactMode::Blur, Some(MAX_STRENGTH))
+            .validate()
+            .is_ok());
+        assert!(redaction(RedactMode::Blur, Some(MAX_STRENGTH + 1))
+            .validate()
+            .is_err());
+        assert!(redaction(RedactMode::Pixelate, Some(4_000_000_000))
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn redacts_only_the_overlapping_part_of_regions() {
+        let mut img =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(10, 10, Rgba([255, 255, 255, 255])));
+        let region = Region {
+            x: 8,
+            y: 8,
+            width: 50,
+            height: 50,
+        };
+        redact(&mut img, &[region], redaction(RedactMode::Solid, None));
+        let rgba = img.to_rgba8();
+        assert_eq!(rgba.get_pixel(9, 9), &Rgba([0, 0, 0, 255]));
+        assert_eq!(rgba.get_pixel(7, 7), &Rgba([255, 255, 255, 255]));
+    }
+}
@@ -0,0 +1,209 @@
+//! Scaled down copies of stored images, cached in a directory which is never served directly.
+//! Each configured size has its own directory within the cache, mirroring the layout of the
+//! storage directories. Full size stills of the first frame, used as posters for animated images,
+//! are cached the same way.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use image::DynamicImage;
+
+use super::{decode_managed, icc, modified, write_cached as write_thumbnail};
+use crate::{conf::Config, index::DATA_DIR_NAME, storage};
+
+/// Name of the default thumbnail cache directory, within the hidden data directory
+static THUMBNAIL_DIR_NAME: &str = "thumbnails";
+/// Extension of cached thumbnails, which are stored as lossless WebP
+static THUMBNAIL_EXTENSION: &str = "webp";
+/// Name of the directory within the cache holding full size posters
+static POSTER_DIR_NAME: &str = "poster";
+
+/// Returns the directory thumbnails are cached in, or `None` if no target directory is configured
+pub fn cache_dir(config: &Config) -> Option<PathBuf> {
+    match (&config.thumbnails.cache_dir, &config.target_dir) {
+        (Some(dir), _) => Some(PathBuf::from(dir)),
+        (None, Some(tgt_dir)) => Some(
+            PathBuf::from(tgt_dir)
+                .join(DATA_DIR_NAME)
+                .join(THUMBNAIL_DIR_NAME),
+        ),
+        (None, None) => None,
+    }
+}
+
+/// Returns where a still is cached within the given directory of the cache for a file, given its
+/// path relative to the storage directories
+fn cached_path(config: &Config, dir_name: &str, rel_path: &Path) -> Option<PathBuf> {
+    let mut file_name = rel_path.file_name()?.to_os_string();
+    file_name.push(".");
+    file_name.push(THUMBNAIL_EXTENSION);
+    Some(
+        cache_dir(config)?
+            .join(dir_name)
+            .join(rel_path)
+            .with_file_name(file_name),
+    )
+}
+
+/// Returns where the thumbnail of the given size is cached for a file, given its path relative to
+/// the storage directories
+pub fn thumbnail_path(config: &Config, size: u32, rel_path: &Path) -> Option<PathBuf> {
+    cached_path(config, &size.to_string(), rel_path)
+}
+
+/// Returns where the poster is cached for a file, given its path relative to the storage directories
+pub fn poster_path(config: &Config, rel_path: &Path) -> Option<PathBuf> {
+    cached_path(config, POSTER_DIR_NAME, rel_path)
+}
+
+/// Scales an image down to fit within a square of the given size, keeping its aspect ratio, and
+/// encodes it with the given colour profile. Images which already fit are encoded at their
+/// original size.
+pub fn render(img: &DynamicImage, size: u32, profile: Option<&[u8]>) -> Result<Vec<u8>> {
+    let rgba = if img.width() > size || img.height() > size {
+        img.thumbnail(size, size).to_rgba8()
+    } else {
+        img.to_rgba8()
+    };
+    let mut data = Vec::new();
+    image_webp::WebPEncoder::new(&mut data).encode(
+        rgba.as_raw(),
+        rgba.width(),
+        rgba.height(),
+        image_webp::ColorType::Rgba8,
+    )?;
+    Ok(icc::attach(data, profile))
+}
+
+/// Writes thumbnails of every configured size for a stored image, carrying its colour profile
+pub fn write_all(
+    config: &Config,
+    rel_path: &Path,
+    img: &DynamicImage,
+    profile: Option<&[u8]>,
+) -> Result<()> {
+    for &size in &config.thumbnails.sizes {
+        let path = thumbnail_path(config, size, rel_path)
+            .ok_or_else(|| anyhow!("No thumbnail cache directory is configured"))?;
+        write_thumbnail(&path, &render(img, size, profile)?)?;
+    }
+    Ok(())
+}
+
+/// Writes the full size poster of a stored image, showing the first frame if it is animated
+pub fn write_poster(
+    config: &Config,
+    rel_path: &Path,
+    img: &DynamicImage,
+    profile: Option<&[u8]>,
+) -> Result<()> {
+    let path = poster_path(config, rel_path)
+        .ok_or_else(|| anyhow!("No thumbnail cache directory is configured"))?;
+    write_thumbnail(&path, &render(img, u32::MAX, profile)?)
+}
+
+/// Returns true if a cached thumbnail is missing or older than the file it was made from
+fn is_stale(thumbnail: &Path, source: &Path) -> bool {
+    match (modified(thumbnail), modified(source)) {
+        (Some(thumbnail), Some(source)) => thumbnail < source,
+        (None, _) => true,
+        (Some(_), None) => false,
+    }
+}
+
+/// Returns the path of an up to date thumbnail of a stored file, generating it first if needed.
+/// `rel_path` is relative to the storage directories.
+pub fn ensure(config: &Config, size: u32, rel_path: &Path) -> Result<PathBuf> {
+    let path = thumbnail_path(config, size, rel_path)
+        .ok_or_else(|| anyhow!("No thumbnail cache directory is configured"))?;
+    ensure_still(config, path, size, rel_path)
+}
+
+/// Returns the path of an up to date poster of a stored file, generating it first if needed.
+/// `rel_path` is relative to the storage directories.
+pub fn ensure_poster(config: &Config, rel_path: &Path) -> Result<PathBuf> {
+    let path = poster_path(config, rel_path)
+        .ok_or_else(|| anyhow!("No thumbnail cache directory is configured"))?;
+    //Every image fits within the largest possible square, so is kept at full size
+    ensure_still(config, path, u32::MAX, rel_path)
+}
+
+/// Regenerates the still cached at `path` from a stored file if it is missing or out of date
+fn ensure_still(config: &Config, path: PathBuf, size: u32, rel_path: &Path) -> Result<PathBuf> {
+    let source = storage::find_root(config, rel_path)
+        .map(|root| root.join(rel_path))
+        .ok_or_else(|| anyhow!("No stored file at {}", rel_path.display()))?;
+    if is_stale(&path, &source) {
+        let (img, profile) = decode_managed(&source, config)?;
+        write_thumbnail(&path, &render(&img, size, profile.as_deref())?)?;
+        tracing::debug!(path = ?rel_path, size, "Generated thumbnail");
+    }
+    Ok(path)
+}
+
+/// Regenerates every thumbnail of a stored file which is missing or out of date. Returns true if
+/// any were written.
+pub fn refresh(config: &Config, source: &Path, rel_path: &Path) -> Result<bool> {
+    let stale: Vec<(u32, PathBuf)> = config
+        .thumbnails
+        .sizes
+        .iter()
+        .filter_map(|&size| Some((size, thumbnail_path(config, size, rel_path)?)))
+        .filter(|(_, path)| is_stale(path, source))
+        .collect();
+    if stale.is_empty() {
+        return Ok(false);
+    }
+    let (img, profile) = decode_managed(source, config)?;
+    for (size, path) in stale {
+        write_thumbnail(&path, &render(&img, size, profile.as_deref())?)?;
+    }
+    Ok(true)
+}
+
+/// Removes cached thumbnails and posters whose stored file no longer exists. Returns the number
+/// removed.
+pub fn remove_orphans(config: &Config) -> usize {
+    let Some(cache_dir) = cache_dir(config) else {
+        return 0;
+    };
+    let mut removed = 0;
+    let dir_names = config
+        .thumbnails
+        .sizes
+        .iter()
+        .map(u32::to_string)
+        .chain(std::iter::once(POSTER_DIR_NAME.to_owned()));
+    for dir_name in dir_names {
+        let size_dir = cache_dir.join(dir_name);
+        if !size_dir.is_dir() {
+            continue;
+        }
+        for thumbnail in storage::list_files(&size_dir) {
+            let rel_path = thumbnail
+                .relative_path
+                .with_extension("")
+                .to_string_lossy()
+                .to_string();
+            if storage::exists_in_any_root(config, &rel_path) {
+                continue;
+            }
+            match std::fs::remove_file(&thumbnail.path) {
+                Ok(()) => removed += 1,
+                Err(e) => {
+                    tracing::warn!(error = %e, path = ?thumbnail.path, "Failed to remove thumbnail")
+                }
+            }
+        }
+    }
+    removed
+}
+
+/// Returns the size of thumbnail to serve for a request, which must be one of the configured sizes.
+/// Defaults to the first configured size.
+pub fn choose_size(config: &Config, requested: Option<u32>) -> Option<u32> {
+    match requested {
+        Some(size) => config.thumbnails.sizes.contains(&size).then_some(size),
+        None => config.thumbnails.sizes.first().copied(),
+    }
+}
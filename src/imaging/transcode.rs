@@ -0,0 +1,116 @@
+//! Conversion of uploads into the format they should be stored in, according to the configured
+//! transcoding rules
+
+use std::{io::Cursor, path::Path};
+
+use anyhow::Result;
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageOutputFormat};
+
+use crate::{
+    conf::{Config, StorageFormat, TranscodeRule},
+    storage::routing::mime_matches,
+};
+
+/// An image which has been encoded in a new format
+#[derive(Debug)]
+pub struct Transcoded {
+    /// Encoded image data
+    pub data: Vec<u8>,
+    /// MIME type of the new format
+    pub mime_type: &'static str,
+    /// File extension of the new format
+    pub extension: &'static str,
+}
+
+/// Finds the first transcoding rule covering an upload with the given MIME type, routed to the
+/// given subdirectory
+pub fn find_rule<'c>(
+    config: &'c Config,
+    mime_type: &str,
+    subdirectory: Option<&Path>,
+) -> Option<&'c TranscodeRule> {
+    config.transcode_rules.iter().find(|rule| {
+        let mime_ok = rule
+            .mime
+            .as_ref()
+            .is_none_or(|mime| mime_matches(&mime.to_lowercase(), &mime_type.to_lowercase()));
+        let subdir_ok = rule
+            .subdirectory
+            .as_ref()
+            .is_none_or(|subdir| subdirectory.is_some_and(|dir| dir.starts_with(subdir)));
+        mime_ok && subdir_ok
+    })
+}
+
+impl StorageFormat {
+    /// Returns the MIME type and file extension of the format, or `None` for [`StorageFormat::Original`]
+    pub fn mime_and_extension(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            StorageFormat::Original => None,
+            StorageFormat::Png => Some(("image/png", "png")),
+            StorageFormat::Jpeg => Some(("image/jpeg", "jpg")),
+            StorageFormat::Webp => Some(("image/webp", "webp")),
+        }
+    }
+
+    /// Returns the format matching a MIME type, if uploads can be stored in it
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        match mime_type {
+            "image/png" => Some(StorageFormat::Png),
+            "image/jpeg" => Some(StorageFormat::Jpeg),
+            "image/webp" => Some(StorageFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// Encodes an image in the format, using the given quality for lossy formats. Returns `None`
+    /// for [`StorageFormat::Original`].
+    pub fn encode(&self, img: &DynamicImage, quality: u8) -> Result<Option<Vec<u8>>> {
+        let mut data = Vec::new();
+        match self {
+            StorageFormat::Original => return Ok(None),
+            StorageFormat::Png => {
+                img.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)?
+            }
+            StorageFormat::Jpeg => {
+                //JPEG has no alpha channel, so transparent areas are flattened
+                let rgb = img.to_rgb8();
+                JpegEncoder::new_with_quality(&mut data, quality.clamp(1, 100))
+                    .encode_image(&rgb)?;
+            }
+            StorageFormat::Webp => {
+                let rgba = img.to_rgba8();
+                image_webp::WebPEncoder::new(&mut data).encode(
+                    rgba.as_raw(),
+                    rgba.width(),
+                    rgba.height(),
+                    image_webp::ColorType::Rgba8,
+                )?;
+            }
+        }
+        Ok(Some(data))
+    }
+}
+
+/// Encodes an image as required by a transcoding rule. Returns `None` if the rule keeps the
+/// original, or the image is already stored in the requested format.
+pub fn transcode(
+    img: &DynamicImage,
+    rule: &TranscodeRule,
+    current_mime_type: &str,
+) -> Result<Option<Transcoded>> {
+    let Some((mime_type, extension)) = rule.format.mime_and_extension() else {
+        return Ok(None);
+    };
+    if mime_type == current_mime_type {
+        return Ok(None);
+    }
+    let Some(data) = rule.format.encode(img, rule.quality)? else {
+        return Ok(None);
+    };
+    Ok(Some(Transcoded {
+        data,
+        mime_type,
+        extension,
+    }))
+}
@@ -0,0 +1,207 @@
+//! Detection and removal of uniform borders around screenshots, such as the solid frame or drop
+//! shadow added around window captures
+
+use image::{DynamicImage, GenericImageView, Rgba};
+use serde_derive::Serialize;
+
+/// Number of pixels removed from each edge of an image
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TrimMargins {
+    /// Columns removed from the left edge
+    pub left: u32,
+    /// Rows removed from the top edge
+    pub top: u32,
+    /// Columns removed from the right edge
+    pub right: u32,
+    /// Rows removed from the bottom edge
+    pub bottom: u32,
+}
+
+impl TrimMargins {
+    /// Returns true if nothing is removed
+    pub fn is_empty(&self) -> bool {
+        *self == TrimMargins::default()
+    }
+}
+
+/// Returns true if every channel of two colours differs by no more than the tolerance
+fn similar(a: Rgba<u8>, b: Rgba<u8>, tolerance: u8) -> bool {
+    a.0.iter().zip(b.0).all(|(a, b)| a.abs_diff(b) <= tolerance)
+}
+
+/// Counts the lines of pixels at one edge of an image which form a uniform border. `line` returns
+/// the pixels of the line at the given distance from the edge. Each line must be uniform within
+/// the tolerance and close to the colour of the line outside it, so gradual shadows are removed
+/// along with solid borders.
+fn border_width(lines: u32, tolerance: u8, line: impl Fn(u32) -> Vec<Rgba<u8>>) -> u32 {
+    let mut previous: Option<Rgba<u8>> = None;
+    for distance in 0..lines {
+        let pixels = line(distance);
+        let Some(&colour) = pixels.first() else {
+            return distance;
+        };
+        let uniform = pixels
+            .iter()
+            .all(|&pixel| similar(pixel, colour, tolerance));
+        let continues = previous.is_none_or(|previous| similar(previous, colour, tolerance));
+        if !(uniform && continues) {
+            return distance;
+        }
+        previous = Some(colour);
+    }
+    lines
+}
+
+/// Finds the uniform borders around an image. Returns `None` if there are none, or if they would
+/// cover the whole image so there would be nothing left.
+pub fn find_margins(img: &DynamicImage, tolerance: u8) -> Option<TrimMargins> {
+    let (width, height) = img.dimensions();
+    let row = |y: u32, x_range: std::ops::Range<u32>| -> Vec<Rgba<u8>> {
+        x_range.map(|x| img.get_pixel(x, y)).collect()
+    };
+    let column = |x: u32, y_range: std::ops::Range<u32>| -> Vec<Rgba<u8>> {
+        y_range.map(|y| img.get_pixel(x, y)).collect()
+    };
+
+    let top = border_width(height, tolerance, |d| row(d, 0..width));
+    if top == height {
+        return None;
+    }
+    let bottom = border_width(height - top, tolerance, |d| row(height - 1 - d, 0..width));
+    if bottom == height - top {
+        return None;
+    }
+    //Only the rows which remain are considered for the side borders
+    let rows = top..height - bottom;
+    let left = border_width(width, tolerance, |d| column(d, rows.clone()));
+    let right = border_width(width - left, tolerance, |d| {
+        column(width - 1 - d, rows.clone())
+    });
+    if left == width || right == width - left {
+        return None;
+    }
+
+    let margins = TrimMargins {
+        left,
+        top,
+        right,
+        bottom,
+    };
+    (!margins.is_empty()).then_some(margins)
+}
+
+/// Crops the margins from an image
+pub fn apply(img: &DynamicImage, margins: TrimMargins) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    img.crop_imm(
+        margins.left,
+        margins.top,
+        width - margins.left - margins.right,
+        height - margins.top - margins.bottom,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const RED: Rgba<u8> = Rgba([200, 30, 30, 255]);
+
+    fn image(width: u32, height: u32, pixel: impl Fn(u32, u32) -> Rgba<u8>) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, pixel))
+    }
+
+    #[test]
+    fn uniform_image_is_left_alone() {
+        assert_eq!(find_margins(&image(20, 10, |_, _| WHITE), 0), None);
+    }
+
+    #[test]
+    fn single_pixel_is_left_alone() {
+        assert_eq!(find_margins(&image(1, 1, |_, _| RED), 0), None);
+    }
+
+    #[test]
+    fn gradient_is_left_alone() {
+        //Every column is uniform and close to the one before it, so could all be taken as border
+        let gradient = image(64, 16, |x, _| Rgba([x as u8 * 4, 0, 0, 255]));
+        assert_eq!(find_margins(&gradient, 4), None);
+        let gradient = image(16, 64, |_, y| Rgba([y as u8 * 4, 0, 0, 255]));
+        assert_eq!(find_margins(&gradient, 4), None);
+    }
+
+    #[test]
+    fn split_image_is_left_alone() {
+        //Both halves are uniform, so each could be taken as a border of the other
+        let split = image(10, 10, |_, y| if y < 5 { WHITE } else { RED });
+        assert_eq!(find_margins(&split, 0), None);
+    }
+
+    #[test]
+    fn removes_one_sided_border() {
+        let img = image(30, 20, |x, y| {
+            if x < 6 {
+                WHITE
+            } else {
+                Rgba([(x * 8) as u8, (y * 8) as u8, 0, 255])
+            }
+        });
+        let margins = find_margins(&img, 0).unwrap();
+        assert_eq!(
+            margins,
+            TrimMargins {
+                left: 6,
+                ..Default::default()
+            }
+        );
+        assert_eq!(apply(&img, margins).dimensions(), (24, 20));
+    }
+
+    #[test]
+    fn removes_border_on_every_side() {
+        let img = image(40, 30, |x, y| {
+            if (10..30).contains(&x) && (5..25).contains(&y) {
+                Rgba([(x * 6) as u8, (y * 6) as u8, 0, 255])
+            } else {
+                WHITE
+            }
+        });
+        let margins = find_margins(&img, 0).unwrap();
+        assert_eq!(
+            margins,
+            TrimMargins {
+                left: 10,
+                top: 5,
+                right: 10,
+                bottom: 5,
+            }
+        );
+        assert_eq!(apply(&img, margins).dimensions(), (20, 20));
+    }
+
+    #[test]
+    fn removes_gradual_shadow_within_tolerance() {
+        let img = image(20, 20, |x, y| {
+            let edge = x.min(y).min(19 - x).min(19 - y);
+            if edge < 3 {
+                let shade = 255 - edge as u8 * 2;
+                Rgba([shade, shade, shade, 255])
+            } else {
+                Rgba([(x * 12) as u8, 0, (y * 12) as u8, 255])
+            }
+        });
+        let margins = find_margins(&img, 4).unwrap();
+        assert_eq!(
+            margins,
+            TrimMargins {
+                left: 3,
+                top: 3,
+                right: 3,
+                bottom: 3,
+            }
+        );
+    }
+}
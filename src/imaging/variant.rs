@@ -0,0 +1,191 @@
+//! Resized and converted copies of stored images, requested through query parameters on `/img`.
+//! Variants are cached in a directory which is never served directly, named after the parameters
+//! they were made with and the modification time of the file they were made from.
+
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, Result};
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use serde_derive::Deserialize;
+
+use super::{decode_managed, icc, modified, remove_orphaned, remove_stale, write_cached};
+use crate::{
+    conf::{Config, StorageFormat},
+    index::DATA_DIR_NAME,
+};
+
+/// Name of the default variant cache directory, within the hidden data directory
+static VARIANT_DIR_NAME: &str = "variants";
+
+/// How an image is fitted to a requested width and height
+#[derive(Default, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale to fit within the box, keeping the aspect ratio
+    #[default]
+    Contain,
+    /// Scale to cover the box, keeping the aspect ratio and cropping whatever overflows
+    Cover,
+    /// Stretch to exactly the requested size
+    Fill,
+}
+
+impl Fit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+/// Query parameters describing a variant of a stored image
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct VariantQuery {
+    /// Width in pixels
+    pub w: Option<u32>,
+    /// Height in pixels
+    pub h: Option<u32>,
+    /// How the image is fitted when both a width and height are given
+    pub fit: Option<Fit>,
+    /// Format to convert the image to
+    pub format: Option<StorageFormat>,
+}
+
+impl VariantQuery {
+    /// Returns true if the query doesn't ask for any change to the stored image
+    pub fn is_empty(&self) -> bool {
+        self.w.is_none()
+            && self.h.is_none()
+            && self.format.unwrap_or_default() == StorageFormat::Original
+    }
+
+    /// Checks the requested size is within the configured limits, returning a description of the
+    /// problem if not
+    pub fn validate(&self, config: &Config) -> Result<(), String> {
+        let limits = &config.variants;
+        match (self.w, self.h) {
+            (Some(0), _) | (_, Some(0)) => Err("w and h must be greater than 0".to_owned()),
+            (Some(w), _) if w > limits.max_width => {
+                Err(format!("w must be at most {}", limits.max_width))
+            }
+            (_, Some(h)) if h > limits.max_height => {
+                Err(format!("h must be at most {}", limits.max_height))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the part of the cache file name identifying these parameters
+    pub(crate) fn key(&self) -> String {
+        let dimension = |d: Option<u32>| d.map_or_else(|| "auto".to_owned(), |d| d.to_string());
+        format!(
+            "{}x{}-{}",
+            dimension(self.w),
+            dimension(self.h),
+            self.fit.unwrap_or_default().as_str()
+        )
+    }
+}
+
+/// Returns the directory variants are cached in, or `None` if no target directory is configured
+pub fn cache_dir(config: &Config) -> Option<PathBuf> {
+    match (&config.variants.cache_dir, &config.target_dir) {
+        (Some(dir), _) => Some(PathBuf::from(dir)),
+        (None, Some(tgt_dir)) => Some(
+            PathBuf::from(tgt_dir)
+                .join(DATA_DIR_NAME)
+                .join(VARIANT_DIR_NAME),
+        ),
+        (None, None) => None,
+    }
+}
+
+/// Returns the format variants are encoded in when the query doesn't ask for one, which is the
+/// format of the stored file if it can be encoded, or PNG otherwise
+pub(crate) fn source_format(source: &Path) -> Result<StorageFormat> {
+    let format = image::io::Reader::open(source)?
+        .with_guessed_format()?
+        .format();
+    Ok(match format {
+        Some(ImageFormat::Jpeg) => StorageFormat::Jpeg,
+        Some(ImageFormat::WebP) => StorageFormat::Webp,
+        _ => StorageFormat::Png,
+    })
+}
+
+/// Resizes an image as requested. If only one of the width and height is given, the other is
+/// chosen to keep the aspect ratio.
+fn resize(img: DynamicImage, query: &VariantQuery) -> DynamicImage {
+    let filter = FilterType::CatmullRom;
+    let scaled = |from: u32, to: u32, other: u32| {
+        ((other as u64 * to as u64) as f64 / from.max(1) as f64)
+            .round()
+            .max(1.0) as u32
+    };
+    match (query.w, query.h) {
+        (None, None) => img,
+        (Some(w), None) => {
+            let h = scaled(img.width(), w, img.height());
+            img.resize_exact(w, h, filter)
+        }
+        (None, Some(h)) => {
+            let w = scaled(img.height(), h, img.width());
+            img.resize_exact(w, h, filter)
+        }
+        (Some(w), Some(h)) => match query.fit.unwrap_or_default() {
+            Fit::Contain => img.resize(w, h, filter),
+            Fit::Cover => img.resize_to_fill(w, h, filter),
+            Fit::Fill => img.resize_exact(w, h, filter),
+        },
+    }
+}
+
+/// Returns the path of an up to date variant of a stored file, generating it first if needed.
+/// `source` is the stored file, and `rel_path` its path relative to the storage directories.
+pub fn ensure(
+    config: &Config,
+    source: &Path,
+    rel_path: &Path,
+    query: &VariantQuery,
+) -> Result<PathBuf> {
+    let format = match query.format.unwrap_or_default() {
+        StorageFormat::Original => source_format(source)?,
+        format => format,
+    };
+    let (_, extension) = format
+        .mime_and_extension()
+        .ok_or_else(|| anyhow!("Variants can't keep the original format"))?;
+    let mtime = modified(source)
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .ok_or_else(|| anyhow!("Couldn't read modification time of {}", source.display()))?
+        .as_millis();
+    let dir = cache_dir(config)
+        .ok_or_else(|| anyhow!("No variant cache directory is configured"))?
+        .join(rel_path);
+    let key = query.key();
+    let path = dir.join(format!("{}-{}.{}", key, mtime, extension));
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    let (img, profile) = decode_managed(source, config)?;
+    let img = resize(img, query);
+    let data = format
+        .encode(&img, config.variants.quality)?
+        .ok_or_else(|| anyhow!("Variants can't keep the original format"))?;
+    write_cached(&path, &icc::attach(data, profile.as_deref()))?;
+    tracing::debug!(path = ?rel_path, key, "Generated variant");
+
+    remove_stale(&path, &key, extension);
+    Ok(path)
+}
+
+/// Removes cached variants whose stored file no longer exists. Returns the number removed.
+pub fn remove_orphans(config: &Config) -> usize {
+    remove_orphaned(config, cache_dir(config))
+}
@@ -0,0 +1,221 @@
+//! Watermarks overlaid onto images, either once when they are stored or on the copies served
+//! through `/img`. Served copies are cached like variants, named after the rule and the
+//! modification time of the file they were made from.
+
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, Result};
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, Rgba, RgbaImage,
+};
+use sha2::{Digest, Sha256};
+
+use super::{
+    animation,
+    annotate::{self, Point},
+    decode, decode_managed, edit_rgba, icc, modified, remove_orphaned, remove_stale,
+    variant::{self, VariantQuery},
+    write_cached,
+};
+use crate::{
+    conf::{
+        Config, DecodeLimits, WatermarkAudience, WatermarkPosition, WatermarkRule, WatermarkStage,
+    },
+    index::DATA_DIR_NAME,
+};
+
+/// Name of the watermarked copy cache directory, within the hidden data directory
+static WATERMARK_DIR_NAME: &str = "watermarks";
+/// Height in pixels text is measured at before being scaled to fit
+const MEASURE_SIZE: f32 = 64.0;
+/// Colour of text watermarks unless their rule specifies one
+const DEFAULT_TEXT_COLOUR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// Finds the first watermark rule applied at the given stage to an image stored in the given
+/// subdirectory. `authenticated` says whether the request being served sent a valid API key.
+pub fn find_rule<'c>(
+    config: &'c Config,
+    stage: WatermarkStage,
+    subdirectory: Option<&Path>,
+    authenticated: bool,
+) -> Option<&'c WatermarkRule> {
+    config.watermark_rules.iter().find(|rule| {
+        let subdir_ok = rule
+            .subdirectory
+            .as_ref()
+            .is_none_or(|subdir| subdirectory.is_some_and(|dir| dir.starts_with(subdir)));
+        let audience_ok = stage == WatermarkStage::Store
+            || match rule.audience {
+                WatermarkAudience::All => true,
+                WatermarkAudience::Public => !authenticated,
+                WatermarkAudience::Authenticated => authenticated,
+            };
+        rule.stage == stage && subdir_ok && audience_ok
+    })
+}
+
+/// Returns true if served images may be watermarked differently depending on whether the request
+/// was authenticated
+pub fn depends_on_audience(config: &Config) -> bool {
+    config
+        .watermark_rules
+        .iter()
+        .any(|rule| rule.stage == WatermarkStage::Serve && rule.audience != WatermarkAudience::All)
+}
+
+/// Renders a rule's watermark, scaled to fit within the given size
+fn render(
+    rule: &WatermarkRule,
+    limits: &DecodeLimits,
+    max_width: u32,
+    max_height: u32,
+) -> Result<RgbaImage> {
+    if let Some(text) = &rule.text {
+        let font = annotate::font();
+        let (width, height) = annotate::text_size(&font, text, MEASURE_SIZE);
+        if width <= 0.0 || height <= 0.0 {
+            return Err(anyhow!("Watermark text is empty"));
+        }
+        let size = MEASURE_SIZE * (max_width as f32 / width).min(max_height as f32 / height);
+        let (width, height) = annotate::text_size(&font, text, size);
+        let mut mark = RgbaImage::new((width.ceil() as u32).max(1), (height.ceil() as u32).max(1));
+        let colour = rule.colour.map_or(DEFAULT_TEXT_COLOUR, |colour| colour.0);
+        annotate::draw_text(
+            &mut mark,
+            &font,
+            text,
+            Point { x: 0.0, y: 0.0 },
+            size,
+            colour,
+        );
+        Ok(mark)
+    } else if let Some(path) = &rule.image {
+        let mark = decode(Path::new(path), limits)?;
+        Ok(mark
+            .resize(max_width, max_height, FilterType::CatmullRom)
+            .into_rgba8())
+    } else {
+        Err(anyhow!("Watermark rule sets neither text nor an image"))
+    }
+}
+
+/// Returns the offset of the top left corner of a watermark from that of the image
+fn place(rule: &WatermarkRule, image: (u32, u32), mark: (u32, u32)) -> (i64, i64) {
+    //0, 1 and 2 align the watermark with the start, middle and end of each axis
+    let (column, row) = match rule.position {
+        WatermarkPosition::TopLeft => (0, 0),
+        WatermarkPosition::Top => (1, 0),
+        WatermarkPosition::TopRight => (2, 0),
+        WatermarkPosition::Left => (0, 1),
+        WatermarkPosition::Centre => (1, 1),
+        WatermarkPosition::Right => (2, 1),
+        WatermarkPosition::BottomLeft => (0, 2),
+        WatermarkPosition::Bottom => (1, 2),
+        WatermarkPosition::BottomRight => (2, 2),
+    };
+    let offset = |align: u32, space: u32, size: u32| {
+        let free = space.saturating_sub(size);
+        let margin = rule.margin.min(free / 2);
+        (match align {
+            0 => margin,
+            1 => free / 2,
+            _ => free - margin,
+        }) as i64
+    };
+    (
+        offset(column, image.0, mark.0),
+        offset(row, image.1, mark.1),
+    )
+}
+
+/// Overlays a rule's watermark onto an image
+pub fn apply(img: &mut DynamicImage, rule: &WatermarkRule, limits: &DecodeLimits) -> Result<()> {
+    let scale = rule.scale.clamp(0.01, 1.0);
+    let (width, height) = (img.width(), img.height());
+    let mut mark = render(
+        rule,
+        limits,
+        ((width as f32 * scale).round() as u32).max(1),
+        ((height as f32 * scale).round() as u32).max(1),
+    )?;
+    let opacity = rule.opacity.clamp(0.0, 1.0);
+    for pixel in mark.pixels_mut() {
+        pixel.0[3] = (pixel.0[3] as f32 * opacity).round() as u8;
+    }
+    let (x, y) = place(rule, (width, height), mark.dimensions());
+    edit_rgba(img, |canvas| imageops::overlay(canvas, &mark, x, y));
+    Ok(())
+}
+
+/// Returns the directory watermarked copies are cached in, or `None` if no target directory is
+/// configured
+pub fn cache_dir(config: &Config) -> Option<PathBuf> {
+    config.target_dir.as_ref().map(|tgt_dir| {
+        PathBuf::from(tgt_dir)
+            .join(DATA_DIR_NAME)
+            .join(WATERMARK_DIR_NAME)
+    })
+}
+
+/// Returns the part of the cache file name identifying a rule, which changes whenever the rule's
+/// settings do
+fn rule_key(rule: &WatermarkRule) -> String {
+    hex::encode(&Sha256::digest(format!("{:?}", rule))[..6])
+}
+
+/// Returns the path of an up to date watermarked copy of a file, generating it first if needed.
+/// `source` is either the stored file or the variant of it made for `variant`, and `rel_path` the
+/// stored file's path relative to the storage directories. Animated files are returned without a
+/// watermark, as watermarking them would flatten them to their first frame.
+pub fn ensure(
+    config: &Config,
+    rule: &WatermarkRule,
+    source: &Path,
+    rel_path: &Path,
+    variant: Option<&VariantQuery>,
+) -> Result<PathBuf> {
+    let format = variant::source_format(source)?;
+    let (mime_type, extension) = format
+        .mime_and_extension()
+        .ok_or_else(|| anyhow!("Watermarked copies can't keep the original format"))?;
+    let mtime = modified(source)
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .ok_or_else(|| anyhow!("Couldn't read modification time of {}", source.display()))?
+        .as_millis();
+    let key = format!(
+        "{}-{}",
+        rule_key(rule),
+        variant.map_or_else(|| "full".to_owned(), VariantQuery::key)
+    );
+    let path = cache_dir(config)
+        .ok_or_else(|| anyhow!("No watermark cache directory is configured"))?
+        .join(rel_path)
+        .join(format!("{}-{}.{}", key, mtime, extension));
+    if path.is_file() {
+        return Ok(path);
+    }
+    if animation::can_animate(mime_type) && animation::inspect(&std::fs::read(source)?)?.is_some() {
+        tracing::debug!(path = ?rel_path, "Serving animated file without watermark");
+        return Ok(source.to_owned());
+    }
+
+    let (mut img, profile) = decode_managed(source, config)?;
+    apply(&mut img, rule, &config.decode_limits)?;
+    let data = format
+        .encode(&img, rule.quality)?
+        .ok_or_else(|| anyhow!("Watermarked copies can't keep the original format"))?;
+    write_cached(&path, &icc::attach(data, profile.as_deref()))?;
+    tracing::debug!(path = ?rel_path, key, "Generated watermarked copy");
+
+    remove_stale(&path, &key, extension);
+    Ok(path)
+}
+
+/// Removes cached watermarked copies whose stored file no longer exists. Returns the number removed.
+pub fn remove_orphans(config: &Config) -> usize {
+    remove_orphaned(config, cache_dir(config))
+}
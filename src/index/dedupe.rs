@@ -0,0 +1,74 @@
+//! Finding indexed images which are exact or near-duplicates of each other
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde_derive::Serialize;
+
+use super::{ImageRecord, Index};
+use crate::imaging::phash::{self, PerceptualHash};
+
+/// An indexed image which looks like another, along with how closely
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarImage {
+    /// The similar image
+    pub image: ImageRecord,
+    /// Number of bits which differ between the perceptual hashes of the two images
+    pub distance: u32,
+}
+
+/// Groups the indexed images whose stored files are identical. Each group holds two or more
+/// images, oldest first.
+pub fn exact_duplicates(index: &Index) -> Result<Vec<Vec<ImageRecord>>> {
+    let mut groups: Vec<Vec<ImageRecord>> = Vec::new();
+    let mut group_of_hash: HashMap<String, usize> = HashMap::new();
+    for record in index.list()? {
+        match group_of_hash.get(&record.sha256) {
+            Some(&group) => groups[group].push(record),
+            None => {
+                group_of_hash.insert(record.sha256.clone(), groups.len());
+                groups.push(vec![record]);
+            }
+        }
+    }
+    groups.retain(|group| group.len() > 1);
+    Ok(groups)
+}
+
+/// Groups the indexed images whose perceptual hashes are within `max_distance` of each other.
+/// Each group holds two or more images, oldest first. Images without a perceptual hash are left out.
+pub fn similar_groups(index: &Index, max_distance: u32) -> Result<Vec<Vec<ImageRecord>>> {
+    let (records, hashes): (Vec<ImageRecord>, Vec<PerceptualHash>) = index
+        .list()?
+        .into_iter()
+        .filter_map(|record| record.perceptual_hash.map(|hash| (record, hash)))
+        .unzip();
+    Ok(phash::cluster(&hashes, max_distance)
+        .into_iter()
+        .map(|group| group.into_iter().map(|idx| records[idx].clone()).collect())
+        .collect())
+}
+
+/// Lists the indexed images other than `record` whose perceptual hashes are within `max_distance`
+/// of its own, closest first
+pub fn similar_to(
+    index: &Index,
+    record: &ImageRecord,
+    hash: PerceptualHash,
+    max_distance: u32,
+) -> Result<Vec<SimilarImage>> {
+    let mut similar: Vec<SimilarImage> = index
+        .list()?
+        .into_iter()
+        .filter(|other| other.id != record.id)
+        .filter_map(|other| {
+            let distance = other.perceptual_hash?.distance(&hash);
+            (distance <= max_distance).then_some(SimilarImage {
+                image: other,
+                distance,
+            })
+        })
+        .collect();
+    similar.sort_by_key(|similar| (similar.distance, similar.image.id));
+    Ok(similar)
+}
@@ -0,0 +1,926 @@
+//! SQLite index of metadata about every image which has been stored, so that it can be
+//! queried later without having to inspect the files themselves.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, ToSql};
+use serde_derive::Serialize;
+
+use crate::{
+    conf::Config,
+    imaging::{
+        animation::Animation, phash::PerceptualHash, placeholder::Placeholder, trim::TrimMargins,
+    },
+};
+
+pub mod dedupe;
+pub mod reindex;
+
+/// Schema migrations, applied in order. The number of migrations which have been applied is
+/// tracked using SQLite's `user_version` pragma.
+static MIGRATIONS: &[&str] = &[
+    r"
+    CREATE TABLE images (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        original_filename TEXT NOT NULL,
+        mime_type TEXT NOT NULL,
+        width INTEGER,
+        height INTEGER,
+        size INTEGER NOT NULL,
+        sha256 TEXT NOT NULL,
+        uploader TEXT,
+        client_name TEXT,
+        client_ip TEXT,
+        subdirectory TEXT,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+    CREATE INDEX images_sha256 ON images (sha256);
+    CREATE INDEX images_created_at ON images (created_at);
+    CREATE INDEX images_subdirectory ON images (subdirectory);
+",
+    r"
+    ALTER TABLE images ADD COLUMN deletion_token TEXT;
+    ALTER TABLE images ADD COLUMN deleted_at TEXT;
+    ALTER TABLE images ADD COLUMN restore_path TEXT;
+",
+    r"
+    ALTER TABLE images ADD COLUMN original_path TEXT;
+",
+    r"
+    ALTER TABLE images ADD COLUMN trim_left INTEGER;
+    ALTER TABLE images ADD COLUMN trim_top INTEGER;
+    ALTER TABLE images ADD COLUMN trim_right INTEGER;
+    ALTER TABLE images ADD COLUMN trim_bottom INTEGER;
+",
+    r"
+    CREATE TABLE versions (
+        id INTEGER PRIMARY KEY,
+        image_id INTEGER NOT NULL,
+        version INTEGER NOT NULL,
+        path TEXT NOT NULL,
+        mime_type TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        sha256 TEXT NOT NULL,
+        operation TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        UNIQUE (image_id, version)
+    );
+",
+    r"
+    ALTER TABLE images ADD COLUMN perceptual_hash TEXT;
+",
+    r"
+    ALTER TABLE images ADD COLUMN frame_count INTEGER;
+    ALTER TABLE images ADD COLUMN duration_ms INTEGER;
+",
+    r"
+    ALTER TABLE images ADD COLUMN blurhash TEXT;
+    ALTER TABLE images ADD COLUMN average_colour TEXT;
+    ALTER TABLE images ADD COLUMN dominant_colour TEXT;
+",
+];
+
+/// Columns selected when reading an [`ImageRecord`], in the order expected by [`ImageRecord::from_row`]
+static IMAGE_COLUMNS: &str =
+    "id, path, original_filename, mime_type, width, height, size, sha256, \
+     uploader, client_name, client_ip, subdirectory, created_at, updated_at, deletion_token, \
+     deleted_at, restore_path, original_path, trim_left, trim_top, trim_right, trim_bottom, \
+     perceptual_hash, frame_count, duration_ms, blurhash, average_colour, dominant_colour";
+
+/// Metadata about a stored image which should be added to the index
+#[derive(Debug, Clone)]
+pub struct NewImage {
+    /// Path of the stored file, relative to the target directory
+    pub path: PathBuf,
+    /// Filename provided by the client when uploading
+    pub original_filename: String,
+    /// Detected MIME type
+    pub mime_type: String,
+    /// Image dimensions in pixels, if the image could be decoded
+    pub dimensions: Option<(u32, u32)>,
+    /// Size of the stored file in bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 hash of the stored file
+    pub sha256: String,
+    /// Name of the user who uploaded the image
+    pub uploader: Option<String>,
+    /// Name of the client software used to upload the image
+    pub client_name: Option<String>,
+    /// IP address the upload was received from
+    pub client_ip: Option<String>,
+    /// Subdirectory of the target directory the image was stored in
+    pub subdirectory: Option<String>,
+    /// Time the image was uploaded
+    pub created_at: DateTime<Utc>,
+    /// Secret token allowing the image to be deleted without authentication
+    pub deletion_token: Option<String>,
+    /// Path the upload was kept at as it was received, if the stored file was altered
+    pub original_path: Option<PathBuf>,
+    /// Borders cropped from the upload before it was stored
+    pub trimmed: Option<TrimMargins>,
+    /// Perceptual hash of the stored image, if it could be decoded
+    pub perceptual_hash: Option<PerceptualHash>,
+    /// Frame count and duration of the stored image, if it is animated
+    pub animation: Option<Animation>,
+    /// Placeholder shown while the stored image loads, if it has been decoded
+    pub placeholder: Option<Placeholder>,
+}
+
+/// Details of an indexed image which are derived from the stored file itself
+#[derive(Debug, Clone)]
+pub struct FileDetails {
+    /// Detected MIME type
+    pub mime_type: String,
+    /// Image dimensions in pixels, if the image could be decoded
+    pub dimensions: Option<(u32, u32)>,
+    /// Size of the stored file in bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 hash of the stored file
+    pub sha256: String,
+    /// Perceptual hash of the stored image, if it has been decoded
+    pub perceptual_hash: Option<PerceptualHash>,
+    /// Frame count and duration of the stored image, if it is animated
+    pub animation: Option<Animation>,
+    /// Placeholder shown while the stored image loads, if it has been decoded
+    pub placeholder: Option<Placeholder>,
+}
+
+/// An image stored in the index
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageRecord {
+    /// Unique ID of the image
+    pub id: i64,
+    /// Path of the stored file, relative to the target directory
+    pub path: String,
+    /// Filename provided by the client when uploading
+    pub original_filename: String,
+    /// Detected MIME type
+    pub mime_type: String,
+    /// Width of the image in pixels
+    pub width: Option<u32>,
+    /// Height of the image in pixels
+    pub height: Option<u32>,
+    /// Size of the stored file in bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 hash of the stored file
+    pub sha256: String,
+    /// Name of the user who uploaded the image
+    pub uploader: Option<String>,
+    /// Name of the client software used to upload the image
+    pub client_name: Option<String>,
+    /// IP address the upload was received from
+    pub client_ip: Option<String>,
+    /// Subdirectory of the target directory the image was stored in
+    pub subdirectory: Option<String>,
+    /// Time the image was uploaded
+    pub created_at: DateTime<Utc>,
+    /// Time the image or its metadata was last changed
+    pub updated_at: DateTime<Utc>,
+    /// Secret token allowing the image to be deleted without authentication
+    #[serde(skip)]
+    pub deletion_token: Option<String>,
+    /// Time the image was moved to the trash, if it has been deleted
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Path the image will be restored to if taken out of the trash. While an image is in the
+    /// trash, `path` points to its location within the trash directory.
+    pub restore_path: Option<String>,
+    /// Path the upload was kept at as it was received, relative to the storage directory, if the
+    /// stored file was altered
+    pub original_path: Option<String>,
+    /// Borders cropped from the upload before it was stored
+    pub trimmed: Option<TrimMargins>,
+    /// Perceptual hash of the stored image, used to find near-duplicates
+    pub perceptual_hash: Option<PerceptualHash>,
+    /// Frame count and duration of the stored image, if it is animated
+    pub animation: Option<Animation>,
+    /// Placeholder shown while the stored image loads, if it has been decoded
+    pub placeholder: Option<Placeholder>,
+}
+
+impl ImageRecord {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            original_filename: row.get(2)?,
+            mime_type: row.get(3)?,
+            width: row.get(4)?,
+            height: row.get(5)?,
+            size: row.get(6)?,
+            sha256: row.get(7)?,
+            uploader: row.get(8)?,
+            client_name: row.get(9)?,
+            client_ip: row.get(10)?,
+            subdirectory: row.get(11)?,
+            created_at: row.get(12)?,
+            updated_at: row.get(13)?,
+            deletion_token: row.get(14)?,
+            deleted_at: row.get(15)?,
+            restore_path: row.get(16)?,
+            original_path: row.get(17)?,
+            trimmed: match (row.get(18)?, row.get(19)?, row.get(20)?, row.get(21)?) {
+                (Some(left), Some(top), Some(right), Some(bottom)) => Some(TrimMargins {
+                    left,
+                    top,
+                    right,
+                    bottom,
+                }),
+                _ => None,
+            },
+            perceptual_hash: row
+                .get::<_, Option<String>>(22)?
+                .and_then(|hash| PerceptualHash::from_hex(&hash)),
+            animation: match (row.get(23)?, row.get(24)?) {
+                (Some(frames), Some(duration_ms)) => Some(Animation {
+                    frames,
+                    duration_ms,
+                }),
+                _ => None,
+            },
+            placeholder: match (row.get(25)?, row.get(26)?, row.get(27)?) {
+                (Some(blurhash), Some(average_colour), Some(dominant_colour)) => {
+                    Some(Placeholder {
+                        blurhash,
+                        average_colour,
+                        dominant_colour,
+                    })
+                }
+                _ => None,
+            },
+        })
+    }
+}
+
+/// An earlier version of an indexed image, kept when the image was edited. Version 1 is the image
+/// as it was uploaded.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageVersion {
+    /// ID of the image this is a version of
+    pub image_id: i64,
+    /// Number of the version, counting up from 1 for each edit
+    pub version: u32,
+    /// Path the version is kept at, relative to the storage directory holding the image
+    #[serde(skip)]
+    pub path: String,
+    /// Detected MIME type
+    pub mime_type: String,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 hash of the file
+    pub sha256: String,
+    /// Name of the edit which replaced this version
+    pub operation: String,
+    /// Time this version was replaced
+    pub created_at: DateTime<Utc>,
+}
+
+impl ImageVersion {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            image_id: row.get(0)?,
+            version: row.get(1)?,
+            path: row.get(2)?,
+            mime_type: row.get(3)?,
+            size: row.get(4)?,
+            sha256: row.get(5)?,
+            operation: row.get(6)?,
+            created_at: row.get(7)?,
+        })
+    }
+}
+
+/// Columns selected when reading an [`ImageVersion`], in the order expected by [`ImageVersion::from_row`]
+static VERSION_COLUMNS: &str =
+    "image_id, version, path, mime_type, size, sha256, operation, created_at";
+
+/// Criteria for selecting indexed images. Criteria which are not set match every image.
+#[derive(Debug, Default, Clone)]
+pub struct ImageFilter {
+    /// Only match images stored within this subdirectory, including any nested directories
+    pub subdirectory: Option<String>,
+    /// Only match images uploaded at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only match images uploaded before this time
+    pub to: Option<DateTime<Utc>>,
+    /// Only match images with one of these IDs
+    pub ids: Option<Vec<i64>>,
+}
+
+impl ImageFilter {
+    /// Builds a filter from user-provided strings. Dates may be given either as `YYYY-MM-DD`,
+    /// in which case the whole day is included, or as RFC 3339 timestamps. IDs are comma separated.
+    pub fn parse(
+        subdirectory: Option<String>,
+        from: Option<&str>,
+        to: Option<&str>,
+        ids: Option<&str>,
+    ) -> Result<Self> {
+        let ids = ids
+            .map(|ids| {
+                ids.split(',')
+                    .filter(|id| !id.trim().is_empty())
+                    .map(|id| {
+                        id.trim()
+                            .parse::<i64>()
+                            .map_err(|e| anyhow!("Invalid image ID {}: {}", id, e))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+        Ok(Self {
+            subdirectory: subdirectory
+                .map(|subdir| subdir.trim_matches('/').to_owned())
+                .filter(|subdir| !subdir.is_empty()),
+            from: from.map(|from| parse_time(from, false)).transpose()?,
+            to: to.map(|to| parse_time(to, true)).transpose()?,
+            ids,
+        })
+    }
+}
+
+/// Parses either a date or an RFC 3339 timestamp. If `end_of_day` is set, dates are interpreted as
+/// the start of the following day so that ranges include the whole of the given date.
+fn parse_time(time: &str, end_of_day: bool) -> Result<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        let date = if end_of_day {
+            date.succ_opt()
+                .ok_or_else(|| anyhow!("Date out of range: {}", time))?
+        } else {
+            date
+        };
+        return Ok(date.and_time(NaiveTime::MIN).and_utc());
+    }
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| anyhow!("Invalid date or time {}: {}", time, e))
+}
+
+/// Storage used by a single uploader
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Usage {
+    /// Total size of the uploader's images in bytes
+    pub bytes: u64,
+    /// Number of images the uploader has stored
+    pub files: u64,
+}
+
+/// Handle to the metadata index database
+#[derive(Debug)]
+pub struct Index {
+    conn: Mutex<Connection>,
+}
+
+impl Index {
+    /// Opens the index configured for the target directory, if indexing is enabled
+    pub fn open_configured(config: &Config) -> Result<Option<Self>> {
+        match index_path(config) {
+            Some(path) if config.enable_index => Self::open(path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Opens (or creates) an index database at the given path and brings its schema up to date
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow!("Index connection mutex was poisoned"))
+    }
+
+    /// Adds a newly stored image to the index, returning its ID
+    pub fn insert(&self, image: &NewImage) -> Result<i64> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO images (path, original_filename, mime_type, width, height, size, sha256, \
+             uploader, client_name, client_ip, subdirectory, created_at, updated_at, deletion_token, \
+             original_path, trim_left, trim_top, trim_right, trim_bottom, perceptual_hash, \
+             frame_count, duration_ms, blurhash, average_colour, dominant_colour) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?12, ?13, ?14, ?15, ?16, \
+             ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24)",
+            params![
+                image.path.to_string_lossy(),
+                image.original_filename,
+                image.mime_type,
+                image.dimensions.map(|(w, _)| w),
+                image.dimensions.map(|(_, h)| h),
+                image.size,
+                image.sha256,
+                image.uploader,
+                image.client_name,
+                image.client_ip,
+                image.subdirectory,
+                image.created_at,
+                image.deletion_token,
+                image
+                    .original_path
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().to_string()),
+                image.trimmed.map(|trim| trim.left),
+                image.trimmed.map(|trim| trim.top),
+                image.trimmed.map(|trim| trim.right),
+                image.trimmed.map(|trim| trim.bottom),
+                image.perceptual_hash.map(String::from),
+                image.animation.map(|animation| animation.frames),
+                image.animation.map(|animation| animation.duration_ms),
+                image.placeholder.as_ref().map(|p| &p.blurhash),
+                image.placeholder.as_ref().map(|p| &p.average_colour),
+                image.placeholder.as_ref().map(|p| &p.dominant_colour),
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Retrieves an image by its ID
+    pub fn get(&self, id: i64) -> Result<Option<ImageRecord>> {
+        let conn = self.conn()?;
+        Ok(conn
+            .query_row(
+                &format!("SELECT {} FROM images WHERE id = ?1", IMAGE_COLUMNS),
+                params![id],
+                ImageRecord::from_row,
+            )
+            .optional()?)
+    }
+
+    /// Retrieves an image by its path relative to the target directory
+    pub fn find_by_path(&self, path: impl AsRef<Path>) -> Result<Option<ImageRecord>> {
+        let conn = self.conn()?;
+        Ok(conn
+            .query_row(
+                &format!("SELECT {} FROM images WHERE path = ?1", IMAGE_COLUMNS),
+                params![path.as_ref().to_string_lossy()],
+                ImageRecord::from_row,
+            )
+            .optional()?)
+    }
+
+    /// Updates the details of an indexed image which are derived from the stored file itself
+    pub fn refresh_file(&self, id: i64, file: &FileDetails) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE images SET mime_type = ?2, width = ?3, height = ?4, size = ?5, sha256 = ?6, \
+             perceptual_hash = ?7, frame_count = ?8, duration_ms = ?9, blurhash = ?10, \
+             average_colour = ?11, dominant_colour = ?12, updated_at = ?13 WHERE id = ?1",
+            params![
+                id,
+                file.mime_type,
+                file.dimensions.map(|(w, _)| w),
+                file.dimensions.map(|(_, h)| h),
+                file.size,
+                file.sha256,
+                file.perceptual_hash.map(String::from),
+                file.animation.map(|animation| animation.frames),
+                file.animation.map(|animation| animation.duration_ms),
+                file.placeholder.as_ref().map(|p| &p.blurhash),
+                file.placeholder.as_ref().map(|p| &p.average_colour),
+                file.placeholder.as_ref().map(|p| &p.dominant_colour),
+                Utc::now(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records the perceptual hash of an image indexed before hashes were computed
+    pub fn set_perceptual_hash(&self, id: i64, hash: PerceptualHash) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE images SET perceptual_hash = ?2 WHERE id = ?1",
+            params![id, String::from(hash)],
+        )?;
+        Ok(())
+    }
+
+    /// Records the placeholder of an image indexed before placeholders were computed
+    pub fn set_placeholder(&self, id: i64, placeholder: &Placeholder) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE images SET blurhash = ?2, average_colour = ?3, dominant_colour = ?4 WHERE id = ?1",
+            params![
+                id,
+                placeholder.blurhash,
+                placeholder.average_colour,
+                placeholder.dominant_colour
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Removes an image and its earlier versions from the index. The stored files themselves are
+    /// left untouched.
+    pub fn remove(&self, id: i64) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM versions WHERE image_id = ?1", params![id])?;
+        tx.execute("DELETE FROM images WHERE id = ?1", params![id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns the number the next version kept of an image will have
+    pub fn next_version(&self, image_id: i64) -> Result<u32> {
+        let conn = self.conn()?;
+        Ok(conn.query_row(
+            "SELECT COALESCE(MAX(version), 0) + 1 FROM versions WHERE image_id = ?1",
+            params![image_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Records an earlier version of an image which has been kept
+    pub fn add_version(&self, version: &ImageVersion) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            &format!(
+                "INSERT INTO versions ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                VERSION_COLUMNS
+            ),
+            params![
+                version.image_id,
+                version.version,
+                version.path,
+                version.mime_type,
+                version.size,
+                version.sha256,
+                version.operation,
+                version.created_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Lists the earlier versions kept of an image, oldest first
+    pub fn versions(&self, image_id: i64) -> Result<Vec<ImageVersion>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM versions WHERE image_id = ?1 ORDER BY version",
+            VERSION_COLUMNS
+        ))?;
+        let versions = stmt
+            .query_map(params![image_id], ImageVersion::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(versions)
+    }
+
+    /// Records that an image has been moved into the trash
+    pub fn mark_deleted(&self, id: i64, trash_path: impl AsRef<Path>) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE images SET restore_path = path, path = ?2, deleted_at = ?3, updated_at = ?3 \
+             WHERE id = ?1 AND deleted_at IS NULL",
+            params![id, trash_path.as_ref().to_string_lossy(), Utc::now()],
+        )?;
+        Ok(())
+    }
+
+    /// Records that an image has been restored from the trash to its original location
+    pub fn mark_restored(&self, id: i64) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE images SET path = restore_path, restore_path = NULL, deleted_at = NULL, \
+             updated_at = ?2 WHERE id = ?1 AND deleted_at IS NOT NULL",
+            params![id, Utc::now()],
+        )?;
+        Ok(())
+    }
+
+    /// Lists the indexed images which have not been deleted and match a filter, oldest first
+    pub fn select(&self, filter: &ImageFilter) -> Result<Vec<ImageRecord>> {
+        let mut conditions = vec!["deleted_at IS NULL".to_owned()];
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(subdir) = &filter.subdirectory {
+            let prefix = format!("{}/", subdir);
+            conditions.push(format!(
+                "substr(path, 1, {}) = ?{}",
+                prefix.chars().count(),
+                values.len() + 1
+            ));
+            values.push(Box::new(prefix));
+        }
+        if let Some(from) = filter.from {
+            conditions.push(format!("created_at >= ?{}", values.len() + 1));
+            values.push(Box::new(from));
+        }
+        if let Some(to) = filter.to {
+            conditions.push(format!("created_at < ?{}", values.len() + 1));
+            values.push(Box::new(to));
+        }
+        if let Some(ids) = &filter.ids {
+            let placeholders: Vec<String> = ids
+                .iter()
+                .enumerate()
+                .map(|(idx, _)| format!("?{}", values.len() + idx + 1))
+                .collect();
+            conditions.push(format!("id IN ({})", placeholders.join(", ")));
+            values.extend(ids.iter().map(|id| Box::new(*id) as Box<dyn ToSql>));
+        }
+
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM images WHERE {} ORDER BY created_at, id",
+            IMAGE_COLUMNS,
+            conditions.join(" AND ")
+        ))?;
+        let records = stmt
+            .query_map(params_from_iter(values.iter()), ImageRecord::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+
+    /// Totals the size and number of images recorded against an uploader, excluding deleted ones
+    pub fn usage(&self, uploader: &str) -> Result<Usage> {
+        let conn = self.conn()?;
+        let usage = conn.query_row(
+            "SELECT COALESCE(SUM(size), 0), COUNT(*) FROM images
+             WHERE uploader = ?1 AND deleted_at IS NULL",
+            params![uploader],
+            |row| {
+                Ok(Usage {
+                    bytes: row.get(0)?,
+                    files: row.get(1)?,
+                })
+            },
+        )?;
+        Ok(usage)
+    }
+
+    /// Lists all indexed images which have not been deleted, oldest first
+    pub fn list(&self) -> Result<Vec<ImageRecord>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM images WHERE deleted_at IS NULL ORDER BY created_at, id",
+            IMAGE_COLUMNS
+        ))?;
+        let records = stmt
+            .query_map([], ImageRecord::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+}
+
+/// Works out where the index database should be stored
+pub fn index_path(config: &Config) -> Option<PathBuf> {
+    match (&config.index_path, &config.target_dir) {
+        (Some(path), _) => Some(PathBuf::from(path)),
+        (None, Some(tgt_dir)) => Some(
+            PathBuf::from(tgt_dir)
+                .join(DATA_DIR_NAME)
+                .join(INDEX_FILE_NAME),
+        ),
+        (None, None) => None,
+    }
+}
+
+/// Name of the hidden directory within the target directory which holds yoinkx's own data
+pub static DATA_DIR_NAME: &str = ".yoinkx";
+static INDEX_FILE_NAME: &str = "index.sqlite3";
+
+/// Applies any schema migrations which have not yet been applied
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let applied: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", idx + 1)?;
+        tx.commit()?;
+        tracing::info!(version = idx + 1, "Applied index schema migration");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn memory_index() -> Index {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        Index {
+            conn: Mutex::new(conn),
+        }
+    }
+
+    fn time(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap()
+    }
+
+    fn image(path: &str, uploader: &str, size: u64, created_at: DateTime<Utc>) -> NewImage {
+        NewImage {
+            path: PathBuf::from(path),
+            original_filename: "upload.png".to_owned(),
+            mime_type: "image/png".to_owned(),
+            dimensions: Some((1, 1)),
+            size,
+            sha256: String::new(),
+            uploader: Some(uploader.to_owned()),
+            client_name: None,
+            client_ip: None,
+            subdirectory: None,
+            created_at,
+            deletion_token: None,
+            original_path: None,
+            trimmed: None,
+            perceptual_hash: None,
+            animation: None,
+            placeholder: None,
+        }
+    }
+
+    fn paths(records: Vec<ImageRecord>) -> Vec<String> {
+        records.into_iter().map(|record| record.path).collect()
+    }
+
+    #[test]
+    fn parses_dates_as_whole_days() {
+        let filter =
+            ImageFilter::parse(None, Some("2024-03-07"), Some("2024-03-07"), None).unwrap();
+        assert_eq!(filter.from, Some(time(7, 0)));
+        assert_eq!(filter.to, Some(time(8, 0)));
+
+        let filter =
+            ImageFilter::parse(None, Some("2024-03-07T12:00:00+02:00"), None, None).unwrap();
+        assert_eq!(filter.from, Some(time(7, 10)));
+        assert_eq!(filter.to, None);
+    }
+
+    #[test]
+    fn rejects_invalid_dates_and_ids() {
+        for date in ["2024-02-30", "07/03/2024", "yesterday", ""] {
+            assert!(
+                ImageFilter::parse(None, Some(date), None, None).is_err(),
+                "{}",
+                date
+            );
+            assert!(
+                ImageFilter::parse(None, None, Some(date), None).is_err(),
+                "{}",
+                date
+            );
+        }
+        for ids in ["1,two", "1.5", "1;2"] {
+            assert!(
+                ImageFilter::parse(None, None, None, Some(ids)).is_err(),
+                "{}",
+                ids
+            );
+        }
+    }
+
+    #[test]
+    fn parses_id_lists_and_subdirectories() {
+        let filter =
+            ImageFilter::parse(Some("/a/b/".to_owned()), None, None, Some(" 1, 2,,3 ,")).unwrap();
+        assert_eq!(filter.subdirectory.as_deref(), Some("a/b"));
+        assert_eq!(filter.ids, Some(vec![1, 2, 3]));
+
+        //An empty list matches no images, rather than every image
+        let filter = ImageFilter::parse(Some("/".to_owned()), None, None, Some(" , ")).unwrap();
+        assert_eq!(filter.subdirectory, None);
+        assert_eq!(filter.ids, Some(vec![]));
+        let index = memory_index();
+        index
+            .insert(&image("a.png", "alice", 1, time(7, 0)))
+            .unwrap();
+        assert!(index.select(&filter).unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrates_from_an_empty_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let version = |conn: &Connection| -> usize {
+            conn.pragma_query_value(None, "user_version", |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(version(&conn), 0);
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+        //Every column read back is present
+        conn.prepare(&format!("SELECT {} FROM images", IMAGE_COLUMNS))
+            .unwrap();
+        conn.prepare(&format!("SELECT {} FROM versions", VERSION_COLUMNS))
+            .unwrap();
+        //Migrations which have been applied aren't run again
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrations_keep_existing_images() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO images (path, original_filename, mime_type, size, sha256, created_at, \
+             updated_at) VALUES ('a.png', 'a.png', 'image/png', 5, '', ?1, ?1)",
+            params![time(7, 0)],
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        let index = Index {
+            conn: Mutex::new(conn),
+        };
+
+        let record = index.find_by_path("a.png").unwrap().unwrap();
+        assert_eq!(record.size, 5);
+        assert_eq!(record.created_at, time(7, 0));
+        assert!(record.deleted_at.is_none());
+        assert!(record.animation.is_none());
+        assert!(record.placeholder.is_none());
+    }
+
+    #[test]
+    fn selects_images_matching_every_criterion() {
+        let index = memory_index();
+        let a1 = index
+            .insert(&image("a/1.png", "alice", 1, time(6, 12)))
+            .unwrap();
+        index
+            .insert(&image("a/b/2.png", "alice", 1, time(7, 12)))
+            .unwrap();
+        let ab = index
+            .insert(&image("ab/3.png", "alice", 1, time(7, 13)))
+            .unwrap();
+        let deleted = index
+            .insert(&image("a/4.png", "alice", 1, time(7, 14)))
+            .unwrap();
+        index.mark_deleted(deleted, ".trash/1/a/4.png").unwrap();
+
+        let select = |subdir: Option<&str>, from, to, ids| {
+            paths(
+                index
+                    .select(&ImageFilter::parse(subdir.map(str::to_owned), from, to, ids).unwrap())
+                    .unwrap(),
+            )
+        };
+        assert_eq!(
+            select(None, None, None, None),
+            ["a/1.png", "a/b/2.png", "ab/3.png"]
+        );
+        //Subdirectories match whole path components only
+        assert_eq!(
+            select(Some("a"), None, None, None),
+            ["a/1.png", "a/b/2.png"]
+        );
+        assert_eq!(select(Some("a/b"), None, None, None), ["a/b/2.png"]);
+        assert_eq!(
+            select(None, Some("2024-03-07"), None, None),
+            ["a/b/2.png", "ab/3.png"]
+        );
+        assert_eq!(select(None, None, Some("2024-03-06"), None), ["a/1.png"]);
+        let ids = format!("{},{},{}", a1, ab, deleted);
+        assert_eq!(
+            select(None, None, None, Some(&ids)),
+            ["a/1.png", "ab/3.png"]
+        );
+        assert_eq!(
+            select(Some("a"), Some("2024-03-07"), None, Some(&ids)),
+            Vec::<String>::new()
+        );
+        assert_eq!(paths(index.list().unwrap()), select(None, None, None, None));
+    }
+
+    #[test]
+    fn totals_usage_per_uploader() {
+        let index = memory_index();
+        index
+            .insert(&image("1.png", "alice", 100, time(7, 0)))
+            .unwrap();
+        let deleted = index
+            .insert(&image("2.png", "alice", 50, time(7, 0)))
+            .unwrap();
+        index
+            .insert(&image("3.png", "alice", 20, time(7, 0)))
+            .unwrap();
+        index.insert(&image("4.png", "bob", 7, time(7, 0))).unwrap();
+        index.mark_deleted(deleted, ".trash/1/2.png").unwrap();
+
+        let usage = index.usage("alice").unwrap();
+        assert_eq!((usage.bytes, usage.files), (120, 2));
+        let usage = index.usage("bob").unwrap();
+        assert_eq!((usage.bytes, usage.files), (7, 1));
+        let usage = index.usage("carol").unwrap();
+        assert_eq!((usage.bytes, usage.files), (0, 0));
+
+        index.mark_restored(deleted).unwrap();
+        assert_eq!(index.usage("alice").unwrap().bytes, 170);
+    }
+}
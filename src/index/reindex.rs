@@ -0,0 +1,246 @@
+//! Rebuilding the metadata index from the files already present in the target directory
+
+use std::{
+    collections::HashSet,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use image::DynamicImage;
+use sha2::{Digest, Sha256};
+
+use super::{FileDetails, ImageRecord, Index, NewImage};
+use crate::{
+    conf::Config,
+    imaging::{
+        self,
+        animation::{self, Animation},
+        phash::PerceptualHash,
+        placeholder::Placeholder,
+        thumbnail, variant, watermark,
+    },
+    storage::{exists_in_any_root, list_all_files, StoredFile},
+    webserver::checked_file_stream::{FileCategory, FileType},
+};
+
+/// Summary of the changes made while reindexing
+#[derive(Debug, Default)]
+pub struct ReindexReport {
+    /// Files which were present on disk but missing from the index, and have now been added
+    pub orphans: Vec<PathBuf>,
+    /// Indexed files whose contents had changed, and have had their details refreshed
+    pub refreshed: Vec<PathBuf>,
+    /// Number of indexed files which were already up to date
+    pub unchanged: usize,
+    /// Index entries whose file no longer exists
+    pub missing: Vec<ImageRecord>,
+    /// Files which were skipped, along with the reason why
+    pub skipped: Vec<(PathBuf, String)>,
+    /// Number of indexed files which were missing a perceptual hash, and have had one computed
+    pub hashed: usize,
+    /// Number of indexed files which were missing a placeholder, and have had one computed
+    pub placeholders: usize,
+    /// Number of files whose thumbnails were missing or out of date, and have been regenerated
+    pub thumbnails: usize,
+    /// Number of cached thumbnails removed as their file no longer exists
+    pub thumbnails_removed: usize,
+    /// Number of cached variants removed as their file no longer exists
+    pub variants_removed: usize,
+    /// Number of cached watermarked copies removed as their file no longer exists
+    pub watermarks_removed: usize,
+}
+
+/// Walks the storage directories, adding any files which are not yet indexed and refreshing the details
+/// of any which have changed. If `remove_missing` is set, index entries for files which no longer
+/// exist are removed.
+pub fn reindex(config: &Config, index: &Index, remove_missing: bool) -> Result<ReindexReport> {
+    if config.target_dir.is_none() {
+        return Err(anyhow!(
+            "Cannot reindex as no target directory is configured"
+        ));
+    }
+    let mut report = ReindexReport::default();
+    let mut seen: HashSet<String> = HashSet::new();
+
+    for file in list_all_files(config) {
+        let rel_path = file.relative_path.to_string_lossy().to_string();
+        let mut details = match inspect_file(&file.path) {
+            Ok(Some(details)) => details,
+            Ok(None) => {
+                report
+                    .skipped
+                    .push((file.relative_path, "not an image".to_owned()));
+                continue;
+            }
+            Err(e) => {
+                report.skipped.push((file.relative_path, e.to_string()));
+                continue;
+            }
+        };
+        seen.insert(rel_path);
+
+        if config.thumbnails.enabled {
+            match thumbnail::refresh(config, &file.path, &file.relative_path) {
+                Ok(true) => report.thumbnails += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!(error = %e, path = ?file.relative_path, "Failed to regenerate thumbnails")
+                }
+            }
+        }
+
+        match index.find_by_path(&file.relative_path)? {
+            Some(existing)
+                if existing.sha256 == details.sha256 && existing.size == details.size =>
+            {
+                //Images indexed before perceptual hashes or placeholders were introduced get them now
+                let needs_hash = existing.perceptual_hash.is_none();
+                let needs_placeholder = existing.placeholder.is_none();
+                if needs_hash || needs_placeholder {
+                    if let Some(img) = decode(config, &file) {
+                        if needs_hash {
+                            index.set_perceptual_hash(existing.id, PerceptualHash::of(&img))?;
+                            report.hashed += 1;
+                        }
+                        if let Some(placeholder) =
+                            Placeholder::of(&img).filter(|_| needs_placeholder)
+                        {
+                            index.set_placeholder(existing.id, &placeholder)?;
+                            report.placeholders += 1;
+                        }
+                    }
+                }
+                report.unchanged += 1;
+            }
+            Some(existing) => {
+                add_decoded_details(config, &file, &mut details);
+                index.refresh_file(existing.id, &details)?;
+                report.refreshed.push(file.relative_path);
+            }
+            None => {
+                add_decoded_details(config, &file, &mut details);
+                index.insert(&orphan_record(&file, details))?;
+                report.orphans.push(file.relative_path);
+            }
+        }
+    }
+
+    for record in index.list()? {
+        if !seen.contains(&record.path) && !exists_in_any_root(config, &record.path) {
+            if remove_missing {
+                index.remove(record.id)?;
+            }
+            report.missing.push(record);
+        }
+    }
+
+    if config.thumbnails.enabled {
+        report.thumbnails_removed = thumbnail::remove_orphans(config);
+    }
+    report.variants_removed = variant::remove_orphans(config);
+    report.watermarks_removed = watermark::remove_orphans(config);
+
+    Ok(report)
+}
+
+/// Builds an index entry for a file which was not uploaded through the server
+fn orphan_record(file: &StoredFile, details: FileDetails) -> NewImage {
+    NewImage {
+        path: file.relative_path.clone(),
+        original_filename: file
+            .relative_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        mime_type: details.mime_type,
+        dimensions: details.dimensions,
+        size: details.size,
+        sha256: details.sha256,
+        uploader: None,
+        client_name: None,
+        client_ip: None,
+        subdirectory: file
+            .relative_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map(|parent| parent.to_string_lossy().to_string()),
+        created_at: DateTime::<Utc>::from(file.modified),
+        deletion_token: None,
+        original_path: None,
+        trimmed: None,
+        perceptual_hash: details.perceptual_hash,
+        animation: details.animation,
+        placeholder: details.placeholder,
+    }
+}
+
+/// Decodes a stored file so its perceptual hash and placeholder can be computed. Returns `None`
+/// if it can't be decoded.
+fn decode(config: &Config, file: &StoredFile) -> Option<DynamicImage> {
+    match imaging::decode(&file.path, &config.decode_limits) {
+        Ok(img) => Some(img),
+        Err(e) => {
+            tracing::warn!(error = %e, path = ?file.relative_path, "Failed to decode image for hashing");
+            None
+        }
+    }
+}
+
+/// Fills in the details of a stored file which can only be computed by decoding it
+fn add_decoded_details(config: &Config, file: &StoredFile, details: &mut FileDetails) {
+    if let Some(img) = decode(config, file) {
+        details.perceptual_hash = Some(PerceptualHash::of(&img));
+        details.placeholder = Placeholder::of(&img);
+    }
+}
+
+/// Reads the frame count and duration of a stored file, if it is animated
+fn animation(path: &Path, mime_type: &str) -> Option<Animation> {
+    if !animation::can_animate(mime_type) {
+        return None;
+    }
+    let inspected = std::fs::read(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| animation::inspect(&data));
+    match inspected {
+        Ok(animation) => animation,
+        Err(e) => {
+            tracing::warn!(error = %e, path = ?path, "Failed to inspect image for animation");
+            None
+        }
+    }
+}
+
+/// Sniffs, measures and hashes a stored file. Returns `None` if the file is not an image.
+fn inspect_file(path: &Path) -> std::io::Result<Option<FileDetails>> {
+    let file_type = FileType::sniff_file(path)?;
+    if file_type.category != FileCategory::Image {
+        return Ok(None);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut f = std::fs::File::open(path)?;
+    let mut buf = [0u8; 64 * 1024];
+    let mut size: u64 = 0;
+    loop {
+        let read = f.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+
+    let animation = animation(path, &file_type.mime_type);
+    Ok(Some(FileDetails {
+        mime_type: file_type.mime_type,
+        dimensions: image::image_dimensions(path).ok(),
+        size,
+        sha256: hex::encode(hasher.finalize()),
+        perceptual_hash: None,
+        animation,
+        placeholder: None,
+    }))
+}
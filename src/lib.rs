@@ -4,4 +4,7 @@
 //! saving them to the filesystem.
 
 pub mod conf;
+pub mod imaging;
+pub mod index;
+pub mod storage;
 pub mod webserver;
@@ -3,8 +3,16 @@
 //! A ShareX server which places any recieved images onto the clipboard, as well as optionally
 //! saving them to the filesystem.
 
-use clap::Parser;
-use yoinkx::{conf, webserver};
+use chrono::Local;
+use clap::{Parser, Subcommand};
+use yoinkx::{
+    conf,
+    imaging::phash,
+    index::{dedupe, reindex, ImageFilter, Index},
+    storage,
+    storage::routing::{RouteInput, Router},
+    webserver,
+};
 
 #[actix_web::main]
 async fn main() {
@@ -29,8 +37,203 @@ async fn main() {
     let config = conf::Config::load(args.conf).expect("Failed to read configuration");
     println!("{:?}", config);
 
-    //Start server
-    let _webserver = webserver::start(config).await;
+    match args.command {
+        None => {
+            //Start server
+            let _webserver = webserver::start(config).await;
+        }
+        Some(Command::Prune { dry_run }) => {
+            let index = Index::open_configured(&config).expect("Failed to open index");
+            let pruned = storage::retention::prune(&config, index.as_ref(), dry_run)
+                .expect("Failed to prune images");
+            for pruned_file in &pruned {
+                println!(
+                    "{}: {} ({}B)",
+                    pruned_file.reason,
+                    pruned_file.file.path.display(),
+                    pruned_file.file.size
+                );
+            }
+            let verb = if dry_run { "Would move" } else { "Moved" };
+            println!("{} {} file(s) to the trash", verb, pruned.len());
+        }
+        Some(Command::RouteTest {
+            file_name,
+            mime,
+            size,
+        }) => {
+            let router = Router::new(&config).expect("Failed to compile routing rules");
+            let mime = mime.unwrap_or_else(|| {
+                mime_guess::from_path(&file_name)
+                    .first_or_octet_stream()
+                    .essence_str()
+                    .to_owned()
+            });
+            let route = router.route(
+                &RouteInput {
+                    file_name: &file_name,
+                    mime_type: &mime,
+                    size,
+                },
+                Local::now().date_naive(),
+            );
+            match &route.rule {
+                Some((idx, Some(name))) => println!("Matched rule {} ({})", idx, name),
+                Some((idx, None)) => println!("Matched rule {}", idx),
+                None => println!("No rule matched, used subdirectory layout"),
+            }
+            let mut destination = std::path::PathBuf::from(config.target_dir.unwrap_or_default());
+            if let Some(subdir) = route.subdirectory {
+                destination.push(subdir);
+            }
+            destination.push(file_name);
+            println!("Destination: {}", destination.display());
+            if let Some(trim) = route.trim {
+                println!("Borders trimmed with tolerance {}", trim.tolerance);
+            }
+        }
+        Some(Command::Export {
+            output,
+            subdir,
+            from,
+            to,
+            ids,
+        }) => {
+            let index = Index::open_configured(&config)
+                .expect("Failed to open index")
+                .expect("Exporting requires a target directory and enable_index to be set");
+            let filter = ImageFilter::parse(subdir, from.as_deref(), to.as_deref(), ids.as_deref())
+                .expect("Invalid image selection");
+            let records = index.select(&filter).expect("Failed to query index");
+            let roots = storage::roots(&config);
+            let file = tokio::fs::File::create(&output)
+                .await
+                .expect("Failed to create output file");
+            let count = storage::archive::write_archive(&roots, &records, file)
+                .await
+                .expect("Failed to write archive");
+            println!("Exported {} image(s) to {}", count, output);
+        }
+        Some(Command::Mirror { verify, dry_run }) => {
+            let discrepancies = storage::mirror::reconcile(&config, verify, dry_run)
+                .expect("Failed to reconcile mirrors");
+            for discrepancy in &discrepancies {
+                let status = if discrepancy.repaired {
+                    "repaired"
+                } else {
+                    "not repaired"
+                };
+                println!(
+                    "{:?} in {}: {} ({})",
+                    discrepancy.problem,
+                    discrepancy.destination.display(),
+                    discrepancy.relative_path.display(),
+                    status
+                );
+            }
+            let repaired = discrepancies.iter().filter(|d| d.repaired).count();
+            println!(
+                "{} file(s) out of sync, {} repaired",
+                discrepancies.len(),
+                repaired
+            );
+        }
+        Some(Command::Reindex { remove_missing }) => {
+            let index = Index::open_configured(&config)
+                .expect("Failed to open index")
+                .expect("Indexing requires a target directory and enable_index to be set");
+            let report = reindex::reindex(&config, &index, remove_missing)
+                .expect("Failed to reindex target directory");
+            for path in &report.orphans {
+                println!("Added orphan: {}", path.display());
+            }
+            for path in &report.refreshed {
+                println!("Refreshed: {}", path.display());
+            }
+            for record in &report.missing {
+                let action = if remove_missing { "removed" } else { "kept" };
+                println!(
+                    "Missing file for image {} ({}), {}",
+                    record.id, record.path, action
+                );
+            }
+            for (path, reason) in &report.skipped {
+                println!("Skipped {}: {}", path.display(), reason);
+            }
+            println!(
+                "{} added, {} refreshed, {} unchanged, {} missing, {} skipped",
+                report.orphans.len(),
+                report.refreshed.len(),
+                report.unchanged,
+                report.missing.len(),
+                report.skipped.len()
+            );
+            if config.thumbnails.enabled {
+                println!(
+                    "{} file(s) had thumbnails regenerated, {} stale thumbnail(s) removed",
+                    report.thumbnails, report.thumbnails_removed
+                );
+            }
+            if report.hashed > 0 {
+                println!("{} image(s) had perceptual hashes computed", report.hashed);
+            }
+            if report.placeholders > 0 {
+                println!("{} image(s) had placeholders computed", report.placeholders);
+            }
+            if report.variants_removed > 0 {
+                println!("{} stale variant(s) removed", report.variants_removed);
+            }
+            if report.watermarks_removed > 0 {
+                println!(
+                    "{} stale watermarked file(s) removed",
+                    report.watermarks_removed
+                );
+            }
+        }
+        Some(Command::Dedupe {
+            similar,
+            max_distance,
+        }) => {
+            let index = Index::open_configured(&config)
+                .expect("Failed to open index")
+                .expect(
+                    "Finding duplicates requires a target directory and enable_index to be set",
+                );
+            let groups = if similar {
+                dedupe::similar_groups(&index, max_distance)
+            } else {
+                dedupe::exact_duplicates(&index)
+            }
+            .expect("Failed to query index");
+            for (idx, group) in groups.iter().enumerate() {
+                println!("Group {}:", idx + 1);
+                let first = group.first().and_then(|record| record.perceptual_hash);
+                for record in group {
+                    //Distances are given from the oldest image in the group
+                    match (similar, first, record.perceptual_hash) {
+                        (true, Some(first), Some(hash)) => println!(
+                            "  {} {} (distance {})",
+                            record.id,
+                            record.path,
+                            first.distance(&hash)
+                        ),
+                        _ => println!("  {} {}", record.id, record.path),
+                    }
+                }
+            }
+            let kind = if similar {
+                "near-duplicate"
+            } else {
+                "duplicate"
+            };
+            println!(
+                "{} group(s) of {} images, {} image(s) in total",
+                groups.len(),
+                kind,
+                groups.iter().map(Vec::len).sum::<usize>()
+            );
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -39,6 +242,71 @@ async fn main() {
 #[command(version = "1.0")]
 #[command(about = "ShareX upload server which dumps images to clipboard")]
 struct Args {
-    #[arg(short = 'f', long = "conf_file", value_hint = clap::ValueHint::FilePath, value_name = "FILE")]
+    #[arg(short = 'f', long = "conf_file", global = true, value_hint = clap::ValueHint::FilePath, value_name = "FILE")]
     conf: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Move images exceeding the configured retention limits to the trash
+    Prune {
+        /// List the files which would be removed without removing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Write a ZIP archive of the selected images
+    Export {
+        /// Path of the archive to create
+        output: String,
+        /// Only include images stored within this subdirectory
+        #[arg(long)]
+        subdir: Option<String>,
+        /// Only include images uploaded on or after this date (YYYY-MM-DD) or time (RFC 3339)
+        #[arg(long)]
+        from: Option<String>,
+        /// Only include images uploaded on or before this date (YYYY-MM-DD), or before this time (RFC 3339)
+        #[arg(long)]
+        to: Option<String>,
+        /// Comma separated list of image IDs to include
+        #[arg(long)]
+        ids: Option<String>,
+    },
+    /// Copy any stored images which are missing or differ to each configured mirror
+    Mirror {
+        /// Compare the contents of files, rather than only their sizes
+        #[arg(long)]
+        verify: bool,
+        /// List the files which are out of sync without copying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Add all images in the target directory to the metadata index, refreshing any which have changed
+    Reindex {
+        /// Remove index entries for images which no longer exist
+        #[arg(long)]
+        remove_missing: bool,
+    },
+    /// List groups of indexed images which are duplicates of each other
+    Dedupe {
+        /// Group images which look alike by comparing perceptual hashes, rather than only those
+        /// whose files are identical. Run reindex first to hash images indexed before hashes were added.
+        #[arg(long)]
+        similar: bool,
+        /// Largest number of bits which may differ between the perceptual hashes of similar images
+        #[arg(long, default_value_t = phash::DEFAULT_MAX_DISTANCE)]
+        max_distance: u32,
+    },
+    /// Show where an upload with the given filename would be stored
+    RouteTest {
+        /// Original filename of the upload
+        file_name: String,
+        /// MIME type of the upload. Guessed from the file extension if not provided.
+        #[arg(long)]
+        mime: Option<String>,
+        /// Size of the upload in bytes
+        #[arg(long, default_value_t = 0)]
+        size: u64,
+    },
 }
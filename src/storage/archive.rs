@@ -0,0 +1,52 @@
+//! Streaming ZIP archives of stored images. Entries are written one at a time as they are read
+//! from disk, so archives never need to be held in memory.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use async_zip::{base::write::ZipFileWriter, Compression, ZipDateTime, ZipEntryBuilder};
+use tokio::io::AsyncWrite;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use crate::index::ImageRecord;
+
+/// Writes a ZIP archive containing the given images to `writer`. Entries are named by their path
+/// relative to the storage directories `roots`, the first of which holding the file is used, and
+/// stored without compression since images are already compressed. Images whose files are missing
+/// are skipped. Returns the number of entries written.
+pub async fn write_archive<W: AsyncWrite + Unpin>(
+    roots: &[PathBuf],
+    records: &[ImageRecord],
+    writer: W,
+) -> Result<usize> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let mut written = 0;
+    for record in records {
+        let Some(path) = roots
+            .iter()
+            .map(|root| root.join(&record.path))
+            .find(|path| path.is_file())
+        else {
+            tracing::warn!(
+                path = record.path,
+                "Skipping file missing from every storage directory"
+            );
+            continue;
+        };
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::warn!(error = %e, path = record.path, "Skipping missing file in archive");
+                continue;
+            }
+        };
+        let entry = ZipEntryBuilder::new(record.path.clone().into(), Compression::Stored)
+            .last_modification_date(ZipDateTime::from_chrono(&record.created_at));
+        let mut entry_writer = zip.write_entry_stream(entry).await?;
+        futures_util::io::copy(file.compat(), &mut entry_writer).await?;
+        entry_writer.close().await?;
+        written += 1;
+    }
+    zip.close().await?;
+    Ok(written)
+}
@@ -0,0 +1,139 @@
+//! Rendering of the `subdirectory_layout` template into a relative directory path
+
+use std::{collections::HashMap, path::PathBuf};
+
+use chrono::{Datelike, NaiveDate};
+use regex::{Captures, Regex};
+
+/// Name of the layout placeholder which is filled from the `subdir` regex capture
+pub static PROGRAM_PLACEHOLDER: &str = "program";
+
+/// Collects the named captures of a regex match into a map which can be used to render a layout
+pub fn capture_values(regex: &Regex, captures: &Captures) -> HashMap<String, String> {
+    regex
+        .capture_names()
+        .flatten()
+        .filter_map(|name| {
+            captures
+                .name(name)
+                .map(|val| (name.to_owned(), val.as_str().to_owned()))
+        })
+        .collect()
+}
+
+/// Renders a layout template such as `{program}/{YYYY}/{MM}` into a relative path.
+/// Placeholders are looked up in `values` first, then treated as date components of `date`.
+/// Any segment containing a placeholder with no value is skipped entirely.
+pub fn render(layout: &str, values: &HashMap<String, String>, date: NaiveDate) -> PathBuf {
+    let placeholder = Regex::new(r"\{([^{}]+)\}").expect("Layout placeholder regex is invalid");
+    let mut path = PathBuf::new();
+    for segment in layout.split('/') {
+        let mut missing = false;
+        let rendered = placeholder.replace_all(segment, |caps: &Captures| {
+            let name = &caps[1];
+            match values.get(name) {
+                Some(val) => val.clone(),
+                None => date_component(name, date).unwrap_or_else(|| {
+                    missing = true;
+                    String::new()
+                }),
+            }
+        });
+        if missing {
+            continue;
+        }
+        if let Some(segment) = sanitize_segment(&rendered) {
+            path.push(segment);
+        }
+    }
+    path
+}
+
+/// Formats one of the supported date placeholders
+fn date_component(name: &str, date: NaiveDate) -> Option<String> {
+    match name {
+        "YYYY" => Some(format!("{:04}", date.year())),
+        "MM" => Some(format!("{:02}", date.month())),
+        "DD" => Some(format!("{:02}", date.day())),
+        "YYYY-MM-DD" => Some(date.format("%Y-%m-%d").to_string()),
+        _ => None,
+    }
+}
+
+/// Makes sure a rendered segment can't escape the target directory or create hidden directories
+fn sanitize_segment(segment: &str) -> Option<String> {
+    let cleaned = segment
+        .replace(['/', '\\'], "_")
+        .trim()
+        .trim_start_matches('.')
+        .to_owned();
+    if cleaned.is_empty() {
+        None
+    } else {
+        Some(cleaned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 7).unwrap()
+    }
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, val)| (name.to_string(), val.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn renders_values_and_date_components() {
+        let path = render(
+            "{program}/{YYYY}/{MM}/{DD}",
+            &values(&[("program", "firefox")]),
+            date(),
+        );
+        assert_eq!(path, PathBuf::from("firefox/2024/03/07"));
+        let path = render("shots-{YYYY-MM-DD}", &HashMap::new(), date());
+        assert_eq!(path, PathBuf::from("shots-2024-03-07"));
+    }
+
+    #[test]
+    fn values_take_precedence_over_dates() {
+        let path = render("{YYYY}", &values(&[("YYYY", "custom")]), date());
+        assert_eq!(path, PathBuf::from("custom"));
+    }
+
+    #[test]
+    fn skips_segments_with_missing_values() {
+        let path = render("{program}/{YYYY}", &HashMap::new(), date());
+        assert_eq!(path, PathBuf::from("2024"));
+        let path = render("{unknown}", &HashMap::new(), date());
+        assert_eq!(path, PathBuf::new());
+    }
+
+    #[test]
+    fn sanitizes_rendered_segments() {
+        let path = render(
+            "{program}/{other}",
+            &values(&[("program", "../../etc"), ("other", ".hidden")]),
+            date(),
+        );
+        assert_eq!(path, PathBuf::from("_.._etc/hidden"));
+        let path = render("{program}", &values(&[("program", "..")]), date());
+        assert_eq!(path, PathBuf::new());
+    }
+
+    #[test]
+    fn collects_named_captures() {
+        let regex = Regex::new(r"(?P<subdir>\w+)_(?P<num>\d+)").unwrap();
+        let captures = regex.captures("game_42.png").unwrap();
+        assert_eq!(
+            capture_values(&regex, &captures),
+            values(&[("subdir", "game"), ("num", "42")])
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Replication of stored images to backup directories. New uploads are copied in the background
+//! as soon as they are stored, and whole mirrors can be checked and repaired on demand.
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+use super::list_all_files;
+use crate::conf::{Config, MirrorConfig};
+
+/// Suffix added to files while they are being copied, so that partial copies are never mistaken
+/// for complete ones
+static PARTIAL_SUFFIX: &str = ".partial";
+
+/// Copies a file into place through a temporary file, so the destination is replaced atomically
+fn copy_atomic(src: &Path, dst: &Path) -> std::io::Result<()> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut partial = dst.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+    std::fs::copy(src, &partial)?;
+    std::fs::rename(&partial, dst)
+}
+
+/// Copies a newly stored file to every mirror destination, retrying failed copies with an
+/// exponentially increasing delay. `rel_path` is relative to the target directory `root`.
+pub async fn replicate(mirror: MirrorConfig, root: PathBuf, rel_path: PathBuf) {
+    let src = root.join(&rel_path);
+    for destination in &mirror.destinations {
+        let dst = Path::new(destination).join(&rel_path);
+        let mut delay = mirror.retry_delay;
+        let mut attempt = 0;
+        loop {
+            let (src, dst_copy) = (src.clone(), dst.clone());
+            let result = tokio::task::spawn_blocking(move || copy_atomic(&src, &dst_copy)).await;
+            match result {
+                Ok(Ok(())) => {
+                    tracing::debug!(path = ?dst, "Mirrored file");
+                    break;
+                }
+                Ok(Err(e)) if attempt < mirror.retries => {
+                    tracing::warn!(error = %e, path = ?dst, attempt, "Failed to mirror file, retrying");
+                }
+                Ok(Err(e)) => {
+                    tracing::error!(error = %e, path = ?dst, "Failed to mirror file, giving up");
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, path = ?dst, "Mirroring task panicked");
+                    break;
+                }
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// The state of a file in a mirror which did not match the target directory
+#[derive(Debug, Clone, Copy)]
+pub enum MirrorProblem {
+    /// File was not present in the mirror
+    Missing,
+    /// File in the mirror had different contents
+    Differs,
+}
+
+/// A file which was out of sync in a mirror
+#[derive(Debug)]
+pub struct MirrorDiscrepancy {
+    /// Mirror destination the file belongs in
+    pub destination: PathBuf,
+    /// Path of the file relative to the target directory
+    pub relative_path: PathBuf,
+    /// How the mirrored file differed
+    pub problem: MirrorProblem,
+    /// Whether the file was copied to fix the discrepancy
+    pub repaired: bool,
+}
+
+/// Compares every stored file against each mirror, copying over any which are missing or differ
+/// unless `dry_run` is set. Files are compared by size, and additionally by hash if `verify` is set.
+pub fn reconcile(config: &Config, verify: bool, dry_run: bool) -> Result<Vec<MirrorDiscrepancy>> {
+    if config.target_dir.is_none() {
+        return Err(anyhow!(
+            "Cannot mirror images as no target directory is configured"
+        ));
+    }
+    let files = list_all_files(config);
+    let mut discrepancies = Vec::new();
+    for destination in &config.mirror.destinations {
+        let destination = PathBuf::from(destination);
+        for file in &files {
+            let dst = destination.join(&file.relative_path);
+            let problem = match dst.metadata() {
+                Err(_) => Some(MirrorProblem::Missing),
+                Ok(metadata) if metadata.len() != file.size => Some(MirrorProblem::Differs),
+                Ok(_) if verify && hash_file(&file.path)? != hash_file(&dst)? => {
+                    Some(MirrorProblem::Differs)
+                }
+                Ok(_) => None,
+            };
+            if let Some(problem) = problem {
+                let repaired = !dry_run
+                    && copy_atomic(&file.path, &dst)
+                        .map_err(
+                            |e| tracing::error!(error = %e, path = ?dst, "Failed to repair mirror"),
+                        )
+                        .is_ok();
+                discrepancies.push(MirrorDiscrepancy {
+                    destination: destination.clone(),
+                    relative_path: file.relative_path.clone(),
+                    problem,
+                    repaired,
+                });
+            }
+        }
+    }
+    Ok(discrepancies)
+}
+
+/// Computes the SHA-256 hash of a file
+fn hash_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    let mut f = std::fs::File::open(path)?;
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = f.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
@@ -0,0 +1,122 @@
+//! Helpers for managing images which have been saved to the target directory
+
+pub mod archive;
+pub mod layout;
+pub mod mirror;
+pub mod optimise;
+pub mod retention;
+pub mod routing;
+pub mod trash;
+pub mod versions;
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::conf::Config;
+
+/// An image file found within the target directory
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    /// Absolute path to the file
+    pub path: PathBuf,
+    /// Path to the file relative to the directory it was found in
+    pub relative_path: PathBuf,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Time the file was last modified
+    pub modified: SystemTime,
+}
+
+/// Returns true if a directory entry should be hidden from listings and pruning
+fn is_hidden(entry: &walkdir::DirEntry) -> bool {
+    entry.file_name().to_string_lossy().starts_with('.')
+}
+
+/// Recursively lists all files stored within `root`, skipping any hidden files and directories
+pub fn list_files(root: impl AsRef<Path>) -> Vec<StoredFile> {
+    let root = root.as_ref();
+    walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !is_hidden(entry))
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read entry in storage directory");
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let metadata = entry
+                .metadata()
+                .map_err(|e| tracing::warn!(error = %e, path = ?entry.path(), "Failed to read file metadata"))
+                .ok()?;
+            Some(StoredFile {
+                path: entry.path().to_owned(),
+                relative_path: entry.path().strip_prefix(root).ok()?.to_owned(),
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            })
+        })
+        .collect()
+}
+
+/// Returns every directory images are stored in, starting with the target directory and followed
+/// by the failover directories in order. Empty if no target directory is configured.
+pub fn roots(config: &Config) -> Vec<PathBuf> {
+    match &config.target_dir {
+        Some(tgt_dir) => std::iter::once(tgt_dir)
+            .chain(&config.failover_dirs)
+            .map(PathBuf::from)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Finds the storage directory holding a file, given its path relative to the storage directories
+pub fn find_root(config: &Config, rel_path: impl AsRef<Path>) -> Option<PathBuf> {
+    roots(config)
+        .into_iter()
+        .find(|root| root.join(rel_path.as_ref()).exists())
+}
+
+/// Returns true if a file exists at the given relative path in any storage directory
+pub fn exists_in_any_root(config: &Config, rel_path: impl AsRef<Path>) -> bool {
+    find_root(config, rel_path).is_some()
+}
+
+/// Lists the files stored across every storage directory. If the same relative path exists in
+/// several directories, only the one in the earliest directory is included, as that is the one
+/// which is served.
+pub fn list_all_files(config: &Config) -> Vec<StoredFile> {
+    let mut seen = HashSet::new();
+    roots(config)
+        .iter()
+        .flat_map(list_files)
+        .filter(|file| seen.insert(file.relative_path.clone()))
+        .collect()
+}
+
+/// Chooses the first storage directory whose volume would still have at least `min_free_space`
+/// bytes available after storing `needed` more bytes. Returns `None` if every volume is too full.
+pub fn choose_root(config: &Config, needed: u64) -> Option<PathBuf> {
+    let required = config.min_free_space.saturating_add(needed);
+    roots(config).into_iter().find(|root| {
+        //Directories which don't exist yet are created on the volume of their nearest ancestor
+        let existing = root.ancestors().find(|dir| dir.exists()).unwrap_or(root);
+        match fs2::available_space(existing) {
+            Ok(available) if available >= required => true,
+            Ok(available) => {
+                tracing::warn!(directory = ?root, available, required, "Storage directory is low on space");
+                false
+            }
+            Err(e) => {
+                tracing::error!(error = %e, directory = ?root, "Failed to check free space");
+                false
+            }
+        }
+    })
+}
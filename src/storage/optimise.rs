@@ -0,0 +1,249 @@
+//! Background queue which losslessly recompresses stored images after they are uploaded. PNGs are
+//! recompressed with oxipng, and JPEGs with an external command if one is configured. A file is
+//! only replaced if the new copy is smaller and decodes to exactly the same pixels.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use image::ImageFormat;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use super::{mirror, versions::EditLocks};
+use crate::{
+    conf::{Config, DecodeLimits, OptimiseConfig},
+    imaging::{animation, decode_data},
+    index::{FileDetails, Index},
+};
+
+/// Counts of what the optimiser has done since the server started
+#[derive(Debug, Default)]
+pub struct OptimiseStats {
+    /// Files waiting to be optimised
+    pub queued: AtomicU64,
+    /// Files replaced by a smaller copy
+    pub replaced: AtomicU64,
+    /// Files left as they were, because they couldn't be made smaller or aren't supported
+    pub unchanged: AtomicU64,
+    /// Files which couldn't be optimised due to an error
+    pub failed: AtomicU64,
+    /// Total bytes saved by replacing files
+    pub bytes_saved: AtomicU64,
+}
+
+/// What happened to a file sent to the optimiser
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The file was replaced by a smaller copy
+    Replaced {
+        /// Size of the file before it was optimised
+        before: u64,
+        /// Size of the optimised file
+        after: u64,
+    },
+    /// No smaller copy could be made
+    NotSmaller,
+    /// The file isn't in a format which can be optimised, or is animated
+    Unsupported,
+    /// The optimised copy decoded to different pixels, so was discarded
+    PixelsDiffer,
+    /// The file was changed or removed while it was being optimised
+    Modified,
+}
+
+/// A stored file waiting to be optimised
+#[derive(Debug)]
+struct Job {
+    /// Storage directory holding the file
+    root: PathBuf,
+    /// Path of the file relative to its storage directory
+    rel_path: PathBuf,
+}
+
+/// Handle to the background optimisation queue
+#[derive(Debug)]
+pub struct OptimiseQueue {
+    sender: mpsc::UnboundedSender<Job>,
+    stats: Arc<OptimiseStats>,
+}
+
+impl OptimiseQueue {
+    /// Starts the task which optimises queued files one at a time. Must be called from within the
+    /// async runtime.
+    pub fn start(config: Config, index: Option<Arc<Index>>, locks: Arc<EditLocks>) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Job>();
+        let stats = Arc::new(OptimiseStats::default());
+        let task_stats = stats.clone();
+        actix_web::rt::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                let (conf, index_handle, locks, root, rel_path) = (
+                    config.clone(),
+                    index.clone(),
+                    locks.clone(),
+                    job.root.clone(),
+                    job.rel_path.clone(),
+                );
+                let result = tokio::task::spawn_blocking(move || {
+                    optimise_file(&conf, index_handle.as_deref(), &locks, &root, &rel_path)
+                })
+                .await
+                .map_err(|e| anyhow!("Optimisation task panicked: {}", e))
+                .and_then(|result| result);
+                task_stats.queued.fetch_sub(1, Ordering::Relaxed);
+                match result {
+                    Ok(Outcome::Replaced { before, after }) => {
+                        tracing::info!(path = ?job.rel_path, before, after, "Optimised image");
+                        task_stats.replaced.fetch_add(1, Ordering::Relaxed);
+                        task_stats
+                            .bytes_saved
+                            .fetch_add(before - after, Ordering::Relaxed);
+                        //Mirrors hold the file as it was uploaded, so need the smaller copy too
+                        if !config.mirror.destinations.is_empty() {
+                            mirror::replicate(config.mirror.clone(), job.root, job.rel_path).await;
+                        }
+                    }
+                    Ok(outcome) => {
+                        tracing::debug!(path = ?job.rel_path, ?outcome, "Left image unoptimised");
+                        task_stats.unchanged.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        tracing::warn!(error = %e, path = ?job.rel_path, "Failed to optimise image");
+                        task_stats.failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+        Self { sender, stats }
+    }
+
+    /// Adds a stored file to the queue. `rel_path` is relative to the storage directory `root`.
+    pub fn enqueue(&self, root: PathBuf, rel_path: PathBuf) {
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        if self.sender.send(Job { root, rel_path }).is_err() {
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+            tracing::error!("Optimisation queue has stopped");
+        }
+    }
+
+    /// Returns counts of what the optimiser has done
+    pub fn stats(&self) -> &OptimiseStats {
+        &self.stats
+    }
+}
+
+/// Recompresses PNG data with oxipng
+fn optimise_png(config: &OptimiseConfig, data: &[u8]) -> Result<Vec<u8>> {
+    let mut options = oxipng::Options::from_preset(config.level.min(6));
+    options.timeout = Some(config.timeout);
+    Ok(oxipng::optimize_from_memory(data, &options)?)
+}
+
+/// Recompresses a JPEG by running the configured command on it
+fn optimise_jpeg(config: &OptimiseConfig, path: &Path) -> Result<Vec<u8>> {
+    let (program, args) = config
+        .jpeg_command
+        .split_first()
+        .ok_or_else(|| anyhow!("No JPEG optimisation command is configured"))?;
+    let output = tempfile::NamedTempFile::new()?.into_temp_path();
+    let args = args.iter().map(|arg| {
+        arg.replace("{input}", &path.to_string_lossy())
+            .replace("{output}", &output.to_string_lossy())
+    });
+    let status = Command::new(program).args(args).status()?;
+    if !status.success() {
+        return Err(anyhow!("{} exited with {}", program, status));
+    }
+    Ok(std::fs::read(&output)?)
+}
+
+/// Returns true if two encoded images decode to the same pixels. Images exceeding the decode limits
+/// are treated as errors rather than decoded.
+fn same_pixels(a: &[u8], b: &[u8], limits: &DecodeLimits) -> Result<bool> {
+    let (a, b) = (decode_data(a, limits)?, decode_data(b, limits)?);
+    Ok(a.width() == b.width()
+        && a.height() == b.height()
+        && a.into_rgba16().into_raw() == b.into_rgba16().into_raw())
+}
+
+/// Optimises a single stored file, replacing it and refreshing its index entry if a smaller copy
+/// with the same pixels could be made. The file is swapped in while holding the image's edit lock,
+/// so an edit can't land between checking the file is unchanged and replacing it.
+pub fn optimise_file(
+    config: &Config,
+    index: Option<&Index>,
+    locks: &EditLocks,
+    root: &Path,
+    rel_path: &Path,
+) -> Result<Outcome> {
+    let path = root.join(rel_path);
+    let data = std::fs::read(&path)?;
+    //Only the first frames would be compared, so animations are left as they are
+    if animation::inspect(&data)?.is_some() {
+        return Ok(Outcome::Unsupported);
+    }
+    let optimised = match image::guess_format(&data) {
+        Ok(ImageFormat::Png) => optimise_png(&config.optimise, &data)?,
+        Ok(ImageFormat::Jpeg) if !config.optimise.jpeg_command.is_empty() => {
+            optimise_jpeg(&config.optimise, &path)?
+        }
+        _ => return Ok(Outcome::Unsupported),
+    };
+    if optimised.is_empty() || optimised.len() >= data.len() {
+        return Ok(Outcome::NotSmaller);
+    }
+    if !same_pixels(&data, &optimised, &config.decode_limits)? {
+        return Ok(Outcome::PixelsDiffer);
+    }
+
+    //Write the optimised copy alongside the file, and only swap it in if the file hasn't been
+    //edited or removed in the meantime
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("Stored file has no parent directory"))?;
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    std::io::Write::write_all(&mut temp_file, &optimised)?;
+    let lock = match index {
+        Some(index) => index
+            .find_by_path(rel_path)?
+            .map(|record| locks.for_image(record.id)),
+        None => None,
+    };
+    let _guard = lock
+        .as_ref()
+        .map(|lock| lock.lock().unwrap_or_else(PoisonError::into_inner));
+    if std::fs::read(&path).ok().as_deref() != Some(data.as_slice()) {
+        return Ok(Outcome::Modified);
+    }
+    temp_file
+        .as_file()
+        .set_permissions(std::fs::metadata(&path)?.permissions())?;
+    temp_file.persist(&path)?;
+
+    if let Some(index) = index {
+        if let Some(record) = index.find_by_path(rel_path)? {
+            index.refresh_file(
+                record.id,
+                &FileDetails {
+                    mime_type: record.mime_type,
+                    dimensions: record.width.zip(record.height),
+                    size: optimised.len() as u64,
+                    sha256: hex::encode(Sha256::digest(&optimised)),
+                    perceptual_hash: record.perceptual_hash,
+                    animation: record.animation,
+                    placeholder: record.placeholder,
+                },
+            )?;
+        }
+    }
+    Ok(Outcome::Replaced {
+        before: data.len() as u64,
+        after: optimised.len() as u64,
+    })
+}
@@ -0,0 +1,307 @@
+//! Enforcement of the limits configured in [`RetentionConfig`], either periodically
+//! from the webserver or on demand from the command line.
+
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf, sync::Arc, time::SystemTime};
+
+use anyhow::{anyhow, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use super::{list_all_files, trash, StoredFile};
+use crate::{
+    conf::{Config, RetentionConfig},
+    index::Index,
+};
+
+/// A file which was (or in a dry run, would have been) removed
+#[derive(Debug)]
+pub struct PrunedFile {
+    /// The file that was removed
+    pub file: StoredFile,
+    /// The first limit which the file was found to exceed
+    pub reason: PruneReason,
+}
+
+/// The limit that caused a file to be pruned
+#[derive(Debug, Clone, Copy)]
+pub enum PruneReason {
+    /// File was older than the maximum age
+    MaxAge,
+    /// Covered directory contained too many files
+    MaxFileCount,
+    /// Covered directory took up too much space
+    MaxTotalSize,
+}
+
+impl Display for PruneReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PruneReason::MaxAge => write!(f, "exceeded maximum age"),
+            PruneReason::MaxFileCount => write!(f, "exceeded maximum file count"),
+            PruneReason::MaxTotalSize => write!(f, "exceeded maximum total size"),
+        }
+    }
+}
+
+/// Builds a matcher for the pinned file patterns
+fn pinned_matcher(retention: &RetentionConfig) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in &retention.pinned {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Works out which files in the target directory exceed the configured retention limits,
+/// then moves them to the trash unless `dry_run` is set. Pinned files are never removed.
+pub fn prune(config: &Config, index: Option<&Index>, dry_run: bool) -> Result<Vec<PrunedFile>> {
+    if config.target_dir.is_none() {
+        return Err(anyhow!(
+            "Cannot prune images as no target directory is configured"
+        ));
+    }
+    let pinned = pinned_matcher(&config.retention)?;
+    let now = SystemTime::now();
+
+    let mut files: Vec<StoredFile> = list_all_files(config)
+        .into_iter()
+        .filter(|file| !pinned.is_match(&file.relative_path))
+        .collect();
+    //Oldest files are always the first to go
+    files.sort_by_key(|file| file.modified);
+
+    let mut selected: BTreeMap<PathBuf, PruneReason> = BTreeMap::new();
+    for rule in &config.retention.rules {
+        let mut covered: Vec<&StoredFile> = files
+            .iter()
+            .filter(|file| match &rule.subdirectory {
+                Some(subdir) => file.relative_path.starts_with(subdir),
+                None => true,
+            })
+            .filter(|file| !selected.contains_key(&file.path))
+            .collect();
+
+        if let Some(max_age) = rule.max_age {
+            covered.retain(|file| {
+                let age = now.duration_since(file.modified).unwrap_or_default();
+                if age > max_age {
+                    selected.insert(file.path.clone(), PruneReason::MaxAge);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+
+        if let Some(max_count) = rule.max_file_count {
+            let excess = covered.len().saturating_sub(max_count as usize);
+            for file in covered.drain(..excess) {
+                selected.insert(file.path.clone(), PruneReason::MaxFileCount);
+            }
+        }
+
+        if let Some(max_size) = rule.max_total_size {
+            let mut total: u64 = covered.iter().map(|file| file.size).sum();
+            let mut excess = 0;
+            while total > max_size && excess < covered.len() {
+                total -= covered[excess].size;
+                excess += 1;
+            }
+            for file in covered.drain(..excess) {
+                selected.insert(file.path.clone(), PruneReason::MaxTotalSize);
+            }
+        }
+    }
+
+    let mut pruned = Vec::with_capacity(selected.len());
+    for file in files {
+        if let Some(reason) = selected.remove(&file.path) {
+            if !dry_run {
+                if let Err(e) = trash::move_to_trash(config, index, &file.relative_path) {
+                    tracing::error!(error = %e, path = ?file.path, "Failed to prune file");
+                    continue;
+                }
+                tracing::info!(path = ?file.path, %reason, "Pruned file");
+            }
+            pruned.push(PrunedFile { file, reason });
+        }
+    }
+    Ok(pruned)
+}
+
+/// Repeatedly prunes the target directory at the configured interval. Never returns.
+pub async fn prune_periodically(config: Config, index: Option<Arc<Index>>) {
+    if config.retention.interval.is_zero() {
+        tracing::error!(
+            "Retention interval must be greater than zero; background pruning disabled"
+        );
+        return;
+    }
+    let mut interval = tokio::time::interval(config.retention.interval);
+    loop {
+        interval.tick().await;
+        let conf = config.clone();
+        let index = index.clone();
+        match tokio::task::spawn_blocking(move || prune(&conf, index.as_deref(), false)).await {
+            Ok(Ok(pruned)) => {
+                tracing::debug!(count = pruned.len(), "Finished pruning target directory")
+            }
+            Ok(Err(e)) => tracing::error!(error = %e, "Failed to prune target directory"),
+            Err(e) => tracing::error!(error = %e, "Pruning task panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use super::*;
+    use crate::conf::RetentionRule;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    /// Stores a file of the given size, last modified the given time ago
+    fn write(root: &Path, rel_path: &str, size: usize, age: Duration) {
+        let path = root.join(rel_path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, vec![0; size]).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    fn config(root: &Path, rules: Vec<RetentionRule>, pinned: &[&str]) -> Config {
+        Config {
+            target_dir: Some(root.to_string_lossy().to_string()),
+            retention: RetentionConfig {
+                rules,
+                pinned: pinned.iter().map(|&pattern| pattern.to_owned()).collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Relative paths and reasons of the pruned files, oldest first
+    fn summary(pruned: &[PrunedFile]) -> Vec<(String, String)> {
+        pruned
+            .iter()
+            .map(|pruned| {
+                (
+                    pruned.file.relative_path.to_string_lossy().to_string(),
+                    format!("{:?}", pruned.reason),
+                )
+            })
+            .collect()
+    }
+
+    fn expected(files: &[(&str, &str)]) -> Vec<(String, String)> {
+        files
+            .iter()
+            .map(|&(path, reason)| (path.to_owned(), reason.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn rules_apply_to_their_own_subdirectories_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "top.png", 10, 240 * HOUR);
+        write(dir.path(), "a/old.png", 10, 200 * HOUR);
+        write(dir.path(), "b/old.png", 100, 150 * HOUR);
+        write(dir.path(), "a/1.png", 10, 3 * HOUR);
+        write(dir.path(), "a/2.png", 10, 2 * HOUR);
+        write(dir.path(), "b/new.png", 100, 2 * HOUR);
+        write(dir.path(), "a/3.png", 10, HOUR);
+        let config = config(
+            dir.path(),
+            vec![
+                RetentionRule {
+                    subdirectory: Some("a".to_owned()),
+                    max_age: Some(100 * HOUR),
+                    max_file_count: Some(2),
+                    ..Default::default()
+                },
+                RetentionRule {
+                    subdirectory: Some("b".to_owned()),
+                    max_total_size: Some(150),
+                    ..Default::default()
+                },
+                //Files already selected by earlier rules don't count towards later ones
+                RetentionRule {
+                    max_file_count: Some(3),
+                    ..Default::default()
+                },
+            ],
+            &[],
+        );
+
+        let pruned = prune(&config, None, true).unwrap();
+        assert_eq!(
+            summary(&pruned),
+            expected(&[
+                ("top.png", "MaxFileCount"),
+                ("a/old.png", "MaxAge"),
+                ("b/old.png", "MaxTotalSize"),
+                ("a/1.png", "MaxFileCount"),
+            ])
+        );
+    }
+
+    #[test]
+    fn pinned_files_are_exempt_and_not_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "keep/a.png", 10, 300 * HOUR);
+        write(dir.path(), "logo.gif", 10, 300 * HOUR);
+        write(dir.path(), "old.png", 10, 200 * HOUR);
+        write(dir.path(), "new.png", 10, HOUR);
+        let config = config(
+            dir.path(),
+            vec![RetentionRule {
+                max_file_count: Some(1),
+                ..Default::default()
+            }],
+            &["keep/**", "*.gif"],
+        );
+
+        let pruned = prune(&config, None, true).unwrap();
+        assert_eq!(summary(&pruned), expected(&[("old.png", "MaxFileCount")]));
+    }
+
+    #[test]
+    fn dry_runs_leave_files_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "old.png", 10, 200 * HOUR);
+        write(dir.path(), "new.png", 10, HOUR);
+        let config = config(
+            dir.path(),
+            vec![RetentionRule {
+                max_age: Some(100 * HOUR),
+                ..Default::default()
+            }],
+            &[],
+        );
+
+        let pruned = prune(&config, None, true).unwrap();
+        assert_eq!(summary(&pruned), expected(&[("old.png", "MaxAge")]));
+        assert!(dir.path().join("old.png").is_file());
+
+        let pruned = prune(&config, None, false).unwrap();
+        assert_eq!(summary(&pruned), expected(&[("old.png", "MaxAge")]));
+        assert!(!dir.path().join("old.png").exists());
+        assert!(dir.path().join("new.png").is_file());
+        let trashed = trash::list(&config, None).unwrap();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed[0].original_path, "old.png");
+
+        //Trashed files are no longer covered by the rules
+        assert!(prune(&config, None, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn requires_a_target_directory() {
+        assert!(prune(&Config::default(), None, true).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! Ordered routing rules which decide the subdirectory an upload is stored in.
+//! Rules are tried in order, and the first one whose conditions all match is used. If no rule
+//! matches, the `subdirectory_layout` template is used as a fallback.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+
+use super::layout;
+use crate::conf::{Config, RoutingRule, TrimRule};
+
+/// Name of the regex capture used to fill in `{program}` in the fallback layout
+static SUBDIR_CAPTURE_NAME: &str = "subdir";
+
+/// Facts about an upload which routing rules can match against
+#[derive(Debug)]
+pub struct RouteInput<'a> {
+    /// Original filename of the upload, including extension
+    pub file_name: &'a str,
+    /// Detected MIME type of the upload
+    pub mime_type: &'a str,
+    /// Size of the upload in bytes
+    pub size: u64,
+}
+
+/// The outcome of routing an upload
+#[derive(Debug)]
+pub struct Route {
+    /// Position in `routing_rules` and name of the rule that matched, or `None` if the
+    /// fallback layout was used
+    pub rule: Option<(usize, Option<String>)>,
+    /// Subdirectory of the target directory the upload should be stored in
+    pub subdirectory: Option<PathBuf>,
+    /// How to crop borders from the upload, if the matching rule asks for it
+    pub trim: Option<TrimRule>,
+}
+
+/// A routing rule with its patterns compiled
+#[derive(Debug)]
+struct CompiledRule {
+    name: Option<String>,
+    regex: Option<Regex>,
+    glob: Option<GlobMatcher>,
+    mime: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    destination: String,
+    trim: Option<TrimRule>,
+}
+
+impl CompiledRule {
+    fn compile(rule: &RoutingRule) -> Result<Self> {
+        Ok(Self {
+            name: rule.name.clone(),
+            regex: rule.regex.as_deref().map(Regex::new).transpose()?,
+            glob: rule
+                .glob
+                .as_deref()
+                .map(|glob| Glob::new(glob).map(|glob| glob.compile_matcher()))
+                .transpose()?,
+            mime: rule.mime.as_ref().map(|mime| mime.to_lowercase()),
+            min_size: rule.min_size,
+            max_size: rule.max_size,
+            destination: rule.destination.clone(),
+            trim: rule.trim.clone(),
+        })
+    }
+
+    /// Returns the named captures to render the destination with if every condition matches
+    fn matches(&self, input: &RouteInput) -> Option<HashMap<String, String>> {
+        if let Some(glob) = &self.glob {
+            if !glob.is_match(input.file_name) {
+                return None;
+            }
+        }
+        if let Some(mime) = &self.mime {
+            if !mime_matches(mime, &input.mime_type.to_lowercase()) {
+                return None;
+            }
+        }
+        if self.min_size.is_some_and(|min| input.size < min)
+            || self.max_size.is_some_and(|max| input.size > max)
+        {
+            return None;
+        }
+        match &self.regex {
+            Some(regex) => regex
+                .captures(input.file_name)
+                .map(|captures| layout::capture_values(regex, &captures)),
+            None => Some(HashMap::new()),
+        }
+    }
+}
+
+/// Returns true if a MIME type matches a pattern such as `image/png` or `image/*`
+pub(crate) fn mime_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(top_level) => mime_type.split('/').next() == Some(top_level),
+        None => pattern == "*" || pattern == mime_type,
+    }
+}
+
+/// Compiled set of routing rules, along with the fallback layout
+#[derive(Debug)]
+pub struct Router {
+    rules: Vec<CompiledRule>,
+    fallback_regex: Option<Regex>,
+    fallback_layout: String,
+}
+
+impl Router {
+    /// Compiles the routing rules and fallback layout from the configuration
+    pub fn new(config: &Config) -> Result<Self> {
+        let rules = config
+            .routing_rules
+            .iter()
+            .map(CompiledRule::compile)
+            .collect::<Result<Vec<_>>>()?;
+        let fallback_regex = if config.enable_subdirectories {
+            Some(Regex::new(&config.subdirectory_regex)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            rules,
+            fallback_regex,
+            fallback_layout: config.subdirectory_layout.clone(),
+        })
+    }
+
+    /// Works out the subdirectory an upload should be stored in, using the first matching rule
+    pub fn route(&self, input: &RouteInput, date: NaiveDate) -> Route {
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if let Some(values) = rule.matches(input) {
+                return Route {
+                    rule: Some((idx, rule.name.clone())),
+                    subdirectory: non_empty(layout::render(&rule.destination, &values, date)),
+                    trim: rule.trim.clone(),
+                };
+            }
+        }
+
+        let mut values = HashMap::new();
+        if let Some(regex) = &self.fallback_regex {
+            if let Some(captures) = regex.captures(input.file_name) {
+                values = layout::capture_values(regex, &captures);
+                if let Some(program) = values.get(SUBDIR_CAPTURE_NAME).cloned() {
+                    values.insert(layout::PROGRAM_PLACEHOLDER.to_owned(), program);
+                }
+            }
+        }
+        Route {
+            rule: None,
+            subdirectory: non_empty(layout::render(&self.fallback_layout, &values, date)),
+            trim: None,
+        }
+    }
+}
+
+fn non_empty(path: PathBuf) -> Option<PathBuf> {
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 7).unwrap()
+    }
+
+    fn input<'a>(file_name: &'a str, mime_type: &'a str, size: u64) -> RouteInput<'a> {
+        RouteInput {
+            file_name,
+            mime_type,
+            size,
+        }
+    }
+
+    fn router(rules: Vec<RoutingRule>) -> Router {
+        Router::new(&Config {
+            routing_rules: rules,
+            subdirectory_layout: "{YYYY}".to_owned(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_mime_patterns() {
+        assert!(mime_matches("image/png", "image/png"));
+        assert!(mime_matches("image/*", "image/jpeg"));
+        assert!(mime_matches("*", "video/mp4"));
+        assert!(!mime_matches("image/*", "video/mp4"));
+        assert!(!mime_matches("image/png", "image/jpeg"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let router = router(vec![
+            RoutingRule {
+                name: Some("large".to_owned()),
+                min_size: Some(1000),
+                destination: "large".to_owned(),
+                ..Default::default()
+            },
+            RoutingRule {
+                glob: Some("*.png".to_owned()),
+                destination: "png".to_owned(),
+                ..Default::default()
+            },
+            RoutingRule {
+                mime: Some("IMAGE/*".to_owned()),
+                destination: "images".to_owned(),
+                ..Default::default()
+            },
+        ]);
+        let route = router.route(&input("a.png", "image/png", 5000), date());
+        assert_eq!(route.rule, Some((0, Some("large".to_owned()))));
+        assert_eq!(route.subdirectory, Some(PathBuf::from("large")));
+        let route = router.route(&input("a.png", "image/png", 10), date());
+        assert_eq!(route.rule, Some((1, None)));
+        let route = router.route(&input("a.jpg", "image/jpeg", 10), date());
+        assert_eq!(route.subdirectory, Some(PathBuf::from("images")));
+    }
+
+    #[test]
+    fn renders_regex_captures_into_destination() {
+        let router = router(vec![RoutingRule {
+            regex: Some(r"^(?P<game>[a-z]+)_\d+".to_owned()),
+            max_size: Some(100),
+            destination: "games/{game}/{MM}".to_owned(),
+            ..Default::default()
+        }]);
+        let route = router.route(&input("doom_123.png", "image/png", 50), date());
+        assert_eq!(route.subdirectory, Some(PathBuf::from("games/doom/03")));
+        //Too large for the rule, so the fallback layout is used
+        let route = router.route(&input("doom_123.png", "image/png", 500), date());
+        assert_eq!(route.rule, None);
+        assert_eq!(route.subdirectory, Some(PathBuf::from("2024")));
+    }
+
+    #[test]
+    fn fallback_fills_program_from_subdirectory_regex() {
+        let router = Router::new(&Config {
+            enable_subdirectories: true,
+            subdirectory_regex: r"(?P<subdir>.*)_[\d\w]{10}.[\w]+".to_owned(),
+            subdirectory_layout: "{program}/{YYYY}".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        let route = router.route(&input("firefox_abcdef1234.png", "image/png", 1), date());
+        assert_eq!(route.subdirectory, Some(PathBuf::from("firefox/2024")));
+        let route = router.route(&input("plain.png", "image/png", 1), date());
+        assert_eq!(route.subdirectory, Some(PathBuf::from("2024")));
+    }
+
+    #[test]
+    fn empty_layout_stores_in_target_directory() {
+        let router = Router::new(&Config::default()).unwrap();
+        let route = router.route(&input("a.png", "image/png", 1), date());
+        assert_eq!(route.subdirectory, None);
+    }
+}
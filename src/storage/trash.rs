@@ -0,0 +1,515 @@
+//! Soft deletion of stored images. Deleted images are moved into a hidden trash directory
+//! within the storage directory holding them, laid out as `<deletion time in ms>/<original path>`,
+//! so that they can be restored to their original location until they are purged.
+
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde_derive::Serialize;
+use thiserror::Error;
+
+use super::{exists_in_any_root, find_root, list_files, roots, versions};
+use crate::{conf::Config, index::Index};
+
+/// Name of the hidden directory within the target directory that deleted files are moved to
+pub static TRASH_DIR_NAME: &str = ".trash";
+
+/// Errors which can occur when moving files into or out of the trash
+#[derive(Debug, Error)]
+#[allow(missing_docs)]
+pub enum TrashError {
+    #[error("Trash is unavailable as no target directory is configured")]
+    NoTargetDir(),
+    #[error("Illegal trash path: {0}")]
+    InvalidPath(String),
+    #[error("No file found at {0}")]
+    NotFound(String),
+    #[error("Cannot restore as a file already exists at {0}")]
+    RestoreConflict(String),
+    #[error("Failed to move file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to update index: {0}")]
+    Index(anyhow::Error),
+}
+
+/// A deleted file waiting in the trash
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashEntry {
+    /// Location of the file within the trash directory, used to refer to it when restoring
+    pub trash_path: String,
+    /// Path the file was stored at before deletion, relative to the target directory
+    pub original_path: String,
+    /// Time the file was deleted
+    pub deleted_at: DateTime<Utc>,
+    /// Time after which the file will be permanently removed
+    pub purge_at: DateTime<Utc>,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// ID of the deleted image in the index, if it was indexed
+    pub image_id: Option<i64>,
+    /// Trash directory holding the file
+    #[serde(skip)]
+    pub trash_dir: PathBuf,
+}
+
+/// Returns the trash directory of every configured storage directory
+pub fn trash_dirs(config: &Config) -> Result<Vec<PathBuf>, TrashError> {
+    let roots = roots(config);
+    if roots.is_empty() {
+        return Err(TrashError::NoTargetDir());
+    }
+    Ok(roots
+        .into_iter()
+        .map(|root| root.join(TRASH_DIR_NAME))
+        .collect())
+}
+
+/// Makes sure a path supplied by a client is relative and can't escape the directory it is joined to
+fn check_relative(path: &Path) -> Result<(), TrashError> {
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        Err(TrashError::InvalidPath(path.to_string_lossy().to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Moves a stored file into the trash, recording the deletion in the index if the file is indexed.
+/// `rel_path` is relative to the target directory.
+pub fn move_to_trash(
+    config: &Config,
+    index: Option<&Index>,
+    rel_path: impl AsRef<Path>,
+) -> Result<TrashEntry, TrashError> {
+    let rel_path = rel_path.as_ref();
+    check_relative(rel_path)?;
+    if config.target_dir.is_none() {
+        return Err(TrashError::NoTargetDir());
+    }
+    let root = find_root(config, rel_path)
+        .ok_or_else(|| TrashError::NotFound(rel_path.to_string_lossy().to_string()))?;
+    let trash_dir = root.join(TRASH_DIR_NAME);
+    let src = root.join(rel_path);
+    if !src.is_file() {
+        return Err(TrashError::NotFound(rel_path.to_string_lossy().to_string()));
+    }
+
+    let deleted_at = Utc::now();
+    let trash_path = PathBuf::from(deleted_at.timestamp_millis().to_string()).join(rel_path);
+    let dst = trash_dir.join(&trash_path);
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let size = src.metadata()?.len();
+    std::fs::rename(&src, &dst)?;
+    tracing::info!(path = ?rel_path, trash_path = ?trash_path, "Moved file to trash");
+
+    let mut image_id = None;
+    if let Some(index) = index {
+        if let Some(record) = index.find_by_path(rel_path).map_err(TrashError::Index)? {
+            index
+                .mark_deleted(record.id, Path::new(TRASH_DIR_NAME).join(&trash_path))
+                .map_err(TrashError::Index)?;
+            image_id = Some(record.id);
+        }
+    }
+
+    Ok(TrashEntry {
+        trash_path: trash_path.to_string_lossy().to_string(),
+        original_path: rel_path.to_string_lossy().to_string(),
+        deleted_at,
+        purge_at: purge_at(config, deleted_at),
+        size,
+        image_id,
+        trash_dir,
+    })
+}
+
+/// Lists every file currently in the trash, oldest deletion first
+pub fn list(config: &Config, index: Option<&Index>) -> Result<Vec<TrashEntry>, TrashError> {
+    let mut entries: Vec<TrashEntry> = trash_dirs(config)?
+        .into_iter()
+        .filter(|trash_dir| trash_dir.is_dir())
+        .flat_map(|trash_dir| {
+            list_files(&trash_dir)
+                .into_iter()
+                .map(move |file| (trash_dir.clone(), file))
+        })
+        .filter_map(|(trash_dir, file)| {
+            let entry = parse_entry(config, &trash_dir, &file.relative_path, file.size)?;
+            let image_id = index.and_then(|index| {
+                index
+                    .find_by_path(Path::new(TRASH_DIR_NAME).join(&file.relative_path))
+                    .ok()
+                    .flatten()
+                    .map(|record| record.id)
+            });
+            Some(TrashEntry { image_id, ..entry })
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.deleted_at);
+    Ok(entries)
+}
+
+/// Interprets a path within a trash directory as a trash entry
+fn parse_entry(
+    config: &Config,
+    trash_dir: &Path,
+    trash_path: &Path,
+    size: u64,
+) -> Option<TrashEntry> {
+    let mut components = trash_path.components();
+    let millis: i64 = components.next()?.as_os_str().to_str()?.parse().ok()?;
+    let original_path = components.as_path();
+    if original_path.as_os_str().is_empty() {
+        return None;
+    }
+    let deleted_at = Utc.timestamp_millis_opt(millis).single()?;
+    Some(TrashEntry {
+        trash_path: trash_path.to_string_lossy().to_string(),
+        original_path: original_path.to_string_lossy().to_string(),
+        deleted_at,
+        purge_at: purge_at(config, deleted_at),
+        size,
+        image_id: None,
+        trash_dir: trash_dir.to_owned(),
+    })
+}
+
+/// Moves a file out of the trash back to the location it was deleted from. `trash_path` is
+/// relative to the trash directory. Returns the entry which was restored.
+pub fn restore(
+    config: &Config,
+    index: Option<&Index>,
+    trash_path: impl AsRef<Path>,
+) -> Result<TrashEntry, TrashError> {
+    let trash_path = trash_path.as_ref();
+    check_relative(trash_path)?;
+    let trash_dir = trash_dirs(config)?
+        .into_iter()
+        .find(|trash_dir| trash_dir.join(trash_path).is_file())
+        .ok_or_else(|| TrashError::NotFound(trash_path.to_string_lossy().to_string()))?;
+    let src = trash_dir.join(trash_path);
+    let size = src
+        .metadata()
+        .map_err(|_| TrashError::NotFound(trash_path.to_string_lossy().to_string()))?
+        .len();
+    let mut entry = parse_entry(config, &trash_dir, trash_path, size)
+        .ok_or_else(|| TrashError::InvalidPath(trash_path.to_string_lossy().to_string()))?;
+
+    //Files are restored to the storage directory they were deleted from
+    let dst = trash_dir
+        .parent()
+        .expect("Trash directory has no parent")
+        .join(&entry.original_path);
+    if exists_in_any_root(config, &entry.original_path) {
+        return Err(TrashError::RestoreConflict(entry.original_path.clone()));
+    }
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(&src, &dst)?;
+    remove_empty_parents(&src, &trash_dir);
+    tracing::info!(path = ?dst, "Restored file from trash");
+
+    if let Some(index) = index {
+        if let Some(record) = index
+            .find_by_path(Path::new(TRASH_DIR_NAME).join(trash_path))
+            .map_err(TrashError::Index)?
+        {
+            index.mark_restored(record.id).map_err(TrashError::Index)?;
+            entry.image_id = Some(record.id);
+        }
+    }
+
+    Ok(entry)
+}
+
+/// Permanently removes every file which has been in the trash for longer than the purge delay,
+/// along with its index entry. Returns the entries which were removed.
+pub fn purge(config: &Config, index: Option<&Index>) -> Result<Vec<TrashEntry>, TrashError> {
+    let now = Utc::now();
+    let mut purged = Vec::new();
+    for entry in list(config, index)? {
+        if entry.purge_at > now {
+            continue;
+        }
+        let path = entry.trash_dir.join(&entry.trash_path);
+        if let Err(e) = std::fs::remove_file(&path) {
+            tracing::error!(error = %e, path = ?path, "Failed to purge file from trash");
+            continue;
+        }
+        remove_empty_parents(&path, &entry.trash_dir);
+        if let (Some(index), Some(id)) = (index, entry.image_id) {
+            //Any original or earlier versions kept alongside the image go with it
+            let original = index
+                .get(id)
+                .map_err(TrashError::Index)?
+                .and_then(|r| r.original_path);
+            let earlier_versions = index.versions(id).map_err(TrashError::Index)?;
+            if let Some(root) = entry.trash_dir.parent() {
+                if let Some(original) = original {
+                    if let Err(e) = std::fs::remove_file(root.join(&original)) {
+                        tracing::warn!(error = %e, path = original, "Failed to purge original upload");
+                    }
+                }
+                versions::remove_all(root, &earlier_versions);
+            }
+            index.remove(id).map_err(TrashError::Index)?;
+        }
+        tracing::info!(path = ?entry.original_path, "Purged file from trash");
+        purged.push(entry);
+    }
+    Ok(purged)
+}
+
+/// Removes any directories left empty between a removed file and the trash directory
+fn remove_empty_parents(path: &Path, trash_dir: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == trash_dir || std::fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+/// Works out when a file deleted at the given time should be permanently removed
+fn purge_at(config: &Config, deleted_at: DateTime<Utc>) -> DateTime<Utc> {
+    chrono::Duration::from_std(config.trash.purge_after)
+        .ok()
+        .and_then(|delay| deleted_at.checked_add_signed(delay))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// Repeatedly purges expired files from the trash at the configured interval. Never returns.
+pub async fn purge_periodically(config: Config, index: Option<Arc<Index>>) {
+    let period = if config.trash.purge_interval.is_zero() {
+        Duration::from_secs(3600)
+    } else {
+        config.trash.purge_interval
+    };
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let conf = config.clone();
+        let index = index.clone();
+        match tokio::task::spawn_blocking(move || purge(&conf, index.as_deref())).await {
+            Ok(Ok(purged)) => tracing::debug!(count = purged.len(), "Finished purging trash"),
+            Ok(Err(e)) => tracing::error!(error = %e, "Failed to purge trash"),
+            Err(e) => tracing::error!(error = %e, "Trash purging task panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{ImageVersion, NewImage};
+
+    fn config(root: &Path, purge_after: Duration) -> Config {
+        Config {
+            target_dir: Some(root.to_string_lossy().to_string()),
+            trash: crate::conf::TrashConfig {
+                purge_after,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn write(root: &Path, rel_path: &str) {
+        let path = root.join(rel_path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, rel_path).unwrap();
+    }
+
+    fn index_image(index: &Index, rel_path: &str, original_path: Option<&str>) -> i64 {
+        index
+            .insert(&NewImage {
+                path: PathBuf::from(rel_path),
+                original_filename: "upload.png".to_owned(),
+                mime_type: "image/png".to_owned(),
+                dimensions: None,
+                size: rel_path.len() as u64,
+                sha256: String::new(),
+                uploader: None,
+                client_name: None,
+                client_ip: None,
+                subdirectory: None,
+                created_at: Utc::now(),
+                deletion_token: None,
+                original_path: original_path.map(PathBuf::from),
+                trimmed: None,
+                perceptual_hash: None,
+                animation: None,
+                placeholder: None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn moves_files_under_their_deletion_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), Duration::ZERO);
+        write(dir.path(), "2024/cat.png");
+
+        let entry = move_to_trash(&config, None, "2024/cat.png").unwrap();
+        let millis = entry.deleted_at.timestamp_millis();
+        assert_eq!(entry.trash_path, format!("{}/2024/cat.png", millis));
+        assert_eq!(entry.original_path, "2024/cat.png");
+        assert_eq!(entry.size, "2024/cat.png".len() as u64);
+        assert!(!dir.path().join("2024/cat.png").exists());
+        assert!(dir
+            .path()
+            .join(TRASH_DIR_NAME)
+            .join(&entry.trash_path)
+            .is_file());
+
+        let listed = list(&config, None).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].trash_path, entry.trash_path);
+        assert_eq!(listed[0].original_path, entry.original_path);
+        assert_eq!(listed[0].deleted_at.timestamp_millis(), millis);
+    }
+
+    #[test]
+    fn restores_files_and_index_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), Duration::ZERO);
+        let index = Index::open(dir.path().join("index.sqlite3")).unwrap();
+        write(dir.path(), "2024/cat.png");
+        let id = index_image(&index, "2024/cat.png", None);
+
+        let deleted = move_to_trash(&config, Some(&index), "2024/cat.png").unwrap();
+        assert_eq!(deleted.image_id, Some(id));
+        let record = index.get(id).unwrap().unwrap();
+        assert_eq!(
+            record.path,
+            format!("{}/{}", TRASH_DIR_NAME, deleted.trash_path)
+        );
+        assert!(record.deleted_at.is_some());
+        assert!(index.list().unwrap().is_empty());
+
+        let restored = restore(&config, Some(&index), &deleted.trash_path).unwrap();
+        assert_eq!(restored.image_id, Some(id));
+        assert!(dir.path().join("2024/cat.png").is_file());
+        let record = index.get(id).unwrap().unwrap();
+        assert_eq!(record.path, "2024/cat.png");
+        assert!(record.deleted_at.is_none());
+        //Directories created to hold the deleted file are removed with it
+        let trash_dir = dir.path().join(TRASH_DIR_NAME);
+        assert_eq!(std::fs::read_dir(trash_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn refuses_to_restore_over_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), Duration::ZERO);
+        write(dir.path(), "cat.png");
+        let deleted = move_to_trash(&config, None, "cat.png").unwrap();
+        write(dir.path(), "cat.png");
+
+        assert!(matches!(
+            restore(&config, None, &deleted.trash_path),
+            Err(TrashError::RestoreConflict(path)) if path == "cat.png"
+        ));
+        assert!(dir
+            .path()
+            .join(TRASH_DIR_NAME)
+            .join(&deleted.trash_path)
+            .is_file());
+        assert!(matches!(
+            restore(&config, None, "1/dog.png"),
+            Err(TrashError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_paths_escaping_the_storage_directory() {
+        for path in [
+            "",
+            "../cat.png",
+            "2024/../../cat.png",
+            "/etc/passwd",
+            "./cat.png",
+        ] {
+            assert!(
+                matches!(
+                    check_relative(Path::new(path)),
+                    Err(TrashError::InvalidPath(_))
+                ),
+                "{}",
+                path
+            );
+        }
+        check_relative(Path::new("2024/cat.png")).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), Duration::ZERO);
+        assert!(matches!(
+            move_to_trash(&config, None, "../cat.png"),
+            Err(TrashError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            restore(&config, None, "1/../../cat.png"),
+            Err(TrashError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn purges_expired_files_with_their_versions_and_originals() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), Duration::ZERO);
+        let index = Index::open(dir.path().join("index.sqlite3")).unwrap();
+        write(dir.path(), "cat.png");
+        write(dir.path(), ".originals/cat.png");
+        write(dir.path(), ".versions/cat.1.png");
+        let id = index_image(&index, "cat.png", Some(".originals/cat.png"));
+        index
+            .add_version(&ImageVersion {
+                image_id: id,
+                version: 1,
+                path: ".versions/cat.1.png".to_owned(),
+                mime_type: "image/png".to_owned(),
+                size: 1,
+                sha256: String::new(),
+                operation: "rotate".to_owned(),
+                created_at: Utc::now(),
+            })
+            .unwrap();
+        let deleted = move_to_trash(&config, Some(&index), "cat.png").unwrap();
+
+        let purged = purge(&config, Some(&index)).unwrap();
+        assert_eq!(purged.len(), 1);
+        assert_eq!(purged[0].trash_path, deleted.trash_path);
+        for path in [".originals/cat.png", ".versions/cat.1.png"] {
+            assert!(!dir.path().join(path).exists(), "{}", path);
+        }
+        assert!(list(&config, Some(&index)).unwrap().is_empty());
+        assert!(index.get(id).unwrap().is_none());
+        assert!(index.versions(id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_files_until_the_purge_delay_has_passed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), Duration::from_secs(3600));
+        write(dir.path(), "cat.png");
+        let deleted = move_to_trash(&config, None, "cat.png").unwrap();
+        assert_eq!(
+            deleted.purge_at - deleted.deleted_at,
+            chrono::Duration::hours(1)
+        );
+
+        assert!(purge(&config, None).unwrap().is_empty());
+        assert_eq!(list(&config, None).unwrap().len(), 1);
+    }
+}
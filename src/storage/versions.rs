@@ -0,0 +1,207 @@
+//! Editing stored images while keeping their earlier versions. The stored file is replaced in
+//! place so its links keep working, and the file it replaces is moved into a hidden directory
+//! within the same storage directory, laid out as `<image path>/<version>.<extension>`.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use chrono::Utc;
+use image::DynamicImage;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::find_root;
+use crate::{
+    conf::{Config, StorageFormat},
+    imaging::{self, animation, phash::PerceptualHash, placeholder::Placeholder},
+    index::{FileDetails, ImageRecord, ImageVersion, Index},
+};
+
+/// Name of the hidden directory within each storage directory that earlier versions are kept in
+pub static VERSIONS_DIR_NAME: &str = ".versions";
+/// Quality edited images are re-encoded at if they are stored in a lossy format
+const EDIT_QUALITY: u8 = 90;
+
+/// Errors which can occur when editing a stored image
+#[derive(Debug, Error)]
+#[allow(missing_docs)]
+pub enum VersionError {
+    #[error("No stored file found at {0}")]
+    NotFound(String),
+    #[error("Images of type {0} can't be edited")]
+    UnsupportedFormat(String),
+    #[error("Animated images can't be edited: {0}")]
+    Animated(String),
+    #[error("Invalid edit: {0}")]
+    InvalidEdit(String),
+    #[error("Failed to decode or encode image: {0}")]
+    Image(anyhow::Error),
+    #[error("Failed to replace file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to update index: {0}")]
+    Index(anyhow::Error),
+}
+
+/// The outcome of editing an image
+#[derive(Debug)]
+pub struct Edited {
+    /// Index entry of the image, updated to describe the edited file
+    pub record: ImageRecord,
+    /// The version which was replaced by the edit
+    pub replaced: ImageVersion,
+    /// Storage directory holding the image
+    pub root: PathBuf,
+    /// The edited image
+    pub image: DynamicImage,
+}
+
+/// Locks taken while an image is being edited, so concurrent edits to the same image are applied
+/// one after another rather than racing for the same version number
+#[derive(Debug, Default)]
+pub struct EditLocks(Mutex<HashMap<i64, Arc<Mutex<()>>>>);
+
+impl EditLocks {
+    /// Returns the lock for an image, dropping the locks of images no longer being edited
+    pub(crate) fn for_image(&self, image_id: i64) -> Arc<Mutex<()>> {
+        let mut locks = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(image_id).or_default().clone()
+    }
+}
+
+/// Returns the path an earlier version of an image is kept at, relative to its storage directory
+fn version_path(image_path: &Path, version: u32, extension: &str) -> PathBuf {
+    Path::new(VERSIONS_DIR_NAME)
+        .join(image_path)
+        .join(format!("{}.{}", version, extension))
+}
+
+/// Applies an edit to an indexed image, keeping the file it replaces as a new version. `operation`
+/// names the edit in the list of versions. Edits to the same image are applied one at a time.
+/// Animated images are rejected, since only their first frame could be edited.
+pub fn apply_edit(
+    config: &Config,
+    index: &Index,
+    locks: &EditLocks,
+    record: &ImageRecord,
+    operation: &str,
+    edit: impl FnOnce(&mut DynamicImage) -> Result<(), VersionError>,
+) -> Result<Edited, VersionError> {
+    let root = find_root(config, &record.path)
+        .ok_or_else(|| VersionError::NotFound(record.path.clone()))?;
+    let path = root.join(&record.path);
+    let format = StorageFormat::from_mime_type(&record.mime_type)
+        .ok_or_else(|| VersionError::UnsupportedFormat(record.mime_type.clone()))?;
+
+    let lock = locks.for_image(record.id);
+    let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    let previous = std::fs::read(&path)?;
+    if record.animation.is_some() || matches!(animation::inspect(&previous), Ok(Some(_))) {
+        return Err(VersionError::Animated(record.path.clone()));
+    }
+
+    let (mut img, profile) = imaging::decode_managed(&path, config).map_err(VersionError::Image)?;
+    edit(&mut img)?;
+    let data = format
+        .encode(&img, EDIT_QUALITY)
+        .map_err(VersionError::Image)?
+        .ok_or_else(|| VersionError::UnsupportedFormat(record.mime_type.clone()))?;
+    let data = imaging::icc::attach(data, profile.as_deref());
+
+    //Write the edited file alongside the current one so it can be swapped in atomically
+    let dir = path
+        .parent()
+        .ok_or_else(|| VersionError::NotFound(record.path.clone()))?;
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    std::io::Write::write_all(&mut temp_file, &data)?;
+
+    //Move the current file out of the way, putting it back if the edited one can't replace it
+    let version = index.next_version(record.id).map_err(VersionError::Index)?;
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let kept_path = version_path(Path::new(&record.path), version, &extension);
+    let kept = root.join(&kept_path);
+    if let Some(parent) = kept.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(&path, &kept)?;
+    if let Err(e) = temp_file.persist(&path) {
+        std::fs::rename(&kept, &path)?;
+        return Err(e.error.into());
+    }
+
+    let replaced = ImageVersion {
+        image_id: record.id,
+        version,
+        path: kept_path.to_string_lossy().to_string(),
+        mime_type: record.mime_type.clone(),
+        size: previous.len() as u64,
+        sha256: hex::encode(Sha256::digest(&previous)),
+        operation: operation.to_owned(),
+        created_at: Utc::now(),
+    };
+    if let Err(e) = index.add_version(&replaced) {
+        std::fs::rename(&kept, &path)?;
+        return Err(VersionError::Index(e));
+    }
+    index
+        .refresh_file(
+            record.id,
+            &FileDetails {
+                mime_type: record.mime_type.clone(),
+                dimensions: Some((img.width(), img.height())),
+                size: data.len() as u64,
+                sha256: hex::encode(Sha256::digest(&data)),
+                perceptual_hash: Some(PerceptualHash::of(&img)),
+                //Animated images are never edited
+                animation: None,
+                placeholder: Placeholder::of(&img),
+            },
+        )
+        .map_err(VersionError::Index)?;
+    let record = index
+        .get(record.id)
+        .map_err(VersionError::Index)?
+        .ok_or_else(|| VersionError::NotFound(record.path.clone()))?;
+    tracing::info!(path = record.path, version, operation, "Edited image");
+
+    Ok(Edited {
+        record,
+        replaced,
+        root,
+        image: img,
+    })
+}
+
+/// Returns the location of an earlier version of an image
+pub fn find_version(
+    config: &Config,
+    index: &Index,
+    record: &ImageRecord,
+    version: u32,
+) -> Result<PathBuf, VersionError> {
+    let kept = index
+        .versions(record.id)
+        .map_err(VersionError::Index)?
+        .into_iter()
+        .find(|kept| kept.version == version)
+        .ok_or_else(|| VersionError::NotFound(format!("{} version {}", record.path, version)))?;
+    find_root(config, &kept.path)
+        .map(|root| root.join(&kept.path))
+        .ok_or(VersionError::NotFound(kept.path))
+}
+
+/// Deletes every earlier version kept of an image from a storage directory
+pub fn remove_all(root: &Path, versions: &[ImageVersion]) {
+    for version in versions {
+        let path = root.join(&version.path);
+        if let Err(e) = std::fs::remove_file(&path) {
+            tracing::warn!(error = %e, path = ?path, "Failed to remove earlier version");
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Handlers for the JSON API used to manage stored images, along with deletion links.
+
+use std::{path::Path, sync::Arc};
+
+use actix_web::{
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Data, Json},
+    HttpRequest, HttpResponse,
+};
+use serde_derive::{Deserialize, Serialize};
+use tokio_util::io::ReaderStream;
+use tracing::instrument;
+
+use super::{
+    edit::{authorize_owner, may_manage},
+    handler_err::HandlerError,
+    public_link, quota, OpenHandles,
+};
+use crate::{
+    conf::Config,
+    imaging::{phash, placeholder::Placeholder},
+    index::{dedupe, ImageFilter, ImageRecord, Index},
+    storage::{
+        self, archive,
+        trash::{self, TrashEntry},
+    },
+};
+
+/// Returns a handle to the index, or an error if indexing is disabled
+pub(crate) fn index_handle(handles: &OpenHandles) -> Result<Arc<Index>, HandlerError> {
+    handles.index.clone().ok_or(HandlerError::IndexDisabled())
+}
+
+/// Looks up an image which has not been deleted by its ID
+pub(crate) fn find_image(index: &Index, id: i64) -> Result<ImageRecord, HandlerError> {
+    index
+        .get(id)?
+        .filter(|record| record.deleted_at.is_none())
+        .ok_or_else(|| HandlerError::ImageDoesNotExist(id.to_string()))
+}
+
+/// Moves an indexed image into the trash
+fn trash_image(
+    config: &Config,
+    index: &Index,
+    record: &ImageRecord,
+) -> Result<TrashEntry, HandlerError> {
+    Ok(trash::move_to_trash(config, Some(index), &record.path)?)
+}
+
+#[instrument(skip(handles, req))]
+/// Handler for DELETE /api/images/<id>, which moves an image owned by the client into the trash
+pub async fn delete_image(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    id: web::Path<i64>,
+) -> Result<Json<TrashEntry>, HandlerError> {
+    let index = index_handle(&handles)?;
+    let id = id.into_inner();
+    let lookup = index.clone();
+    let record = web::block(move || find_image(&lookup, id)).await??;
+    authorize_owner(&req, &config, &record)?;
+    let entry = web::block(move || trash_image(&config, &index, &record)).await??;
+    Ok(Json(entry))
+}
+
+#[instrument(skip(handles))]
+/// Handler for GET /delete/<id>/<token>, which moves an image into the trash if the token matches
+/// the one generated when it was uploaded
+pub async fn delete_with_token(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    path: web::Path<(i64, String)>,
+) -> Result<String, HandlerError> {
+    let index = index_handle(&handles)?;
+    let (id, token) = path.into_inner();
+    let entry = web::block(move || -> Result<TrashEntry, HandlerError> {
+        let record = find_image(&index, id)?;
+        if record.deletion_token.as_deref() != Some(token.as_str()) {
+            return Err(HandlerError::InvalidDeletionToken());
+        }
+        trash_image(&config, &index, &record)
+    })
+    .await??;
+    Ok(format!("Deleted {}", entry.original_path))
+}
+
+#[instrument(skip(handles, req))]
+/// Handler for GET /api/trash, which lists the deleted files owned by the client which have not
+/// yet been purged
+pub async fn list_trash(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<Json<Vec<TrashEntry>>, HandlerError> {
+    let user = quota::require_user(&req, &config)?.cloned();
+    let index = index_handle(&handles)?;
+    let entries = web::block(move || -> Result<Vec<TrashEntry>, HandlerError> {
+        let mut entries = Vec::new();
+        for entry in trash::list(&config, Some(&index))? {
+            let uploader = match entry.image_id {
+                Some(id) => index.get(id)?.and_then(|record| record.uploader),
+                None => None,
+            };
+            if may_manage(&config, user.as_ref(), uploader.as_deref()) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    })
+    .await??;
+    Ok(Json(entries))
+}
+
+#[instrument(skip(handles, req))]
+/// Handler for POST /api/trash/restore/<trash_path>, which moves a deleted file owned by the
+/// client back to its original location
+pub async fn restore_from_trash(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    trash_path: web::Path<String>,
+) -> Result<Json<TrashEntry>, HandlerError> {
+    let user = quota::require_user(&req, &config)?.cloned();
+    let index = index_handle(&handles)?;
+    let trash_path = trash_path.into_inner();
+    let entry = web::block(move || -> Result<TrashEntry, HandlerError> {
+        let uploader = index
+            .find_by_path(Path::new(trash::TRASH_DIR_NAME).join(&trash_path))?
+            .and_then(|record| record.uploader);
+        if !may_manage(&config, user.as_ref(), uploader.as_deref()) {
+            return Err(HandlerError::NotOwner());
+        }
+        Ok(trash::restore(&config, Some(&index), trash_path)?)
+    })
+    .await??;
+    Ok(Json(entry))
+}
+
+/// Query parameters accepted by [`similar_images`]
+#[derive(Debug, Deserialize)]
+pub struct SimilarQuery {
+    /// Largest number of bits which may differ between perceptual hashes, from 0 to 64
+    max_distance: Option<u32>,
+}
+
+/// An image which looks like the one asked about, as listed by [`similar_images`]
+#[derive(Debug, Serialize)]
+pub struct SimilarImageResponse {
+    /// ID of the image in the index
+    pub id: i64,
+    /// Path of the stored file, relative to its storage directory
+    pub path: String,
+    /// Link to the image on the imagehost, if enabled
+    pub url: Option<String>,
+    /// Number of bits which differ between the perceptual hashes of the two images
+    pub distance: u32,
+    /// Placeholder to show while the image loads
+    pub placeholder: Option<Placeholder>,
+}
+
+#[instrument(skip(handles, req))]
+/// Handler for GET /api/images/<id>/similar, which lists images that look like one owned by the
+/// client, closest first. Clients without admin rights only see their own uploads.
+pub async fn similar_images(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    id: web::Path<i64>,
+    query: web::Query<SimilarQuery>,
+) -> Result<Json<Vec<SimilarImageResponse>>, HandlerError> {
+    let user = quota::require_user(&req, &config)?.cloned();
+    let max_distance = query.max_distance.unwrap_or(phash::DEFAULT_MAX_DISTANCE);
+    if max_distance > 64 {
+        return Err(HandlerError::InvalidQuery(
+            "max_distance must be at most 64".to_owned(),
+        ));
+    }
+    let index = index_handle(&handles)?;
+    let id = id.into_inner();
+    let similar = web::block(move || -> Result<Vec<SimilarImageResponse>, HandlerError> {
+        let record = find_image(&index, id)?;
+        if !may_manage(&config, user.as_ref(), record.uploader.as_deref()) {
+            return Err(HandlerError::NotOwner());
+        }
+        let hash = record
+            .perceptual_hash
+            .ok_or(HandlerError::NoPerceptualHash(id))?;
+        Ok(dedupe::similar_to(&index, &record, hash, max_distance)?
+            .into_iter()
+            .filter(|similar| may_manage(&config, user.as_ref(), similar.image.uploader.as_deref()))
+            .map(|similar| SimilarImageResponse {
+                id: similar.image.id,
+                url: config
+                    .enable_imagehost
+                    .then(|| public_link(&config, "img", &similar.image.path)),
+                path: similar.image.path,
+                distance: similar.distance,
+                placeholder: similar.image.placeholder,
+            })
+            .collect())
+    })
+    .await??;
+    Ok(Json(similar))
+}
+
+/// Query parameters selecting the images to include in an archive
+#[derive(Debug, Deserialize)]
+pub struct ArchiveQuery {
+    /// Only include images stored within this subdirectory
+    subdir: Option<String>,
+    /// Only include images uploaded on or after this date or time
+    from: Option<String>,
+    /// Only include images uploaded on or before this date, or before this time
+    to: Option<String>,
+    /// Comma separated list of image IDs to include
+    ids: Option<String>,
+}
+
+/// Size of the buffer between the task writing an archive and the response body
+const ARCHIVE_BUF_LEN: usize = 64 * 1024;
+
+#[instrument(skip(handles, req))]
+/// Handler for GET /api/archive, which streams a ZIP archive of the selected images. Clients
+/// without admin rights only receive their own uploads.
+pub async fn download_archive(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    query: web::Query<ArchiveQuery>,
+) -> Result<HttpResponse, HandlerError> {
+    let user = quota::require_user(&req, &config)?.cloned();
+    let index = index_handle(&handles)?;
+    let roots = storage::roots(&config);
+    if roots.is_empty() {
+        return Err(HandlerError::ImageHostingDisabled());
+    }
+    let query = query.into_inner();
+    let filter = ImageFilter::parse(
+        query.subdir,
+        query.from.as_deref(),
+        query.to.as_deref(),
+        query.ids.as_deref(),
+    )
+    .map_err(|e| HandlerError::InvalidQuery(e.to_string()))?;
+    let records = web::block(move || -> Result<Vec<ImageRecord>, HandlerError> {
+        let mut records = index.select(&filter)?;
+        records.retain(|record| may_manage(&config, user.as_ref(), record.uploader.as_deref()));
+        Ok(records)
+    })
+    .await??;
+
+    //Write archive in the background, streaming it out as the response body as it is produced
+    let (writer, reader) = tokio::io::duplex(ARCHIVE_BUF_LEN);
+    actix_web::rt::spawn(async move {
+        match archive::write_archive(&roots, &records, writer).await {
+            Ok(count) => tracing::info!(count, "Finished streaming archive"),
+            Err(e) => tracing::error!(error = %e, "Failed to stream archive"),
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(ARCHIVE_FILE_NAME.to_owned())],
+        })
+        .streaming(ReaderStream::new(reader)))
+}
+
+static ARCHIVE_FILE_NAME: &str = "yoinkx-archive.zip";
@@ -1,6 +1,10 @@
 //! Helpers and types for checking the type and extension of a file
 
-use std::{path::PathBuf, task::Poll};
+use std::{
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    task::Poll,
+};
 
 use actix_multipart::Field;
 use derive_more::Display;
@@ -17,18 +21,21 @@ use super::handler_err::HandlerError;
 pub struct CheckedFileStream {
     inference_buf: Vec<u8>,
     buf_has_been_consumed: bool,
+    field_exhausted: bool,
     pub file_type: FileType,
     pub base_file_name: String,
     field: Field,
 }
 
-const INFERENCE_BUF_LEN: usize = 8192;
+/// Number of bytes read from the start of a file to infer its type
+pub const INFERENCE_BUF_LEN: usize = 8192;
 
 impl CheckedFileStream {
     /// Extracts metadata from a Field struct into a new CheckedFileStream
     pub async fn from_field(mut field: Field) -> Result<Self, HandlerError> {
         let mut inference_buf: Vec<u8> = Vec::with_capacity(INFERENCE_BUF_LEN);
         let buf_has_been_consumed: bool = false;
+        let mut field_exhausted: bool = false;
         let base_file_name = field
             .content_disposition()
             .get_filename()
@@ -51,6 +58,7 @@ impl CheckedFileStream {
                     file_len = bytes_copied,
                     "File was shorter than target inference len."
                 );
+                field_exhausted = true;
                 break;
             }
         }
@@ -58,7 +66,7 @@ impl CheckedFileStream {
         //Infer file type
         let file_type: FileType;
         let mimed: FileType = field.content_type().into();
-        let magic: FileType = infer::get(&inference_buf).into();
+        let magic = FileType::from_magic(&inference_buf);
 
         if mimed != magic {
             if magic.category == FileCategory::Unknown && mimed.category != FileCategory::Unknown {
@@ -85,12 +93,23 @@ impl CheckedFileStream {
         Ok(Self {
             inference_buf,
             buf_has_been_consumed,
+            field_exhausted,
             file_type,
             base_file_name,
             field,
         })
     }
 
+    /// Reads the dimensions declared in the image header, if the header fits within the inference
+    /// buffer and is of a format which can be decoded
+    pub fn header_dimensions(&self) -> Option<(u32, u32)> {
+        image::io::Reader::new(Cursor::new(&self.inference_buf))
+            .with_guessed_format()
+            .ok()?
+            .into_dimensions()
+            .ok()
+    }
+
     pub fn get_filename_with_extension(&self) -> PathBuf {
         let mut name = PathBuf::from(self.base_file_name.clone());
         if name.extension().is_none() {
@@ -113,6 +132,9 @@ impl Stream for CheckedFileStream {
             Poll::Ready(Some(Ok(bytes::Bytes::from(
                 self.as_ref().inference_buf.clone(),
             ))))
+        } else if self.field_exhausted {
+            //Polling a field again after it has finished causes a panic, so stop here
+            Poll::Ready(None)
         } else {
             match self.as_mut().field.try_poll_next_unpin(cx) {
                 Poll::Ready(Some(res)) => {
@@ -121,7 +143,10 @@ impl Stream for CheckedFileStream {
                         cause: err.to_string(),
                     })))
                 }
-                Poll::Ready(None) => Poll::Ready(None),
+                Poll::Ready(None) => {
+                    self.field_exhausted = true;
+                    Poll::Ready(None)
+                }
                 Poll::Pending => Poll::Pending,
             }
         }
@@ -135,6 +160,22 @@ pub struct FileType {
     pub file_extension: String,
 }
 
+impl FileType {
+    /// Infers a file type from the magic numbers at the start of a file
+    pub fn from_magic(buf: &[u8]) -> Self {
+        infer::get(buf).into()
+    }
+
+    /// Infers the type of a file on disk from its first [`INFERENCE_BUF_LEN`] bytes
+    pub fn sniff_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut buf = Vec::with_capacity(INFERENCE_BUF_LEN);
+        std::fs::File::open(path)?
+            .take(INFERENCE_BUF_LEN as u64)
+            .read_to_end(&mut buf)?;
+        Ok(Self::from_magic(&buf))
+    }
+}
+
 impl From<Option<infer::Type>> for FileType {
     fn from(value: Option<infer::Type>) -> Self {
         if let Some(t) = value {
@@ -0,0 +1,249 @@
+//! Handlers for editing stored images through the JSON API. Every edit replaces the stored file
+//! in place and keeps the file it replaced as an earlier version, which only the image's owner
+//! can retrieve.
+
+use actix_files::NamedFile;
+use actix_web::{
+    web::{self, Data, Json},
+    HttpRequest,
+};
+use image::DynamicImage;
+use serde_derive::{Deserialize, Serialize};
+use tracing::instrument;
+
+use super::{api, handler_err::HandlerError, image_upload, quota, OpenHandles};
+use crate::{
+    conf::{Config, UserConfig},
+    imaging::{
+        annotate::{self, Annotation},
+        redact::{self, RedactMode, Redaction, Region},
+        Colour,
+    },
+    index::{ImageRecord, ImageVersion},
+    storage::{
+        mirror,
+        versions::{self, VersionError},
+    },
+};
+
+/// Returns true if a user may manage an image with the given uploader. Images belong to the user
+/// whose API key uploaded them, and admins may manage every image. Images without an uploader can
+/// otherwise only be managed when no users are configured.
+pub(crate) fn may_manage(
+    config: &Config,
+    user: Option<&UserConfig>,
+    uploader: Option<&str>,
+) -> bool {
+    match (uploader, user) {
+        (_, Some(user)) if user.admin => true,
+        (Some(owner), Some(user)) => owner == user.name,
+        (Some(_), None) => false,
+        (None, _) => config.users.is_empty(),
+    }
+}
+
+/// Checks the client may edit, delete or restore an image, or retrieve its earlier versions
+pub(crate) fn authorize_owner(
+    req: &HttpRequest,
+    config: &Config,
+    record: &ImageRecord,
+) -> Result<(), HandlerError> {
+    let user = quota::authenticate(req, config)?;
+    if may_manage(config, user, record.uploader.as_deref()) {
+        Ok(())
+    } else {
+        Err(HandlerError::NotOwner())
+    }
+}
+
+/// Looks up an image and checks the client owns it
+async fn find_owned_image(
+    handles: &OpenHandles,
+    config: &Config,
+    req: &HttpRequest,
+    id: i64,
+) -> Result<ImageRecord, HandlerError> {
+    let index = api::index_handle(handles)?;
+    let record = web::block(move || api::find_image(&index, id)).await??;
+    authorize_owner(req, config, &record)?;
+    Ok(record)
+}
+
+/// Response returned after an image has been edited
+#[derive(Debug, Serialize)]
+pub struct EditResponse {
+    /// Index entry of the edited image
+    pub image: ImageRecord,
+    /// The earlier version kept in place of the file which was replaced
+    pub replaced: ImageVersion,
+}
+
+/// Applies an edit to an owned image, then replicates the edited file to any mirrors and places it
+/// onto the clipboard if asked to
+async fn edit_image(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: &HttpRequest,
+    id: i64,
+    operation: &'static str,
+    clipboard: bool,
+    edit: impl FnOnce(&mut DynamicImage) -> Result<(), VersionError> + Send + 'static,
+) -> Result<Json<EditResponse>, HandlerError> {
+    let record = find_owned_image(&handles, &config, req, id).await?;
+    let index = api::index_handle(&handles)?;
+    let conf = config.clone();
+    let locks = handles.clone();
+    let edited = web::block(move || {
+        versions::apply_edit(&conf, &index, &locks.edit_locks, &record, operation, edit)
+    })
+    .await??;
+
+    if !config.mirror.destinations.is_empty() {
+        actix_web::rt::spawn(mirror::replicate(
+            config.mirror.clone(),
+            edited.root.clone(),
+            edited.record.path.clone().into(),
+        ));
+    }
+    if clipboard {
+        image_upload::insert_image_to_clipboard(edited.image, handles).await?;
+    }
+    Ok(Json(EditResponse {
+        image: edited.record,
+        replaced: edited.replaced,
+    }))
+}
+
+/// Body of a request to redact regions of an image
+#[derive(Debug, Deserialize)]
+pub struct RedactRequest {
+    /// Regions to hide
+    pub regions: Vec<Region>,
+    /// How to hide the regions
+    #[serde(default)]
+    pub mode: RedactMode,
+    /// Colour to fill regions with in solid mode, defaulting to black
+    pub colour: Option<Colour>,
+    /// Block size for pixelation or strength of blurring, at most [`redact::MAX_STRENGTH`]
+    pub strength: Option<u32>,
+    /// Place the redacted image onto the clipboard
+    #[serde(default)]
+    pub clipboard: bool,
+}
+
+#[instrument(skip(handles, req))]
+/// Handler for POST /api/images/<id>/redact, which hides regions of an image
+pub async fn redact_image(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    id: web::Path<i64>,
+    body: Json<RedactRequest>,
+) -> Result<Json<EditResponse>, HandlerError> {
+    let body = body.into_inner();
+    if body.regions.is_empty() {
+        return Err(HandlerError::InvalidQuery(
+            "At least one region must be given".to_owned(),
+        ));
+    }
+    let redaction = Redaction {
+        mode: body.mode,
+        colour: body.colour.unwrap_or(Colour(image::Rgba([0, 0, 0, 255]))),
+        strength: body.strength,
+    };
+    redaction.validate().map_err(HandlerError::InvalidQuery)?;
+    let regions = body.regions;
+    edit_image(
+        handles,
+        config,
+        &req,
+        id.into_inner(),
+        "redact",
+        body.clipboard,
+        move |img| {
+            if regions
+                .iter()
+                .all(|region| region.clamp(img.width(), img.height()).is_none())
+            {
+                return Err(VersionError::InvalidEdit(
+                    "No region overlaps the image".to_owned(),
+                ));
+            }
+            redact::redact(img, &regions, redaction);
+            Ok(())
+        },
+    )
+    .await
+}
+
+/// Body of a request to draw annotations onto an image
+#[derive(Debug, Deserialize)]
+pub struct AnnotateRequest {
+    /// Shapes to draw, in order
+    pub shapes: Vec<Annotation>,
+    /// Place the annotated image onto the clipboard
+    #[serde(default)]
+    pub clipboard: bool,
+}
+
+#[instrument(skip(handles, req, body))]
+/// Handler for POST /api/images/<id>/annotate, which draws shapes and labels onto an image
+pub async fn annotate_image(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    id: web::Path<i64>,
+    body: Json<AnnotateRequest>,
+) -> Result<Json<EditResponse>, HandlerError> {
+    let body = body.into_inner();
+    if body.shapes.is_empty() {
+        return Err(HandlerError::InvalidQuery(
+            "At least one shape must be given".to_owned(),
+        ));
+    }
+    let shapes = body.shapes;
+    edit_image(
+        handles,
+        config,
+        &req,
+        id.into_inner(),
+        "annotate",
+        body.clipboard,
+        move |img| {
+            annotate::annotate(img, &shapes);
+            Ok(())
+        },
+    )
+    .await
+}
+
+#[instrument(skip(handles, req))]
+/// Handler for GET /api/images/<id>/versions, which lists the earlier versions kept of an image
+pub async fn list_versions(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    id: web::Path<i64>,
+) -> Result<Json<Vec<ImageVersion>>, HandlerError> {
+    let record = find_owned_image(&handles, &config, &req, id.into_inner()).await?;
+    let index = api::index_handle(&handles)?;
+    let versions = web::block(move || index.versions(record.id)).await??;
+    Ok(Json(versions))
+}
+
+#[instrument(skip(handles, req))]
+/// Handler for GET /api/images/<id>/versions/<version>, which returns an earlier version of an
+/// image. Version 1 is the image as it was uploaded.
+pub async fn get_version(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    path: web::Path<(i64, u32)>,
+) -> Result<NamedFile, HandlerError> {
+    let (id, version) = path.into_inner();
+    let record = find_owned_image(&handles, &config, &req, id).await?;
+    let index = api::index_handle(&handles)?;
+    let path =
+        web::block(move || versions::find_version(&config, &index, &record, version)).await??;
+    NamedFile::open(path).map_err(HandlerError::InvalidPath)
+}
@@ -6,6 +6,7 @@ use anyhow::anyhow;
 use thiserror::Error;
 
 use super::checked_file_stream;
+use crate::storage::{trash::TrashError, versions::VersionError};
 
 #[derive(Debug, Error)]
 #[allow(missing_docs)]
@@ -36,6 +37,30 @@ pub enum HandlerError {
     FileWasNotAnImage(checked_file_stream::FileType),
     #[error("Failed to extract data from multipart form")]
     FieldReadError { field_name: String, cause: String },
+    #[error("Blocking task failed: {0}")]
+    BlockingError(#[from] actix_web::error::BlockingError),
+    #[error("Image index is disabled")]
+    IndexDisabled(),
+    #[error("Deletion token did not match")]
+    InvalidDeletionToken(),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("{0}")]
+    Trash(#[from] TrashError),
+    #[error("API key was not recognised")]
+    InvalidApiKey(),
+    #[error("Upload would exceed storage quota: {0}")]
+    QuotaExceeded(String),
+    #[error("Not enough free disk space to store {0}B")]
+    InsufficientSpace(u64),
+    #[error("Image is too large to decode: {0}")]
+    ImageExceedsLimits(String),
+    #[error("Only the owner of an image can manage it or see its earlier versions")]
+    NotOwner(),
+    #[error("{0}")]
+    Version(#[from] VersionError),
+    #[error("Image {0} has no perceptual hash, reindex to compute one")]
+    NoPerceptualHash(i64),
 }
 
 impl actix_web::error::ResponseError for HandlerError {
@@ -57,6 +82,33 @@ impl actix_web::error::ResponseError for HandlerError {
                 cause: _,
             } => StatusCode::INTERNAL_SERVER_ERROR,
             HandlerError::FailedToWriteImage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            HandlerError::BlockingError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            HandlerError::IndexDisabled() => StatusCode::NOT_FOUND,
+            HandlerError::InvalidDeletionToken() => StatusCode::FORBIDDEN,
+            HandlerError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            HandlerError::Trash(e) => match e {
+                TrashError::NoTargetDir() => StatusCode::NOT_FOUND,
+                TrashError::InvalidPath(_) => StatusCode::FORBIDDEN,
+                TrashError::NotFound(_) => StatusCode::NOT_FOUND,
+                TrashError::RestoreConflict(_) => StatusCode::CONFLICT,
+                TrashError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                TrashError::Index(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            HandlerError::InvalidApiKey() => StatusCode::UNAUTHORIZED,
+            HandlerError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            HandlerError::InsufficientSpace(_) => StatusCode::INSUFFICIENT_STORAGE,
+            HandlerError::ImageExceedsLimits(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            HandlerError::NotOwner() => StatusCode::FORBIDDEN,
+            HandlerError::Version(e) => match e {
+                VersionError::NotFound(_) => StatusCode::NOT_FOUND,
+                VersionError::UnsupportedFormat(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                VersionError::Animated(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                VersionError::InvalidEdit(_) => StatusCode::BAD_REQUEST,
+                VersionError::Image(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                VersionError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                VersionError::Index(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            HandlerError::NoPerceptualHash(_) => StatusCode::UNPROCESSABLE_ENTITY,
         }
     }
 }
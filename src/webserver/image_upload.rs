@@ -1,28 +1,57 @@
 //! Handlers for uploading screenshots from ShareX to the server.
 
 use anyhow::Result;
+use chrono::{Local, Utc};
+use sha2::{Digest, Sha256};
 use std::{
-    io::{BufReader, SeekFrom},
+    io::{Cursor, SeekFrom},
     path::{Path, PathBuf},
 };
+use tempfile::TempPath;
 use tokio::{
-    fs::{File, OpenOptions},
+    fs::File,
     io::{AsyncSeekExt, AsyncWriteExt},
     sync::OnceCell,
 };
 use tracing::{debug, instrument};
 
 use actix_multipart::{form::MultipartForm, MultipartError};
-use actix_web::{web::Data, HttpRequest};
+use actix_web::{
+    http::header,
+    web::{self, Data, Json},
+    Either, HttpRequest,
+};
 use anyhow::anyhow;
-use image::DynamicImage;
+use image::{DynamicImage, GenericImageView, ImageError};
+use serde_derive::Serialize;
 
 use super::{
-    checked_file_stream::{CheckedFileStream, FileCategory},
+    checked_file_stream::{CheckedFileStream, FileCategory, FileType},
     handler_err::HandlerError,
+    public_link,
+    quota::{self, QuotaReservation},
     OpenHandles,
 };
-use crate::conf::Config;
+use crate::{
+    conf::{
+        AnimationClipboard, ColourProfilePolicy, Config, MetadataPolicy, StorageFormat, TrimRule,
+        WatermarkStage,
+    },
+    imaging::{
+        animation::{self, Animation},
+        icc, metadata,
+        phash::PerceptualHash,
+        placeholder::Placeholder,
+        thumbnail, transcode,
+        trim::{self, TrimMargins},
+        watermark, ORIGINALS_DIR_NAME,
+    },
+    index::NewImage,
+    storage::{
+        self, mirror,
+        routing::{Route, RouteInput, Router},
+    },
+};
 
 use futures_util::TryStreamExt as _;
 
@@ -45,8 +74,66 @@ pub struct MaybeTempImageFile {
     /// Open file handle for the file we have saved our image to
     pub f: File,
     /// If uploaded file has been saved as a tempfile, contains nothing,
-    /// otherwise holds the path the file has been saved to
-    pub path: Option<PathBuf>,
+    /// otherwise holds the staged upload which is waiting to be moved to its final location
+    pub staged: Option<StagedUpload>,
+    /// Quota space reserved for the upload, if its uploader has a quota
+    pub quota: Option<QuotaReservation>,
+}
+
+/// An upload which has been written to a staging file within the target directory, but
+/// not yet moved to its final location
+#[derive(Debug)]
+pub struct StagedUpload {
+    /// Storage directory the upload is being staged in, and will be stored in
+    pub root: PathBuf,
+    /// Path of the staging file, which is removed if dropped before being persisted
+    pub temp_path: TempPath,
+    /// File name provided by the client
+    pub original_filename: String,
+    /// Original file name, with an extension added if not already present
+    pub file_name: PathBuf,
+    /// Detected type of the uploaded file
+    pub file_type: FileType,
+    /// Size and hash of the uploaded data
+    pub summary: WriteSummary,
+}
+
+/// An upload which has been moved to its final location within the target directory
+#[derive(Debug)]
+pub struct StoredUpload {
+    /// Path the upload was stored at
+    pub path: PathBuf,
+    /// Storage directory the upload was stored in
+    pub root: PathBuf,
+    /// Path the upload was stored at, relative to its storage directory
+    pub relative_path: PathBuf,
+    /// Path the upload was kept at as it was received, relative to its storage directory, if
+    /// the stored file was altered
+    pub original_path: Option<PathBuf>,
+    /// Subdirectory of the target directory the upload was routed to
+    pub subdirectory: Option<PathBuf>,
+    /// Borders cropped from the upload before it was stored
+    pub trimmed: Option<TrimMargins>,
+    /// Pixels of the stored file if a watermark was overlaid onto it, so anything published from
+    /// the stored file carries the watermark too
+    pub watermarked: Option<DynamicImage>,
+    /// Frame count and duration of the upload, if it is animated
+    pub animation: Option<Animation>,
+    /// File name provided by the client
+    pub original_filename: String,
+    /// Detected type of the uploaded file
+    pub file_type: FileType,
+    /// Size and hash of the uploaded data
+    pub summary: WriteSummary,
+}
+
+/// Size and hash of the data written by [`write_to_file`]
+#[derive(Debug)]
+pub struct WriteSummary {
+    /// Number of bytes written
+    pub size: u64,
+    /// Hex encoded SHA-256 hash of the written data
+    pub sha256: String,
 }
 
 impl<'t> actix_multipart::form::FieldReader<'t> for MaybeTempImageFile {
@@ -62,6 +149,24 @@ impl<'t> actix_multipart::form::FieldReader<'t> for MaybeTempImageFile {
                 .app_data::<Config>()
                 .or_else(|| req.app_data::<Data<Config>>().map(|d| d.as_ref()));
             let field_name = field.name().to_owned();
+
+            //Reject uploads which are announced as too large for the client before reading any of
+            //them. Limits which depend on the type of file are checked once it is known.
+            let expected_size = expected_upload_size(req);
+            let user = match config_data {
+                Some(config) => {
+                    let user = quota::authenticate(req, config)
+                        .map_err(HandlerError::to_multipart_err(&field_name))?;
+                    let max_size = config.largest_upload_size_limit_for(user);
+                    if expected_size > max_size + MULTIPART_OVERHEAD {
+                        return Err(HandlerError::FileTooLarge(expected_size, max_size))
+                            .map_err(HandlerError::to_multipart_err(&field_name));
+                    }
+                    user
+                }
+                None => None,
+            };
+
             let mut file_stream = CheckedFileStream::from_field(field)
                 .await
                 .map_err(HandlerError::to_multipart_err(&field_name))?;
@@ -74,23 +179,72 @@ impl<'t> actix_multipart::form::FieldReader<'t> for MaybeTempImageFile {
                         .map_err(HandlerError::to_multipart_err(&field_name));
                 }
 
-                //Get file name with extension added if not already present
-                let file_name = file_stream.get_filename_with_extension();
-                if let Some(tgt_file) = choose_filename(config, file_name).await {
-                    //If we have a local save dir configured, choose a filename manually
-                    let f = write_to_path(limits, &mut file_stream, &tgt_file)
+                //Reject images which would be too large to decode before receiving the rest of them
+                if let Some((width, height)) = file_stream.header_dimensions() {
+                    if config.decode_limits.exceeded_by(width, height) {
+                        return Err(HandlerError::ImageExceedsLimits(format!(
+                            "{}x{} is larger than the maximum of {}x{}",
+                            width,
+                            height,
+                            config.decode_limits.max_width,
+                            config.decode_limits.max_height
+                        )))
+                        .map_err(HandlerError::to_multipart_err(&field_name));
+                    }
+                }
+
+                //Reject uploads which are announced as too large for their type before receiving
+                //the rest of them
+                let max_size =
+                    config.upload_size_limit(&file_stream.file_type.category.to_string(), user);
+                if expected_size > max_size + MULTIPART_OVERHEAD {
+                    return Err(HandlerError::FileTooLarge(expected_size, max_size))
+                        .map_err(HandlerError::to_multipart_err(&field_name));
+                }
+
+                //Make sure there is room for the upload before accepting it
+                let staging = choose_staging_dir(config, expected_size)
+                    .await
+                    .map_err(HandlerError::to_multipart_err(&field_name))?;
+                if let Some((root, staging_dir)) = staging {
+                    //Stored files count towards the uploader's quota
+                    let mut quota = reserve_quota(req, config)
                         .await
                         .map_err(HandlerError::to_multipart_err(&field_name))?;
+
+                    //If we have a local save dir configured, stage the file there until we know where it should go
+                    let file_name = file_stream.get_filename_with_extension();
+                    let (f, temp_path, summary) = write_to_staging_file(
+                        limits,
+                        max_size,
+                        &mut file_stream,
+                        staging_dir,
+                        quota.as_mut(),
+                    )
+                    .await
+                    .map_err(HandlerError::to_multipart_err(&field_name))?;
                     Ok(MaybeTempImageFile {
                         f,
-                        path: Some(tgt_file),
+                        staged: Some(StagedUpload {
+                            root,
+                            temp_path,
+                            original_filename: file_stream.base_file_name,
+                            file_name,
+                            file_type: file_stream.file_type,
+                            summary,
+                        }),
+                        quota,
                     })
                 } else {
                     //Otherwise, just create a tempfile
-                    let f = write_to_new_tempfile(limits, &mut file_stream)
+                    let f = write_to_new_tempfile(limits, max_size, &mut file_stream)
                         .await
                         .map_err(HandlerError::to_multipart_err(&field_name))?;
-                    Ok(MaybeTempImageFile { f, path: None })
+                    Ok(MaybeTempImageFile {
+                        f,
+                        staged: None,
+                        quota: None,
+                    })
                 }
             } else {
                 Err(MultipartError::Field {
@@ -108,6 +262,7 @@ impl<'t> actix_multipart::form::FieldReader<'t> for MaybeTempImageFile {
 /// Creates a new temporary file in the default OS tempfile directory, then writes the contents of the file stream to it
 async fn write_to_new_tempfile(
     multipart_limits: &mut actix_multipart::form::Limits,
+    max_size: u64,
     field: &mut CheckedFileStream,
 ) -> Result<File, HandlerError> {
     //Create tempfile
@@ -122,53 +277,95 @@ async fn write_to_new_tempfile(
     .map_err(HandlerError::FailedToWriteImage)?;
 
     //Write data
-    write_to_file(multipart_limits, field, &mut f).await?;
+    write_to_file(multipart_limits, max_size, field, &mut f, None).await?;
     Ok(f)
 }
 
-/// Attempts to create a file at the provided path, then writes the contents of the file stream to it.
-/// If a file already exists at the provided path, it will first be truncated then overwritten
-async fn write_to_path(
+/// Creates a new staging file in the provided directory, then writes the contents of the file stream to it.
+/// Returns the open file handle, the path of the staging file and a summary of the data written.
+/// The staging file is deleted if the upload can't be written in full.
+async fn write_to_staging_file(
     multipart_limits: &mut actix_multipart::form::Limits,
+    max_size: u64,
     field: &mut CheckedFileStream,
-    path: impl AsRef<Path>,
-) -> Result<File, HandlerError> {
-    let mut f: File = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .read(true)
-        .open(&path)
-        .await
-        .map_err(HandlerError::FailedToWriteImage)?;
-    debug!("Writing data to path: {}", path.as_ref().display());
-    write_to_file(multipart_limits, field, &mut f).await?;
+    dir: PathBuf,
+    quota: Option<&mut QuotaReservation>,
+) -> Result<(File, TempPath, WriteSummary), HandlerError> {
+    let (mut f, temp_path) = tokio::task::spawn_blocking(move || -> Result<_, std::io::Error> {
+        let (f, temp_path) = tempfile::NamedTempFile::new_in(dir)?.into_parts();
+        Ok((File::from_std(f), temp_path))
+    })
+    .await
+    .map_err(HandlerError::TokioRuntimeError)?
+    .map_err(HandlerError::FailedToWriteImage)?;
+    debug!("Writing data to staging file: {}", temp_path.display());
+    let summary = write_to_file(multipart_limits, max_size, field, &mut f, quota).await?;
     //Seek back to start of file once written
     f.seek(SeekFrom::Start(0))
         .await
         .map_err(HandlerError::FailedToWriteImage)?;
-    Ok(f)
+    Ok((f, temp_path, summary))
 }
 
 /// Given an async file handle and a file stream, writes the contents of the file stream to the file,
-/// observing file size limits
+/// observing file size limits and the uploader's quota. Returns the number of bytes written along with their hash.
 async fn write_to_file(
     multipart_limits: &mut actix_multipart::form::Limits,
+    max_size: u64,
     field: &mut CheckedFileStream,
     tgt_file: &mut tokio::fs::File,
-) -> Result<(), HandlerError> {
-    let mut written_bytes: usize = 0;
+    mut quota: Option<&mut QuotaReservation>,
+) -> Result<WriteSummary, HandlerError> {
+    let mut written_bytes: u64 = 0;
+    let mut hasher = Sha256::new();
     while let Some(chunk) = field.try_next().await? {
-        multipart_limits.try_consume_limits(chunk.len(), false)?;
+        let received_bytes = written_bytes + chunk.len() as u64;
+        if received_bytes > max_size {
+            return Err(HandlerError::FileTooLarge(received_bytes, max_size));
+        }
+        multipart_limits
+            .try_consume_limits(chunk.len(), false)
+            .map_err(|_| HandlerError::FileTooLarge(received_bytes, max_size))?;
+        if let Some(quota) = quota.as_deref_mut() {
+            quota.reserve_bytes(chunk.len() as u64)?;
+        }
         //Write chunk
-        tgt_file
-            .write_all(&chunk)
-            .await
-            .map_err(HandlerError::FailedToWriteImage)?;
-        written_bytes += chunk.len();
+        tgt_file.write_all(&chunk).await.map_err(|e| {
+            //Report a full disk distinctly, as the upload may succeed once space is freed
+            if e.kind() == std::io::ErrorKind::StorageFull {
+                HandlerError::InsufficientSpace(chunk.len() as u64)
+            } else {
+                HandlerError::FailedToWriteImage(e)
+            }
+        })?;
+        hasher.update(&chunk);
+        written_bytes += chunk.len() as u64;
     }
     debug!("Wrote {} bytes to file system", written_bytes);
-    Ok(())
+    Ok(WriteSummary {
+        size: written_bytes,
+        sha256: hex::encode(hasher.finalize()),
+    })
+}
+
+/// Reserves quota space for an upload if it was sent with the API key of a user who has a quota
+async fn reserve_quota(
+    req: &HttpRequest,
+    config: &Config,
+) -> Result<Option<QuotaReservation>, HandlerError> {
+    let Some(user) = quota::authenticate(req, config)? else {
+        return Ok(None);
+    };
+    if user.quota_bytes.is_none() && user.quota_files.is_none() {
+        return Ok(None);
+    }
+    let handles = req
+        .app_data::<Data<OpenHandles>>()
+        .ok_or_else(|| anyhow!("Failed to retrieve handles in upload handler"))?;
+    let (quotas, index, user) = (handles.quotas.clone(), handles.index.clone(), user.clone());
+    Ok(Some(
+        web::block(move || quotas.begin(&user, index.as_deref())).await??,
+    ))
 }
 
 /// Returns true if the provided file stream is of an allowed type
@@ -177,16 +374,439 @@ async fn check_is_allowed_type(file: &CheckedFileStream, _conf: &Config) -> bool
     file.file_type.category == FileCategory::Image
 }
 
-/// Choose a path a file should be saved to based on its original filename. Returns `None` if
-/// a local file path is not configured or if errors occurred when trying to ensure the directory
-/// exists.
+/// Returns the size of the upload announced by the client, or 0 if it wasn't given
+fn expected_upload_size(req: &HttpRequest) -> u64 {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Chooses the storage directory with room for an upload of the given size, and returns it along
+/// with the hidden directory within it that uploads are staged in before being routed, creating it
+/// if needed. Returns `None` if a local file path is not configured, and an error if no storage
+/// directory has enough free space or the staging directory could not be created.
+async fn choose_staging_dir(
+    config: &Config,
+    needed: u64,
+) -> Result<Option<(PathBuf, PathBuf)>, HandlerError> {
+    if config.target_dir.is_none() {
+        return Ok(None);
+    }
+    let root =
+        storage::choose_root(config, needed).ok_or(HandlerError::InsufficientSpace(needed))?;
+    let staging_dir = root.join(STAGING_DIR_NAME);
+    if let Err(e) = tokio::fs::create_dir_all(&staging_dir).await {
+        tracing::error!(error = %e, directory = ?staging_dir, "Failed to create staging directory");
+        return Err(HandlerError::FailedToWriteImage(e));
+    }
+    Ok(Some((root, staging_dir)))
+}
+
+/// Routes a staged upload to its subdirectory, then moves it into place. Animated uploads are
+/// stored without being cropped, watermarked or transcoded, as only their first frame is decoded.
+/// Files re-encoded along the way carry the given colour profile. The decoded image is cropped
+/// along with the stored file, but never watermarked.
+async fn store_upload(
+    config: &Config,
+    mut staged: StagedUpload,
+    img: &mut DynamicImage,
+    animation: Option<Animation>,
+    profile: Option<&[u8]>,
+) -> Result<StoredUpload, HandlerError> {
+    let filename = staged.file_name.to_string_lossy().to_string();
+    let (subdir, trim_rule) = choose_route(
+        config,
+        &RouteInput {
+            file_name: &filename,
+            mime_type: &staged.file_type.mime_type,
+            size: staged.summary.size,
+        },
+    )
+    .await
+    .map(|route| (route.subdirectory, route.trim))
+    .unwrap_or_default();
+    if let Some(animation) = animation {
+        debug!(?animation, "Storing animated upload as received");
+    }
+    let trim_rule = trim_rule.filter(|_| animation.is_none());
+
+    let mut originals = Originals::default();
+
+    //Crop uniform borders if the upload was routed by a rule which asks for it
+    let trimmed = match trim_staged(&mut staged, trim_rule.as_ref(), img, profile).await? {
+        Some((margins, replaced)) => {
+            originals.note(Some(replaced));
+            Some(margins)
+        }
+        None => None,
+    };
+
+    //Overlay a watermark onto the stored file if a rule asks for it when storing, then convert the
+    //upload to the configured storage format
+    let mut watermarked = None;
+    if animation.is_none() {
+        if let Some((marked, replaced)) =
+            watermark_staged(config, &mut staged, subdir.as_deref(), img, profile).await?
+        {
+            originals.note(Some(replaced));
+            watermarked = Some(marked);
+        }
+        let stored_img = watermarked.as_ref().unwrap_or(img);
+        let replaced =
+            transcode_staged(config, &mut staged, subdir.as_deref(), stored_img, profile).await?;
+        originals.note(replaced);
+    }
+    let original = originals.into_kept();
+
+    //Remove any metadata which shouldn't be published before the file can be served
+    strip_staged_metadata(config, &mut staged).await?;
+
+    let tgt_file = choose_filename(config, &staged.root, &staged.file_name, subdir.clone())
+        .await
+        .ok_or_else(|| {
+            HandlerError::InternalError(anyhow!("Failed to choose a location to store the image"))
+        })?;
+    staged
+        .temp_path
+        .persist_noclobber(&tgt_file)
+        .map_err(|e| HandlerError::FailedToWriteImage(e.error))?;
+    debug!("Stored upload at path: {}", tgt_file.display());
+    let relative_path = tgt_file
+        .strip_prefix(&staged.root)
+        .unwrap_or(&tgt_file)
+        .to_owned();
+    let original_path = match original {
+        Some((temp_path, extension)) => {
+            keep_original(&staged.root, &relative_path, temp_path, &extension).await
+        }
+        None => None,
+    };
+    Ok(StoredUpload {
+        path: tgt_file,
+        root: staged.root,
+        relative_path,
+        original_path,
+        subdirectory: subdir,
+        trimmed,
+        watermarked,
+        animation,
+        original_filename: staged.original_filename,
+        file_type: staged.file_type,
+        summary: staged.summary,
+    })
+}
+
+/// A staged file which a processing step replaced with its re-encoded output
+struct Replaced {
+    /// Path of the replaced file, which is removed when dropped
+    temp_path: TempPath,
+    /// File extension of the replaced file
+    extension: String,
+    /// Whether the rule which ran the step asks for the upload as received to be kept
+    keep_original: bool,
+}
+
+impl Replaced {
+    /// Records whether the upload as received should be kept
+    fn keeping(self, keep_original: bool) -> Self {
+        Self {
+            keep_original,
+            ..self
+        }
+    }
+}
+
+/// Collects the staged files replaced while an upload is processed. The first is the upload as
+/// received, which is kept if any step asks for it, while later ones are intermediate results.
+#[derive(Default)]
+struct Originals {
+    received: Option<(TempPath, String)>,
+    keep: bool,
+}
+
+impl Originals {
+    /// Records a file replaced by a processing step, if it ran
+    fn note(&mut self, replaced: Option<Replaced>) {
+        if let Some(replaced) = replaced {
+            self.keep |= replaced.keep_original;
+            if self.received.is_none() {
+                self.received = Some((replaced.temp_path, replaced.extension));
+            }
+        }
+    }
+
+    /// Returns the path and file extension of the upload as received, if any step asks for it
+    fn into_kept(self) -> Option<(TempPath, String)> {
+        self.received.filter(|_| self.keep)
+    }
+}
+
+/// Writes processed image data next to the staged upload within a storage directory, so it can be
+/// moved into place the same way
+async fn write_staged(
+    root: &Path,
+    data: Vec<u8>,
+) -> Result<(TempPath, WriteSummary), HandlerError> {
+    let staging_dir = root.join(STAGING_DIR_NAME);
+    let summary = WriteSummary {
+        size: data.len() as u64,
+        sha256: hex::encode(Sha256::digest(&data)),
+    };
+    let temp_path = web::block(move || -> std::io::Result<TempPath> {
+        let mut temp_file = tempfile::NamedTempFile::new_in(staging_dir)?;
+        std::io::Write::write_all(&mut temp_file, &data)?;
+        Ok(temp_file.into_temp_path())
+    })
+    .await?
+    .map_err(HandlerError::FailedToWriteImage)?;
+    Ok((temp_path, summary))
+}
+
+/// Crops uniform borders from a staged upload if its routing rule asks for it, replacing the staged
+/// file and the decoded image with cropped versions. Returns the margins which were removed, along
+/// with the staged file which was replaced.
+async fn trim_staged(
+    staged: &mut StagedUpload,
+    rule: Option<&TrimRule>,
+    img: &mut DynamicImage,
+    profile: Option<&[u8]>,
+) -> Result<Option<(TrimMargins, Replaced)>, HandlerError> {
+    let Some(rule) = rule else {
+        return Ok(None);
+    };
+    //Cropped images are re-encoded in the same format where possible
+    let format =
+        StorageFormat::from_mime_type(&staged.file_type.mime_type).unwrap_or(StorageFormat::Png);
+    let Some((mime_type, extension)) = format.mime_and_extension() else {
+        return Ok(None);
+    };
+    let (source, tolerance, quality) = (img.clone(), rule.tolerance, rule.quality);
+    let trimmed = web::block(
+        move || -> Result<Option<(TrimMargins, DynamicImage, Vec<u8>)>> {
+            let Some(margins) = trim::find_margins(&source, tolerance) else {
+                return Ok(None);
+            };
+            let cropped = trim::apply(&source, margins);
+            let data = format
+                .encode(&cropped, quality)?
+                .ok_or_else(|| anyhow!("Cropped images can't keep the original format"))?;
+            Ok(Some((margins, cropped, data)))
+        },
+    )
+    .await??;
+    let Some((margins, cropped, data)) = trimmed else {
+        return Ok(None);
+    };
+
+    let (temp_path, summary) = write_staged(&staged.root, icc::attach(data, profile)).await?;
+    debug!(?margins, size = summary.size, "Trimmed borders from upload");
+
+    let replaced = replace_staged(staged, temp_path, summary, mime_type, extension);
+    *img = cropped;
+    Ok(Some((margins, replaced.keeping(rule.keep_original))))
+}
+
+/// Swaps a re-encoded file in place of a staged upload, updating its type if the encoding changed
+/// it. Returns the file which was replaced.
+fn replace_staged(
+    staged: &mut StagedUpload,
+    temp_path: TempPath,
+    summary: WriteSummary,
+    mime_type: &str,
+    extension: &str,
+) -> Replaced {
+    let original_extension = staged.file_type.file_extension.clone();
+    let original = std::mem::replace(&mut staged.temp_path, temp_path);
+    if staged.file_type.mime_type != mime_type {
+        staged.file_name.set_extension(extension);
+        staged.file_type = FileType {
+            category: FileCategory::Image,
+            mime_type: mime_type.to_owned(),
+            file_extension: extension.to_owned(),
+        };
+    }
+    staged.summary = summary;
+    Replaced {
+        temp_path: original,
+        extension: original_extension,
+        keep_original: false,
+    }
+}
+
+/// Overlays a watermark onto a staged upload if a rule applies it when storing, replacing the
+/// staged file with a watermarked version. The decoded image is left as it is, so the clipboard and
+/// index still get the upload as taken. Returns the watermarked image along with the staged file
+/// which was replaced.
+async fn watermark_staged(
+    config: &Config,
+    staged: &mut StagedUpload,
+    subdir: Option<&Path>,
+    img: &DynamicImage,
+    profile: Option<&[u8]>,
+) -> Result<Option<(DynamicImage, Replaced)>, HandlerError> {
+    let Some(rule) = watermark::find_rule(config, WatermarkStage::Store, subdir, false) else {
+        return Ok(None);
+    };
+    //Watermarked images are re-encoded in the same format where possible
+    let format =
+        StorageFormat::from_mime_type(&staged.file_type.mime_type).unwrap_or(StorageFormat::Png);
+    let Some((mime_type, extension)) = format.mime_and_extension() else {
+        return Ok(None);
+    };
+    let keep_original = rule.keep_original;
+    let (rule, limits, mut marked) = (rule.clone(), config.decode_limits.clone(), img.clone());
+    let (marked, data) = web::block(move || -> Result<(DynamicImage, Vec<u8>)> {
+        watermark::apply(&mut marked, &rule, &limits)?;
+        let data = format
+            .encode(&marked, rule.quality)?
+            .ok_or_else(|| anyhow!("Watermarked images can't keep the original format"))?;
+        Ok((marked, data))
+    })
+    .await??;
+
+    let (temp_path, summary) = write_staged(&staged.root, icc::attach(data, profile)).await?;
+    debug!(size = summary.size, "Watermarked upload");
+
+    let replaced = replace_staged(staged, temp_path, summary, mime_type, extension);
+    Ok(Some((marked, replaced.keeping(keep_original))))
+}
+
+/// Re-encodes a staged upload if a transcoding rule applies to it, replacing the staged file with the
+/// converted one. Returns the staged file which was replaced.
+async fn transcode_staged(
+    config: &Config,
+    staged: &mut StagedUpload,
+    subdir: Option<&Path>,
+    img: &DynamicImage,
+    profile: Option<&[u8]>,
+) -> Result<Option<Replaced>, HandlerError> {
+    let Some(rule) = transcode::find_rule(config, &staged.file_type.mime_type, subdir) else {
+        return Ok(None);
+    };
+    let keep_original = rule.keep_original;
+    let (rule, img, mime_type) = (
+        rule.clone(),
+        img.clone(),
+        staged.file_type.mime_type.clone(),
+    );
+    let Some(transcoded) =
+        web::block(move || transcode::transcode(&img, &rule, &mime_type)).await??
+    else {
+        return Ok(None);
+    };
+
+    let (temp_path, summary) =
+        write_staged(&staged.root, icc::attach(transcoded.data, profile)).await?;
+    debug!(
+        from = staged.file_type.mime_type,
+        to = transcoded.mime_type,
+        size = summary.size,
+        "Transcoded upload"
+    );
+
+    let replaced = replace_staged(
+        staged,
+        temp_path,
+        summary,
+        transcoded.mime_type,
+        transcoded.extension,
+    );
+    Ok(Some(replaced.keeping(keep_original)))
+}
+
+/// Removes embedded metadata from a staged upload according to the configured policy, rewriting the
+/// staged file in place. Colour profiles are kept if the server is configured to preserve them.
+/// Files which can't be parsed are stored as they are.
+async fn strip_staged_metadata(
+    config: &Config,
+    staged: &mut StagedUpload,
+) -> Result<(), HandlerError> {
+    if config.metadata_policy == MetadataPolicy::KeepAll {
+        return Ok(());
+    }
+    let (path, mime_type, policy, keep_icc) = (
+        staged.temp_path.to_path_buf(),
+        staged.file_type.mime_type.clone(),
+        config.metadata_policy,
+        config.colour_profiles == ColourProfilePolicy::Preserve,
+    );
+    let stripped = web::block(move || -> std::io::Result<Option<WriteSummary>> {
+        let data = std::fs::read(&path)?;
+        let stripped = match metadata::strip(&data, &mime_type, policy, keep_icc) {
+            Ok(Some(stripped)) => stripped,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to parse upload, storing it with its metadata");
+                return Ok(None);
+            }
+        };
+        std::fs::write(&path, &stripped)?;
+        Ok(Some(WriteSummary {
+            size: stripped.len() as u64,
+            sha256: hex::encode(Sha256::digest(&stripped)),
+        }))
+    })
+    .await?
+    .map_err(HandlerError::FailedToWriteImage)?;
+    if let Some(summary) = stripped {
+        debug!(
+            removed = staged.summary.size.saturating_sub(summary.size),
+            "Stripped metadata from upload"
+        );
+        staged.summary = summary;
+    }
+    Ok(())
+}
+
+/// Moves the original of an altered upload into the hidden originals directory, at the same relative
+/// path as the stored file but with its own extension. Returns its path relative to the storage
+/// directory, or `None` if it couldn't be kept.
+async fn keep_original(
+    root: &Path,
+    relative_path: &Path,
+    temp_path: TempPath,
+    extension: &str,
+) -> Option<PathBuf> {
+    let original_path = Path::new(ORIGINALS_DIR_NAME)
+        .join(relative_path)
+        .with_extension(extension);
+    let dst = root.join(&original_path);
+    let result = web::block(move || -> std::io::Result<()> {
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        temp_path.persist_noclobber(&dst).map_err(|e| e.error)
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => Some(original_path),
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, path = ?original_path, "Failed to keep original upload");
+            None
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Keeping original upload failed");
+            None
+        }
+    }
+}
+
+/// Choose a path within the storage directory `root` a file should be saved to based on its original
+/// filename and the subdirectory it was routed to. Returns `None` if a local file path is not configured
+/// or if errors occurred when trying to ensure the directory exists.
 #[instrument]
-async fn choose_filename(config: &Config, base_filename: PathBuf) -> Option<PathBuf> {
-    if let Some(tgt_dir) = &config.target_dir {
-        let filename = base_filename.to_string_lossy();
-        let mut tgt_dir: PathBuf = PathBuf::from(tgt_dir);
-        //Add subdirectory to path if we get a regex match
-        if let Some(subdir_name) = choose_subdirectory(config, &filename).await {
+async fn choose_filename(
+    config: &Config,
+    root: &Path,
+    base_filename: &Path,
+    subdir: Option<PathBuf>,
+) -> Option<PathBuf> {
+    if config.target_dir.is_some() {
+        let mut tgt_dir: PathBuf = root.to_owned();
+        //Add subdirectory to path if the upload was routed to one
+        if let Some(subdir_name) = &subdir {
             tgt_dir.push(subdir_name);
         }
         //Make sure directory exists, create it if it doesn't
@@ -194,10 +814,12 @@ async fn choose_filename(config: &Config, base_filename: PathBuf) -> Option<Path
             tracing::error!(error = %e, directory = ?tgt_dir, "Failed to create nonexistant directory ");
             return None;
         }
-        //If the file already exists, try appending incrementing suffixes until we find one that doesn't already exist
+        //If the file already exists in any storage directory, try appending incrementing suffixes until we find
+        //one that doesn't already exist
         let mut filename_suffix: u32 = 0;
-        let mut tgt_file = tgt_dir.join(&base_filename);
-        while tgt_file.exists() {
+        let mut tgt_file = tgt_dir.join(base_filename);
+        while storage::exists_in_any_root(config, tgt_file.strip_prefix(root).unwrap_or(&tgt_file))
+        {
             let mut suffixed_filename_stem =
                 base_filename.file_stem().unwrap_or_default().to_os_string();
             suffixed_filename_stem.push(format!("_{}", filename_suffix));
@@ -212,36 +834,39 @@ async fn choose_filename(config: &Config, base_filename: PathBuf) -> Option<Path
     }
 }
 
-static SUBDIR_REGEX: OnceCell<regex::Regex> = OnceCell::const_new();
-async fn subdir_regex(config: &Config) -> Result<&regex::Regex> {
-    SUBDIR_REGEX
-        .get_or_try_init(|| async { regex::Regex::new(&config.subdirectory_regex) })
+static ROUTER: OnceCell<Router> = OnceCell::const_new();
+async fn router(config: &Config) -> Result<&Router> {
+    ROUTER
+        .get_or_try_init(|| async { Router::new(config) })
         .await
-        .map_err(anyhow::Error::from)
 }
 
-/// If configured to do so, attempts to derive the name of a subdirectory the file should be placed in,
-/// based on the filename
-async fn choose_subdirectory(config: &Config, filename: &str) -> Option<String> {
-    let regex = subdir_regex(config)
+/// Works out the subdirectory a file should be placed in by evaluating the configured routing rules,
+/// falling back to the subdirectory layout if none match
+async fn choose_route(config: &Config, input: &RouteInput<'_>) -> Option<Route> {
+    let router = router(config)
         .await
         .map_err(|e| {
-            tracing::error!(%e, "Failed to compile subdirectory calculation regex");
+            tracing::error!(%e, "Failed to compile upload routing rules");
             e
         })
-        .ok();
+        .ok()?;
 
-    regex
-        .and_then(|regex| regex.captures(filename))
-        .and_then(|captures| captures.name(SUBDIR_CAPTURE_NAME))
-        .map(|val| val.as_str().to_owned())
+    let route = router.route(input, Local::now().date_naive());
+    debug!(?route, "Routed upload");
+    Some(route)
 }
 
 // ---------------------------------------------------------- //
 // --------------- Handler and util functions --------------- //
 // ---------------------------------------------------------- //
 
-static SUBDIR_CAPTURE_NAME: &str = "subdir";
+/// Name of the hidden directory within the target directory that uploads are staged in
+static STAGING_DIR_NAME: &str = ".incoming";
+
+/// Allowance for multipart boundaries and headers when comparing the size of a whole request
+/// against the size limit for the file within it
+pub const MULTIPART_OVERHEAD: u64 = 16 * 1024;
 
 #[instrument(skip(handles))]
 /// Handler for image upload functionality.
@@ -250,27 +875,264 @@ pub async fn upload(
     config: Data<Config>,
     req: HttpRequest,
     MultipartForm(form): MultipartForm<ImageUploadForm>,
-) -> Result<String, HandlerError> {
+) -> Result<Either<String, Json<UploadResponse>>, HandlerError> {
     let f = form.img_file;
+    let uploader = quota::authenticate(&req, &config)?.map(|user| user.name.clone());
 
-    //Copy image to clipboard
-    insert_file_to_clipboard(f.f, handles).await?;
+    //Load image so that it can be inspected, converted and placed onto the clipboard
+    let DecodedUpload {
+        mut img,
+        animation,
+        profile,
+    } = load_image_from_file(f.f, &config).await?;
+
+    //Move file to its final location if we are storing it
+    let stored = match f.staged {
+        Some(staged) => {
+            Some(store_upload(&config, staged, &mut img, animation, profile.as_deref()).await?)
+        }
+        None => None,
+    };
+
+    //Summarise the image for galleries and embeds to show while it loads
+    let placeholder = compute_placeholder(&img).await;
+
+    //Record upload in the index
+    let deletion_token = generate_deletion_token();
+    let id = match &stored {
+        Some(stored) => {
+            record_upload(
+                &handles,
+                &req,
+                stored,
+                &img,
+                &deletion_token,
+                uploader,
+                placeholder.clone(),
+            )
+            .await
+        }
+        None => None,
+    };
+
+    //Replicate stored file to any mirrors in the background, and queue it to be optimised
+    if let Some(stored) = &stored {
+        spawn_mirroring(&config, stored);
+        spawn_thumbnailing(&config, stored, &img, profile.clone());
+        if let Some(optimiser) = &handles.optimiser {
+            optimiser.enqueue(stored.root.clone(), stored.relative_path.clone());
+        }
+    }
+
+    let rel_path = stored
+        .as_ref()
+        .map(|stored| stored.relative_path.to_string_lossy().to_string());
+    let url = rel_path
+        .as_ref()
+        .filter(|_| config.enable_imagehost)
+        .map(|rel_path| public_link(&config, "img", rel_path));
+
+    //Copy image to clipboard, or a link to it if it is animated and links are preferred
+    match url
+        .as_ref()
+        .filter(|_| animation.is_some() && config.animation.clipboard == AnimationClipboard::Url)
+    {
+        Some(url) => insert_text_to_clipboard(url.clone(), handles).await?,
+        None => insert_image_to_clipboard(clipboard_colours(img, profile).await, handles).await?,
+    }
+
+    //Return details as JSON if the client asked for it
+    if accepts_json(&req) {
+        return Ok(Either::Right(Json(UploadResponse {
+            id,
+            url,
+            deletion_url: id
+                .map(|id| public_link(&config, "delete", &format!("{}/{}", id, deletion_token))),
+            path: rel_path,
+            animation,
+            placeholder,
+        })));
+    }
 
     //Return file location or some default value
-    match f.path {
-        Some(loc) => Ok(loc.to_string_lossy().to_string()),
-        None => Ok("clipboard only".to_string()),
+    match stored {
+        Some(stored) => Ok(Either::Left(stored.path.to_string_lossy().to_string())),
+        None => Ok(Either::Left("clipboard only".to_string())),
+    }
+}
+
+/// Starts copying a stored file to every configured mirror without waiting for it to finish
+fn spawn_mirroring(config: &Config, stored: &StoredUpload) {
+    if config.mirror.destinations.is_empty() {
+        return;
     }
+    actix_web::rt::spawn(mirror::replicate(
+        config.mirror.clone(),
+        stored.root.clone(),
+        stored.relative_path.clone(),
+    ));
 }
 
-#[instrument(skip(handles, file))]
-/// Given an async handle to an image file, attempts to copy said file to the clipboard
-async fn insert_file_to_clipboard(
-    file: File,
+/// Starts generating thumbnails of a stored file without waiting for them, if they are generated
+/// on upload rather than on first request. Animated files also get a full size poster.
+fn spawn_thumbnailing(
+    config: &Config,
+    stored: &StoredUpload,
+    img: &DynamicImage,
+    profile: Option<Vec<u8>>,
+) {
+    if !(config.thumbnails.enabled && config.thumbnails.on_upload) {
+        return;
+    }
+    let config = config.clone();
+    let rel_path = stored.relative_path.clone();
+    let img = stored.watermarked.as_ref().unwrap_or(img).clone();
+    let animated = stored.animation.is_some();
+    actix_web::rt::task::spawn_blocking(move || {
+        if let Err(e) = thumbnail::write_all(&config, &rel_path, &img, profile.as_deref()) {
+            tracing::warn!(error = %e, path = ?rel_path, "Failed to generate thumbnails");
+        }
+        if animated {
+            if let Err(e) = thumbnail::write_poster(&config, &rel_path, &img, profile.as_deref()) {
+                tracing::warn!(error = %e, path = ?rel_path, "Failed to generate poster");
+            }
+        }
+    });
+}
+
+/// Details of a completed upload, returned to clients which accept JSON
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    /// ID of the upload in the index, if it was stored and indexed
+    pub id: Option<i64>,
+    /// Path the upload was stored at, relative to its storage directory
+    pub path: Option<String>,
+    /// Link to the upload on the imagehost, if enabled
+    pub url: Option<String>,
+    /// Link which deletes the upload when visited
+    pub deletion_url: Option<String>,
+    /// Frame count and duration of the upload, if it is animated
+    pub animation: Option<Animation>,
+    /// Placeholder to show while the upload loads
+    pub placeholder: Option<Placeholder>,
+}
+
+/// Returns true if the client has asked for a JSON response
+fn accepts_json(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(mime::APPLICATION_JSON.essence_str()))
+}
+
+/// Generates a random token which allows an upload to be deleted without further authentication
+fn generate_deletion_token() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Computes the placeholder of an upload on a blocking thread, logging any failure
+async fn compute_placeholder(img: &DynamicImage) -> Option<Placeholder> {
+    let img = img.clone();
+    match web::block(move || Placeholder::of(&img)).await {
+        Ok(placeholder) => placeholder,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to compute placeholder for upload");
+            None
+        }
+    }
+}
+
+/// Adds a stored upload to the metadata index, if enabled. Failures are logged rather than returned,
+/// as the upload itself has already succeeded. Returns the ID of the new index entry.
+async fn record_upload(
+    handles: &OpenHandles,
+    req: &HttpRequest,
+    stored: &StoredUpload,
+    img: &DynamicImage,
+    deletion_token: &str,
+    uploader: Option<String>,
+    placeholder: Option<Placeholder>,
+) -> Option<i64> {
+    let index = handles.index.clone()?;
+    let mut record = NewImage {
+        path: stored.relative_path.clone(),
+        original_filename: stored.original_filename.clone(),
+        mime_type: stored.file_type.mime_type.clone(),
+        dimensions: Some(img.dimensions()),
+        size: stored.summary.size,
+        sha256: stored.summary.sha256.clone(),
+        uploader,
+        client_name: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(str::to_owned),
+        client_ip: req
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_owned),
+        subdirectory: stored
+            .subdirectory
+            .as_ref()
+            .map(|subdir| subdir.to_string_lossy().to_string()),
+        created_at: Utc::now(),
+        deletion_token: Some(deletion_token.to_owned()),
+        original_path: stored.original_path.clone(),
+        trimmed: stored.trimmed,
+        perceptual_hash: None,
+        animation: stored.animation,
+        placeholder,
+    };
+    let img = img.clone();
+    match web::block(move || {
+        record.perceptual_hash = Some(PerceptualHash::of(&img));
+        index.insert(&record)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|result| result)
+    {
+        Ok(id) => {
+            debug!(id, "Added upload to index");
+            Some(id)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to add upload to index");
+            None
+        }
+    }
+}
+
+/// Converts an upload whose colour profile is being preserved to sRGB, as the clipboard can't carry
+/// the profile. Images which can't be converted are placed on the clipboard as they are.
+async fn clipboard_colours(img: DynamicImage, profile: Option<Vec<u8>>) -> DynamicImage {
+    let Some(profile) = profile else {
+        return img;
+    };
+    let fallback = img.clone();
+    let converted = web::block(move || {
+        let mut img = img;
+        icc::to_srgb(&mut img, &profile).map(|()| img)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|result| result);
+    match converted {
+        Ok(img) => img,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to convert upload to sRGB for the clipboard");
+            fallback
+        }
+    }
+}
+
+#[instrument(skip(handles, img))]
+/// Given a decoded image, attempts to copy it to the clipboard
+pub(crate) async fn insert_image_to_clipboard(
+    img: DynamicImage,
     handles: Data<OpenHandles>,
 ) -> Result<(), HandlerError> {
     //Put image into clipboard
-    let img = load_image_from_file(file).await?;
     match handles.clip_image(img).await {
         Ok(()) => Ok(()),
         Err(e) => {
@@ -280,15 +1142,63 @@ async fn insert_file_to_clipboard(
     }
 }
 
+#[instrument(skip(handles))]
+/// Attempts to copy text, such as a link to an upload, to the clipboard
+pub(crate) async fn insert_text_to_clipboard(
+    text: String,
+    handles: Data<OpenHandles>,
+) -> Result<(), HandlerError> {
+    match handles.clip_text(text).await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            tracing::error!("Failed to place text into clipboard due to error {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+/// An upload decoded into memory
+struct DecodedUpload {
+    /// First frame of the upload, converted to sRGB if the server is configured to do so
+    img: DynamicImage,
+    /// Frame count and duration of the upload, if it is animated
+    animation: Option<Animation>,
+    /// Colour profile which copies of the upload encoded by the server should carry
+    profile: Option<Vec<u8>>,
+}
+
 /// Attempts to load an image file into memory, then parse it into a DynamicImage
-/// struct for easier use
-async fn load_image_from_file(f: File) -> Result<DynamicImage> {
+/// struct for easier use. Images whose header exceeds the decode limits are rejected before
+/// any pixel data is allocated. Only the first frame of an animated image is decoded, so its
+/// frame count and duration are returned alongside it, along with the embedded colour profile
+/// if the server is configured to preserve it.
+async fn load_image_from_file(f: File, config: &Config) -> Result<DecodedUpload, HandlerError> {
     //Convert file handle to std::fs::File
-    let f: std::fs::File = f.into_std().await;
-    let reader: image::io::Reader<BufReader<std::fs::File>> =
-        image::io::Reader::new(BufReader::new(f));
-    tokio::task::spawn_blocking(move || -> Result<DynamicImage> {
-        Ok(reader.with_guessed_format()?.decode()?)
+    let mut f: std::fs::File = f.into_std().await;
+    let limits = config.decode_limits.to_image_limits();
+    let policy = config.colour_profiles;
+    tokio::task::spawn_blocking(move || -> Result<DecodedUpload, HandlerError> {
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut f, &mut data).map_err(HandlerError::FailedToLoadImage)?;
+        //Files which can't be walked are treated as stills, leaving the decoder to reject them
+        let animation = animation::inspect(&data).unwrap_or_else(|e| {
+            debug!(error = %e, "Failed to inspect upload for animation");
+            None
+        });
+        let mut reader = image::io::Reader::new(Cursor::new(&data))
+            .with_guessed_format()
+            .map_err(HandlerError::FailedToLoadImage)?;
+        reader.limits(limits);
+        let mut img = reader.decode().map_err(|e| match e {
+            ImageError::Limits(e) => HandlerError::ImageExceedsLimits(e.to_string()),
+            e => HandlerError::InternalError(e.into()),
+        })?;
+        let profile = icc::manage(policy, &data, &mut img);
+        Ok(DecodedUpload {
+            img,
+            animation,
+            profile,
+        })
     })
     .await?
 }
@@ -1,55 +1,312 @@
 //! Handlers for imagehost feature, which allows uploaded images to be accessed.
-//! All files within the configured screenshot storage directory will be accessible
-//! by path.
+//! All files within the configured screenshot storage directories will be accessible
+//! by path, and requesting a directory returns a listing of its contents. Watermark rules applied
+//! when serving are honoured here, so the stored files themselves stay unmarked.
 
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+};
 
 use actix_files::NamedFile;
 use actix_web::{
-    web::{self, Data},
-    Result,
+    http::header,
+    web::{self, Data, Json},
+    CustomizeResponder, Either, HttpRequest, Responder, Result,
 };
+use serde_derive::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::conf::Config;
+use crate::{
+    conf::{Config, WatermarkStage},
+    imaging::{
+        animation,
+        placeholder::Placeholder,
+        thumbnail,
+        variant::{self, VariantQuery},
+        watermark,
+    },
+    index::Index,
+    storage,
+};
+
+use super::{handler_err::HandlerError, quota, OpenHandles};
 
-use super::{handler_err::HandlerError, OpenHandles};
+/// Headers which can change how an image is watermarked
+static AUTH_HEADERS: &str = "Authorization, X-Api-Key";
 
-#[instrument(skip(_handles))]
+#[instrument(skip(handles, req))]
 /// Handler for /img/<image_path> which returns files from the local filesystem.
+/// Images can be resized or converted with the `w`, `h`, `fit` and `format` query parameters.
 pub async fn img(
-    _handles: Data<OpenHandles>,
+    handles: Data<OpenHandles>,
     config: Data<Config>,
+    req: HttpRequest,
     img_loc: web::Path<String>,
-) -> Result<actix_files::NamedFile, HandlerError> {
-    if let Some(tgt_dir) = &config.target_dir {
+    query: web::Query<VariantQuery>,
+) -> Result<Either<CustomizeResponder<NamedFile>, Json<DirectoryListing>>, HandlerError> {
+    let roots = storage::roots(&config);
+    if roots.is_empty() {
+        return Err(HandlerError::ImageHostingDisabled());
+    }
+    //Hidden files and directories are used internally, so should never be served
+    if img_loc
+        .split('/')
+        .any(|component| component.starts_with('.'))
+    {
+        return Err(HandlerError::FilePathNotAllowed(img_loc.to_string()));
+    }
+    tracing::trace!("Got request for image at {}", img_loc);
+    query
+        .validate(&config)
+        .map_err(HandlerError::InvalidQuery)?;
+
+    //Look through each storage directory in turn, merging listings of directories present in several
+    let mut listing: Option<DirectoryListing> = None;
+    for root in roots {
         //Work out image file path
-        let mut tgt_dir_buf: PathBuf = PathBuf::from(tgt_dir);
-        tracing::trace!("Got request for image at {}", img_loc);
-        tgt_dir_buf.push(img_loc.to_string());
-        tracing::trace!("Returning image at {:?}", tgt_dir_buf);
+        let tgt_path = root.join(img_loc.as_str());
+        let Ok(canonical) = tgt_path.canonicalize() else {
+            continue;
+        };
 
         //Make sure requested path is a subdirectory of the screenshots dir
-        let canonical = tgt_dir_buf
+        if !root
             .canonicalize()
-            .map_err(HandlerError::InvalidPath)?;
-        if !canonical.starts_with(tgt_dir) {
+            .is_ok_and(|root| canonical.starts_with(root))
+        {
             tracing::warn!(
                 "Got request for path ({}) outside configured directory: {}",
                 img_loc.to_string(),
                 canonical.display()
             );
-            Err(HandlerError::FilePathNotAllowed(img_loc.to_string()))
-        } else if canonical.exists() {
-            //File exists, so try to open it
-            tracing::info!("Returning image {}", canonical.display());
-            NamedFile::open(canonical).map_err(HandlerError::InvalidPath)
-        } else {
+            return Err(HandlerError::FilePathNotAllowed(img_loc.to_string()));
+        } else if canonical.is_dir() {
+            //Directory, so list its contents
+            tracing::info!("Returning listing of {}", canonical.display());
+            let dir_listing = list_directory(&canonical, img_loc.as_str())
+                .await
+                .map_err(HandlerError::InvalidPath)?;
+            listing = Some(match listing {
+                Some(listing) => listing.merge(dir_listing),
+                None => dir_listing,
+            });
+        } else if listing.is_none() {
+            //File exists, so serve it along with any variant or watermark requested or configured
+            let rel_path = PathBuf::from(img_loc.as_str());
+            //Invalid API keys are treated the same as none, as the image is public either way
+            let authenticated = quota::authenticate(&req, &config).is_ok_and(|u| u.is_some());
+            let rule = watermark::find_rule(
+                &config,
+                WatermarkStage::Serve,
+                rel_path.parent(),
+                authenticated,
+            )
+            .cloned();
+            let path = if query.is_empty() && rule.is_none() {
+                tracing::info!("Returning image {}", canonical.display());
+                canonical
+            } else {
+                tracing::info!(
+                    watermarked = rule.is_some(),
+                    "Returning copy of image {}",
+                    canonical.display()
+                );
+                let conf = config.clone();
+                let query = query.into_inner();
+                web::block(move || -> anyhow::Result<PathBuf> {
+                    let (source, variant) = if query.is_empty() {
+                        (canonical, None)
+                    } else {
+                        let source = variant::ensure(&conf, &canonical, &rel_path, &query)?;
+                        (source, Some(&query))
+                    };
+                    match rule {
+                        Some(rule) => watermark::ensure(&conf, &rule, &source, &rel_path, variant),
+                        None => Ok(source),
+                    }
+                })
+                .await??
+            };
+            let file = NamedFile::open(path).map_err(HandlerError::InvalidPath)?;
+            //Caches must not share copies between requests which are watermarked differently
+            let mut response = file.customize();
+            if watermark::depends_on_audience(&config) {
+                response = response.insert_header((header::VARY, AUTH_HEADERS));
+            }
+            return Ok(Either::Left(response));
+        }
+    }
+    match listing {
+        Some(mut listing) => {
+            if let Some(index) = handles.index.clone() {
+                listing = web::block(move || -> anyhow::Result<DirectoryListing> {
+                    add_placeholders(&index, &mut listing)?;
+                    Ok(listing)
+                })
+                .await??;
+            }
+            Ok(Either::Right(Json(listing)))
+        }
+        None => {
             //File doesn't exist
-            tracing::info!("Image not found at {}", canonical.display());
+            tracing::info!("Image not found at {}", img_loc.as_str());
             Err(HandlerError::ImageDoesNotExist(img_loc.to_string()))
         }
-    } else {
-        Err(HandlerError::ImageHostingDisabled())
     }
 }
+
+/// Query parameters accepted by [`thumb`]
+#[derive(Debug, Deserialize)]
+pub struct ThumbQuery {
+    /// Size of thumbnail to return, which must be one of the configured sizes
+    pub size: Option<u32>,
+    /// Return a full size still of the first frame instead, which is only offered for animations
+    #[serde(default)]
+    pub poster: bool,
+}
+
+/// Returns true if a stored file is animated, going by its index entry if it has one
+fn is_animated(config: &Config, index: Option<&Index>, rel_path: &Path) -> anyhow::Result<bool> {
+    if let Some(record) = index
+        .map(|index| index.find_by_path(rel_path))
+        .transpose()?
+        .flatten()
+    {
+        return Ok(record.animation.is_some());
+    }
+    let source = storage::find_root(config, rel_path)
+        .map(|root| root.join(rel_path))
+        .ok_or_else(|| anyhow::anyhow!("No stored file at {}", rel_path.display()))?;
+    Ok(animation::inspect(&std::fs::read(source)?)?.is_some())
+}
+
+#[instrument(skip(handles, req))]
+/// Handler for /thumb/<image_path> which returns a thumbnail of a stored image, or a poster of an
+/// animated one, generating it if it isn't already cached. Posters are full size, so are
+/// watermarked the same way as images served through `/img`.
+pub async fn thumb(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+    img_loc: web::Path<String>,
+    query: web::Query<ThumbQuery>,
+) -> Result<CustomizeResponder<NamedFile>, HandlerError> {
+    let rel_path = PathBuf::from(img_loc.as_str());
+    //Hidden files are never served, and thumbnails can't be requested for paths outside the storage directories
+    if !rel_path.components().all(|component| {
+        matches!(component, Component::Normal(name) if !name.to_string_lossy().starts_with('.'))
+    }) {
+        return Err(HandlerError::FilePathNotAllowed(img_loc.to_string()));
+    }
+    let size = thumbnail::choose_size(&config, query.size).ok_or_else(|| {
+        HandlerError::InvalidQuery(format!("size must be one of {:?}", config.thumbnails.sizes))
+    })?;
+    if !storage::exists_in_any_root(&config, &rel_path) {
+        return Err(HandlerError::ImageDoesNotExist(img_loc.to_string()));
+    }
+    if !query.poster {
+        let path = web::block(move || thumbnail::ensure(&config, size, &rel_path)).await??;
+        return Ok(NamedFile::open(path)
+            .map_err(HandlerError::InvalidPath)?
+            .customize());
+    }
+
+    //Invalid API keys are treated the same as none, as the image is public either way
+    let authenticated = quota::authenticate(&req, &config).is_ok_and(|u| u.is_some());
+    let rule = watermark::find_rule(
+        &config,
+        WatermarkStage::Serve,
+        rel_path.parent(),
+        authenticated,
+    )
+    .cloned();
+    let (conf, index) = (config.clone(), handles.index.clone());
+    let path = web::block(move || -> Result<PathBuf, HandlerError> {
+        if !is_animated(&conf, index.as_deref(), &rel_path)? {
+            return Err(HandlerError::InvalidQuery(
+                "poster is only available for animated images".to_owned(),
+            ));
+        }
+        let poster = thumbnail::ensure_poster(&conf, &rel_path)?;
+        Ok(match rule {
+            Some(rule) => watermark::ensure(&conf, &rule, &poster, &rel_path, None)?,
+            None => poster,
+        })
+    })
+    .await??;
+    let mut response = NamedFile::open(path)
+        .map_err(HandlerError::InvalidPath)?
+        .customize();
+    if watermark::depends_on_audience(&config) {
+        response = response.insert_header((header::VARY, AUTH_HEADERS));
+    }
+    Ok(response)
+}
+
+/// Contents of a directory within the image storage directory
+#[derive(Debug, Serialize)]
+pub struct DirectoryListing {
+    /// Path of the listed directory, relative to the image storage directory
+    pub path: String,
+    /// Names of the subdirectories, such as program names or date components
+    pub directories: Vec<String>,
+    /// Names of the files stored directly in this directory
+    pub files: Vec<String>,
+    /// Placeholders of the indexed files in this directory, keyed by file name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub placeholders: BTreeMap<String, Placeholder>,
+}
+
+impl DirectoryListing {
+    /// Combines the listings of the same directory from two storage directories
+    fn merge(mut self, other: DirectoryListing) -> Self {
+        self.directories.extend(other.directories);
+        self.directories.sort();
+        self.directories.dedup();
+        self.files.extend(other.files);
+        self.files.sort();
+        self.files.dedup();
+        self.placeholders.extend(other.placeholders);
+        self
+    }
+}
+
+/// Looks up the placeholder recorded in the index for each file in a listing
+fn add_placeholders(index: &Index, listing: &mut DirectoryListing) -> anyhow::Result<()> {
+    for file in &listing.files {
+        let rel_path = Path::new(&listing.path).join(file);
+        if let Some(placeholder) = index
+            .find_by_path(&rel_path)?
+            .and_then(|record| record.placeholder)
+        {
+            listing.placeholders.insert(file.clone(), placeholder);
+        }
+    }
+    Ok(())
+}
+
+/// Lists the non-hidden entries of a directory, sorted by name
+async fn list_directory(dir: &Path, rel_path: &str) -> std::io::Result<DirectoryListing> {
+    let mut listing = DirectoryListing {
+        path: rel_path.trim_matches('/').to_owned(),
+        directories: Vec::new(),
+        files: Vec::new(),
+        placeholders: BTreeMap::new(),
+    };
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type().await?.is_dir() {
+            listing.directories.push(name);
+        } else {
+            listing.files.push(name);
+        }
+    }
+    listing.directories.sort();
+    listing.files.sort();
+    Ok(listing)
+}
@@ -0,0 +1,66 @@
+//! Handler exposing counters in the Prometheus text format
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use actix_web::{web::Data, HttpResponse};
+use tracing::instrument;
+
+use super::OpenHandles;
+use crate::storage::optimise::OptimiseStats;
+
+/// Content type of the Prometheus text exposition format
+static PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Appends a single metric, along with its help and type lines, to the output
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// Renders the optimiser's counters
+fn write_optimise_stats(out: &mut String, stats: &OptimiseStats) {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    write_metric(
+        out,
+        "yoinkx_optimise_files_total",
+        "counter",
+        "Stored files processed by the optimiser, by outcome",
+        &[
+            ("{outcome=\"replaced\"}", load(&stats.replaced)),
+            ("{outcome=\"unchanged\"}", load(&stats.unchanged)),
+            ("{outcome=\"failed\"}", load(&stats.failed)),
+        ],
+    );
+    write_metric(
+        out,
+        "yoinkx_optimise_bytes_saved_total",
+        "counter",
+        "Bytes saved by replacing stored files with optimised copies",
+        &[("", load(&stats.bytes_saved))],
+    );
+    write_metric(
+        out,
+        "yoinkx_optimise_queue_length",
+        "gauge",
+        "Stored files waiting to be optimised",
+        &[("", load(&stats.queued))],
+    );
+}
+
+#[instrument(skip(handles))]
+/// Handler for GET /metrics
+pub async fn metrics(handles: Data<OpenHandles>) -> HttpResponse {
+    let mut out = String::new();
+    if let Some(optimiser) = &handles.optimiser {
+        write_optimise_stats(&mut out, optimiser.stats());
+    }
+    HttpResponse::Ok()
+        .content_type(PROMETHEUS_CONTENT_TYPE)
+        .body(out)
+}
@@ -1,33 +1,62 @@
 //! Initialization and handlers for webserver
 
-mod checked_file_stream;
+pub mod api;
+pub(crate) mod checked_file_stream;
+pub mod edit;
 pub mod handler_err;
 pub mod image_upload;
 pub mod imagehost;
+pub mod metrics;
+pub mod quota;
 
-use std::borrow::Cow;
+use std::{borrow::Cow, sync::Arc};
 
+use actix_multipart::{form::MultipartFormConfig, MultipartError};
 use actix_web::{
     web::{self, Data},
     App, HttpServer,
 };
 use image::{DynamicImage, GenericImageView};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 
 use anyhow::Result;
 use tokio::sync::Mutex;
 
+use self::quota::QuotaTracker;
+use crate::{
+    conf::Config,
+    index::Index,
+    storage::{optimise::OptimiseQueue, retention, trash, versions::EditLocks},
+};
+
 /// Struct containing open resource handles, to be passed to all handlers
 pub struct OpenHandles {
     clipboard: Mutex<arboard::Clipboard>,
+    index: Option<Arc<Index>>,
+    quotas: Arc<QuotaTracker>,
+    optimiser: Option<OptimiseQueue>,
+    edit_locks: Arc<EditLocks>,
 }
 
 impl OpenHandles {
-    /// Initialize handles
-    pub fn new() -> Result<Self> {
+    /// Initialize handles, starting the optimisation queue if enabled
+    pub fn new(conf: &Config) -> Result<Self> {
         let clipboard = arboard::Clipboard::new()?;
         let mutex = Mutex::new(clipboard);
+        let index = Index::open_configured(conf)?.map(Arc::new);
+        let edit_locks = Arc::new(EditLocks::default());
+        let optimiser = conf
+            .optimise
+            .enabled
+            .then(|| OptimiseQueue::start(conf.clone(), index.clone(), edit_locks.clone()));
 
-        Ok(OpenHandles { clipboard: mutex })
+        Ok(OpenHandles {
+            clipboard: mutex,
+            index,
+            quotas: Arc::default(),
+            optimiser,
+            edit_locks,
+        })
     }
 
     /// Copy an image to the clipboard.
@@ -50,27 +79,107 @@ impl OpenHandles {
 
         Ok(())
     }
+
+    /// Copy text to the clipboard.
+    pub async fn clip_text(&self, text: String) -> Result<()> {
+        let mut clipboard = self.clipboard.lock().await;
+        clipboard.set_text(text)?;
+
+        Ok(())
+    }
 }
 
 /// Start the webserver
-pub async fn start(conf: crate::conf::Config) -> Result<()> {
-    //Open clipboard handle
-    let clipboard_data = Data::new(OpenHandles::new()?);
+pub async fn start(conf: Config) -> Result<()> {
+    //Open clipboard and index handles
+    let clipboard_data = Data::new(OpenHandles::new(&conf)?);
     let config_data = Data::new(conf.clone());
 
+    //Start background pruning if enabled, and purging of expired files from the trash
+    if conf.retention.enabled {
+        actix_web::rt::spawn(retention::prune_periodically(
+            conf.clone(),
+            clipboard_data.index.clone(),
+        ));
+    }
+    if conf.target_dir.is_some() {
+        actix_web::rt::spawn(trash::purge_periodically(
+            conf.clone(),
+            clipboard_data.index.clone(),
+        ));
+    }
+
+    //Leave room in the request for the largest upload any client could be allowed
+    let upload_limit =
+        (conf.largest_upload_size_limit() + image_upload::MULTIPART_OVERHEAD) as usize;
+
     //Start webserver
     let mut server = HttpServer::new(move || {
         let mut app = App::new()
             //Attach state
             .app_data(clipboard_data.clone())
             .app_data(config_data.clone())
+            .app_data(
+                MultipartFormConfig::default()
+                    .total_limit(upload_limit)
+                    .error_handler(multipart_error_response),
+            )
             //Add logger middleware
             .wrap(tracing_actix_web::TracingLogger::default())
             //Mount routes
             .service(web::resource("/upload").to(image_upload::upload));
+        //Add metrics route if there is anything to report
+        if conf.optimise.enabled {
+            app = app.service(web::resource("/metrics").route(web::get().to(metrics::metrics)));
+        }
         //Add imagehost route if enabled
         if conf.enable_imagehost {
             app = app.service(web::resource("/img/{path:.*}").to(imagehost::img));
+            if conf.thumbnails.enabled {
+                app = app.service(web::resource("/thumb/{path:.*}").to(imagehost::thumb));
+            }
+        }
+        //Deletion links only work for indexed uploads
+        if conf.enable_index && conf.target_dir.is_some() {
+            app = app.service(
+                web::resource("/delete/{id}/{token}").route(web::get().to(api::delete_with_token)),
+            );
+        }
+        //Add API routes if enabled
+        if conf.enable_api {
+            app = app.service(
+                web::scope("/api")
+                    .service(
+                        web::resource("/images/{id}").route(web::delete().to(api::delete_image)),
+                    )
+                    .service(
+                        web::resource("/images/{id}/redact")
+                            .route(web::post().to(edit::redact_image)),
+                    )
+                    .service(
+                        web::resource("/images/{id}/similar")
+                            .route(web::get().to(api::similar_images)),
+                    )
+                    .service(
+                        web::resource("/images/{id}/annotate")
+                            .route(web::post().to(edit::annotate_image)),
+                    )
+                    .service(
+                        web::resource("/images/{id}/versions")
+                            .route(web::get().to(edit::list_versions)),
+                    )
+                    .service(
+                        web::resource("/images/{id}/versions/{version}")
+                            .route(web::get().to(edit::get_version)),
+                    )
+                    .service(web::resource("/quota").route(web::get().to(quota::quota_usage)))
+                    .service(web::resource("/trash").route(web::get().to(api::list_trash)))
+                    .service(web::resource("/archive").route(web::get().to(api::download_archive)))
+                    .service(
+                        web::resource("/trash/restore/{path:.*}")
+                            .route(web::post().to(api::restore_from_trash)),
+                    ),
+            );
         }
         app
     });
@@ -83,3 +192,44 @@ pub async fn start(conf: crate::conf::Config) -> Result<()> {
 
     Ok(())
 }
+
+/// Responds to errors reading an upload form using the error raised while reading the field, if
+/// any, so that handler errors keep their status codes
+fn multipart_error_response(
+    err: MultipartError,
+    _req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    match err {
+        MultipartError::Field { source, .. } => source,
+        err => err.into(),
+    }
+}
+
+/// Characters which must be escaped within a single segment of a URL path
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Builds a link to a path served under the given route, using the configured public URL if set
+pub(crate) fn public_link(conf: &Config, route: &str, path: &str) -> String {
+    let encoded_path = path
+        .split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    let base = conf
+        .public_url
+        .as_deref()
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    format!("{}/{}/{}", base, route, encoded_path)
+}
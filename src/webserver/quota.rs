@@ -0,0 +1,263 @@
+//! Identification of uploaders by API key, and enforcement of their storage quotas
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::{
+    http::header,
+    web::{self, Data, Json},
+    HttpRequest,
+};
+use serde_derive::Serialize;
+
+use super::{handler_err::HandlerError, OpenHandles};
+use crate::{
+    conf::{Config, UserConfig},
+    index::{Index, Usage},
+};
+
+/// Name of the alternative header an API key can be sent in
+static API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Finds the user whose API key was sent with a request. Returns `None` if no key was sent, or if
+/// no users are configured so keys aren't in use, and an error if the key does not belong to any
+/// configured user.
+pub fn authenticate<'c>(
+    req: &HttpRequest,
+    config: &'c Config,
+) -> Result<Option<&'c UserConfig>, HandlerError> {
+    let headers = req.headers();
+    let key = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get(API_KEY_HEADER)
+                .and_then(|key| key.to_str().ok())
+        });
+    match key {
+        //Clients such as ShareX may send a key even to servers which don't use them
+        Some(_) if config.users.is_empty() => Ok(None),
+        Some(key) => config
+            .users
+            .iter()
+            .find(|user| user.api_key == key.trim())
+            .map(Some)
+            .ok_or(HandlerError::InvalidApiKey()),
+        None => Ok(None),
+    }
+}
+
+/// Finds the user whose API key was sent with a request, requiring a key whenever users are
+/// configured. Returns `None` only if no users are configured, in which case the API is open.
+pub fn require_user<'c>(
+    req: &HttpRequest,
+    config: &'c Config,
+) -> Result<Option<&'c UserConfig>, HandlerError> {
+    match authenticate(req, config)? {
+        Some(user) => Ok(Some(user)),
+        None if config.users.is_empty() => Ok(None),
+        None => Err(HandlerError::InvalidApiKey()),
+    }
+}
+
+/// Keeps track of the data being written by uploads which have not yet been recorded in the
+/// index, so that concurrent uploads by the same user can't exceed their quota between them
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    in_flight: Mutex<HashMap<String, Usage>>,
+}
+
+impl QuotaTracker {
+    /// Starts tracking an upload by a user, checking they have room for another file. Usage already
+    /// recorded in the index counts towards the quota alongside other uploads still in progress.
+    pub fn begin(
+        self: &Arc<Self>,
+        user: &UserConfig,
+        index: Option<&Index>,
+    ) -> Result<QuotaReservation, HandlerError> {
+        let stored = match index {
+            Some(index) => index.usage(&user.name)?,
+            None => Usage::default(),
+        };
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        let pending = in_flight.entry(user.name.clone()).or_default();
+        if let Some(max_files) = user.quota_files {
+            if stored.files + pending.files >= max_files {
+                return Err(HandlerError::QuotaExceeded(format!(
+                    "{} may store at most {} file(s)",
+                    user.name, max_files
+                )));
+            }
+        }
+        pending.files += 1;
+        Ok(QuotaReservation {
+            tracker: self.clone(),
+            user: user.name.clone(),
+            max_bytes: user.quota_bytes,
+            stored_bytes: stored.bytes,
+            reserved_bytes: 0,
+        })
+    }
+
+    /// Returns the usage of uploads by a user which are still in progress
+    fn pending(&self, user: &str) -> Usage {
+        let in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.get(user).copied().unwrap_or_default()
+    }
+}
+
+/// Space reserved for an upload in progress, which is released when dropped. It should be held
+/// until the upload has been recorded in the index, at which point the index accounts for it.
+#[derive(Debug)]
+pub struct QuotaReservation {
+    tracker: Arc<QuotaTracker>,
+    user: String,
+    max_bytes: Option<u64>,
+    stored_bytes: u64,
+    reserved_bytes: u64,
+}
+
+impl QuotaReservation {
+    /// Reserves space for another chunk of the upload, failing if it would take the user over
+    /// their quota
+    pub fn reserve_bytes(&mut self, bytes: u64) -> Result<(), HandlerError> {
+        let mut in_flight = self
+            .tracker
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let pending = in_flight.entry(self.user.clone()).or_default();
+        if let Some(max_bytes) = self.max_bytes {
+            if self.stored_bytes + pending.bytes + bytes > max_bytes {
+                return Err(HandlerError::QuotaExceeded(format!(
+                    "{} may store at most {}B",
+                    self.user, max_bytes
+                )));
+            }
+        }
+        pending.bytes += bytes;
+        self.reserved_bytes += bytes;
+        Ok(())
+    }
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        let mut in_flight = self
+            .tracker
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(pending) = in_flight.get_mut(&self.user) {
+            pending.bytes = pending.bytes.saturating_sub(self.reserved_bytes);
+            pending.files = pending.files.saturating_sub(1);
+            if pending.files == 0 {
+                in_flight.remove(&self.user);
+            }
+        }
+    }
+}
+
+/// Storage used by a user, and the limits placed on it
+#[derive(Debug, Serialize)]
+pub struct QuotaStatus {
+    /// Name of the user
+    pub user: String,
+    /// Storage used by images which have been stored
+    pub used: Usage,
+    /// Storage reserved by uploads still in progress
+    pub pending: Usage,
+    /// Maximum total size in bytes the user may store, if limited
+    pub max_bytes: Option<u64>,
+    /// Maximum number of files the user may store, if limited
+    pub max_files: Option<u64>,
+}
+
+/// Returns the storage used by the user making the request
+pub async fn quota_usage(
+    handles: Data<OpenHandles>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<Json<QuotaStatus>, HandlerError> {
+    let user = authenticate(&req, &config)?.ok_or(HandlerError::InvalidApiKey())?;
+    let index = handles.index.clone();
+    let name = user.name.clone();
+    let used = match index {
+        Some(index) => web::block(move || index.usage(&name)).await??,
+        None => Usage::default(),
+    };
+    Ok(Json(QuotaStatus {
+        user: user.name.clone(),
+        used,
+        pending: handles.quotas.pending(&user.name),
+        max_bytes: user.quota_bytes,
+        max_files: user.quota_files,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn config(users: Vec<UserConfig>) -> Config {
+        Config {
+            users,
+            ..Default::default()
+        }
+    }
+
+    fn alice() -> UserConfig {
+        UserConfig {
+            name: "alice".to_owned(),
+            api_key: "secret".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ignores_keys_when_no_users_are_configured() {
+        let config = config(Vec::new());
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer anything"))
+            .to_http_request();
+        assert!(matches!(authenticate(&req, &config), Ok(None)));
+        assert!(matches!(require_user(&req, &config), Ok(None)));
+    }
+
+    #[test]
+    fn finds_user_by_key_in_either_header() {
+        let config = config(vec![alice()]);
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        assert_eq!(authenticate(&req, &config).unwrap().unwrap().name, "alice");
+        let req = TestRequest::default()
+            .insert_header((API_KEY_HEADER, "secret"))
+            .to_http_request();
+        assert_eq!(authenticate(&req, &config).unwrap().unwrap().name, "alice");
+    }
+
+    #[test]
+    fn rejects_unknown_and_missing_keys_when_users_are_configured() {
+        let config = config(vec![alice()]);
+        let req = TestRequest::default()
+            .insert_header((API_KEY_HEADER, "wrong"))
+            .to_http_request();
+        assert!(matches!(
+            authenticate(&req, &config),
+            Err(HandlerError::InvalidApiKey())
+        ));
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(authenticate(&req, &config), Ok(None)));
+        assert!(matches!(
+            require_user(&req, &config),
+            Err(HandlerError::InvalidApiKey())
+        ));
+    }
+}